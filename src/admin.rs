@@ -0,0 +1,644 @@
+use std::{net::IpAddr, time::Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+    rwarc::LockWithTimeout,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    breaker::{BreakerState, CircuitBreaker},
+    config::{AdminConfig, AppConfig},
+    deadletter::{load_dead_letters, parse_time, Selection},
+    errordigest::ErrorEmail,
+    metrics::{Counters, Metrics},
+    payload::short_hash,
+    queue::TimedEmail,
+    ratelimit::TokenBucket,
+    receiver::Shared,
+};
+
+// Asked of the main loop, which owns the queue, answered on the enclosed sender
+pub enum AdminRequest {
+    Status(oneshot::Sender<Status>),
+    // Each answers whether a queued or held message had that id
+    Retry(String, oneshot::Sender<bool>),
+    Purge(String, oneshot::Sender<bool>),
+    // Answers with the ids put back in the queue
+    Requeue(Selection, oneshot::Sender<Result<Vec<String>, String>>),
+}
+
+// Operator actions on a single message
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    // Send on the next round whatever the backoff says, held mail included
+    Retry,
+    // Drop a poison message for good
+    Purge,
+}
+
+impl Action {
+    fn parse(action: &str) -> Option<Self> {
+        match action {
+            "retry" => Some(Action::Retry),
+            "purge" => Some(Action::Purge),
+            _ => None,
+        }
+    }
+}
+
+// Answer to /api/messages/{id}/{action}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ActionResult {
+    pub id: String,
+    pub action: Action,
+    // False when nothing queued or held had that id
+    pub done: bool,
+}
+
+// A queued or held message, without its body
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueuedMessage {
+    pub id: String,
+    pub client: Option<String>,
+    pub subject: String,
+    pub age_seconds: u64,
+    pub attempts: u32,
+    // 0 when the message goes with the next round
+    pub next_attempt_seconds: u64,
+}
+
+impl QueuedMessage {
+    pub fn new(timed: &TimedEmail, redact: bool) -> Self {
+        Self {
+            id: timed.id.clone(),
+            client: timed.email.client.clone(),
+            subject: redacted(&timed.email.subject, redact),
+            age_seconds: timed.received_at.elapsed().as_secs(),
+            attempts: timed.attempts,
+            next_attempt_seconds: timed.next_attempt.saturating_duration_since(Instant::now()).as_secs(),
+        }
+    }
+}
+
+// A distinct error from the main loop's error list
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ErrorSummary {
+    pub hash: String,
+    pub message: String,
+    pub age_seconds: u64,
+}
+
+// Everything the dashboard shows, gathered by the main loop in one go
+#[derive(Debug, Serialize, Clone)]
+pub struct Status {
+    pub queued: Vec<QueuedMessage>,
+    pub held: Vec<QueuedMessage>,
+    pub digest: usize,
+    pub recent: Vec<AuditEvent>,
+    pub errors: Vec<ErrorSummary>,
+    // Sends the rate limit allows right now out of `burst`
+    pub rate_available: usize,
+    pub rate_per_minute: u32,
+    pub rate_burst: u32,
+    pub circuit: String,
+    pub events: Counters,
+}
+
+// What's stuck and why, served as JSON on /api/queue
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueueListing {
+    pub queued: Vec<QueuedMessage>,
+    pub held: Vec<QueuedMessage>,
+    pub errors: Vec<ErrorSummary>,
+}
+
+impl From<Status> for QueueListing {
+    fn from(status: Status) -> Self {
+        Self {
+            queued: status.queued,
+            held: status.held,
+            errors: status.errors,
+        }
+    }
+}
+
+// A dead letter as listed on /api/dead-letters, without its body
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetterSummary {
+    pub id: String,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub error: String,
+    pub client: Option<String>,
+    pub subject: String,
+}
+
+// Answer to /api/dead-letters/requeue
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Requeued {
+    pub requeued: Vec<String>,
+}
+
+pub fn redacted(subject: &str, redact: bool) -> String {
+    match redact {
+        true => format!("[{}]", short_hash(subject)),
+        false => subject.to_owned(),
+    }
+}
+
+// What the admin dashboard shows, subjects are hashed when logs are redacted
+pub async fn admin_status(
+    app_config: &AppConfig,
+    audit: &AuditLog,
+    bucket: &mut TokenBucket,
+    breaker: &CircuitBreaker,
+    metrics: &Metrics,
+    shared: &Shared,
+    errors: &LockWithTimeout<Vec<ErrorEmail>>,
+) -> Status {
+    let redact = app_config.app.redact_logs;
+    let listed = |queue: &[TimedEmail]| -> Vec<QueuedMessage> {
+        queue.iter().map(|timed| QueuedMessage::new(timed, redact)).collect()
+    };
+
+    Status {
+        queued: shared.emails.try_read().await.map(|queue| listed(&queue)).unwrap_or_default(),
+        held: shared.held.try_read().await.map(|held| listed(&held)).unwrap_or_default(),
+        digest: shared.digest.try_read().await.map(|digest| digest.len()).unwrap_or_default(),
+        recent: audit
+            .recent()
+            .into_iter()
+            .map(|event| AuditEvent {
+                subject: redacted(&event.subject, redact),
+                ..event
+            })
+            .collect(),
+        errors: errors
+            .try_read()
+            .await
+            .map(|errors| {
+                errors
+                    .iter()
+                    .map(|error| ErrorSummary {
+                        hash: error.hash.to_string(),
+                        message: error.subject.clone().unwrap_or_default(),
+                        age_seconds: error.occoured_at.elapsed().as_secs(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        rate_available: bucket.available(app_config.rate_limit.per_minute, app_config.rate_limit.burst),
+        rate_per_minute: app_config.rate_limit.per_minute,
+        rate_burst: app_config.rate_limit.burst,
+        circuit: match breaker.state() {
+            BreakerState::Closed => String::from("closed"),
+            BreakerState::Open(opened) => format!("open for {}s", opened.elapsed().as_secs()),
+            BreakerState::HalfOpen => String::from("half-open"),
+        },
+        events: metrics.events,
+    }
+}
+
+// Where the admin task finds what it serves without asking the main loop
+#[derive(Debug, Clone)]
+struct Site {
+    credentials: String,
+    dead_letter_path: String,
+    redact: bool,
+}
+
+// Serves the dashboard over HTTP with basic auth, every page is built from a fresh `Status`
+pub async fn admin_monitor(
+    config: AdminConfig,
+    dead_letter_path: String,
+    redact: bool,
+    sender: Sender<AdminRequest>,
+) -> Result<(), ErrorArrayItem> {
+    // Never served without a password, an empty one would let anyone in as the configured user
+    let password = match config.password.as_deref().filter(|password| !password.is_empty()) {
+        Some(password) => password,
+        None => {
+            return Err(ErrorArrayItem::new(
+                Errors::AuthenticationError,
+                String::from("admin: admin.password is required, the dashboard was not started"),
+            ))
+        }
+    };
+
+    let listener = TcpListener::bind((config.bind, config.port)).await.map_err(|e| {
+        ErrorArrayItem::new(Errors::Network, format!("admin: {}:{}: {}", config.bind, config.port, e))
+    })?;
+    log!(LogLevel::Info, "Admin dashboard listening on {}:{}", config.bind, config.port);
+
+    let site = Site {
+        credentials: format!("Basic {}", STANDARD.encode(format!("{}:{}", config.username, password))),
+        dead_letter_path,
+        redact,
+    };
+    tokio::spawn(async move {
+        while let Ok((conn, peer)) = listener.accept().await {
+            let sender = sender.clone();
+            let site = site.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(conn, &site, &sender).await {
+                    log!(LogLevel::Debug, "Admin request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve(conn: TcpStream, site: &Site, sender: &Sender<AdminRequest>) -> std::io::Result<()> {
+    let mut reader = BufReader::new(conn);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+
+    // Only the request line and the Authorization header matter, nothing here takes a body
+    let mut authorized = false;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") && value.trim() == site.credentials {
+                authorized = true;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let conn = reader.get_mut();
+
+    if !authorized {
+        return respond(
+            conn,
+            "401 Unauthorized",
+            &[("WWW-Authenticate", "Basic realm=\"MailRegulator\"")],
+            "text/plain",
+            "Unauthorized",
+        )
+        .await;
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", [""]) => match status(sender).await {
+            Some(status) => respond(conn, "200 OK", &[], "text/html; charset=utf-8", &render(&status)).await,
+            None => respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await,
+        },
+        ("GET", ["api", "status"]) => match status(sender).await {
+            Some(status) => respond_json(conn, &status).await,
+            None => respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await,
+        },
+        ("GET", ["api", "queue"]) => match status(sender).await {
+            Some(status) => respond_json(conn, &QueueListing::from(status)).await,
+            None => respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await,
+        },
+        ("POST", ["messages", id, action]) => match Action::parse(action) {
+            Some(action) => match act(sender, id, action).await {
+                Some(true) => respond(conn, "303 See Other", &[("Location", "/")], "text/plain", "Done").await,
+                Some(false) => respond(conn, "404 Not Found", &[], "text/plain", "No queued message with that id").await,
+                None => respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await,
+            },
+            None => respond(conn, "404 Not Found", &[], "text/plain", "Not found").await,
+        },
+        ("POST", ["api", "messages", id, action]) => match Action::parse(action) {
+            Some(action) => api_action(conn, sender, id, action).await,
+            None => respond(conn, "404 Not Found", &[], "text/plain", "Not found").await,
+        },
+        ("DELETE", ["api", "messages", id]) => api_action(conn, sender, id, Action::Purge).await,
+        ("GET", ["api", "dead-letters"]) => match load_dead_letters(&site.dead_letter_path).await {
+            Ok(letters) => {
+                let summaries: Vec<DeadLetterSummary> = letters
+                    .into_iter()
+                    .map(|(_, letter)| DeadLetterSummary {
+                        id: letter.id,
+                        failed_at: letter.failed_at,
+                        attempts: letter.attempts,
+                        error: letter.error,
+                        client: letter.email.client,
+                        subject: redacted(&letter.email.subject, site.redact),
+                    })
+                    .collect();
+                respond_json(conn, &summaries).await
+            }
+            Err(e) => respond(conn, "500 Internal Server Error", &[], "text/plain", &e.err_mesg).await,
+        },
+        ("POST", ["api", "dead-letters", "requeue"]) => {
+            let selection = match selection(query) {
+                Ok(selection) if !selection.is_empty() => selection,
+                Ok(_) => {
+                    let reason = "Choose what to requeue with all=true, id, since or until";
+                    return respond(conn, "400 Bad Request", &[], "text/plain", reason).await;
+                }
+                Err(e) => return respond(conn, "400 Bad Request", &[], "text/plain", &e).await,
+            };
+
+            let (reply, requeued) = oneshot::channel();
+            if sender.send(AdminRequest::Requeue(selection, reply)).await.is_err() {
+                return respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await;
+            }
+            match requeued.await {
+                Ok(Ok(requeued)) => respond_json(conn, &Requeued { requeued }).await,
+                Ok(Err(e)) => respond(conn, "500 Internal Server Error", &[], "text/plain", &e).await,
+                Err(_) => respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await,
+            }
+        }
+        _ => respond(conn, "404 Not Found", &[], "text/plain", "Not found").await,
+    }
+}
+
+// "all=true&id=a&id=b&since=2024-05-01" with form encoding
+fn selection(query: &str) -> Result<Selection, String> {
+    let mut selection = Selection::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = decode_component(value);
+        match name {
+            "all" => selection.all = value == "true",
+            "id" => selection.ids.push(value),
+            "since" => selection.since = Some(parse_time(&value)?),
+            "until" => selection.until = Some(parse_time(&value)?),
+            _ => return Err(format!("unknown parameter {}", name)),
+        }
+    }
+    Ok(selection)
+}
+
+fn decode_component(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 3 <= bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+async fn status(sender: &Sender<AdminRequest>) -> Option<Status> {
+    let (reply, status) = oneshot::channel();
+    sender.send(AdminRequest::Status(reply)).await.ok()?;
+    status.await.ok()
+}
+
+async fn api_action(conn: &mut TcpStream, sender: &Sender<AdminRequest>, id: &str, action: Action) -> std::io::Result<()> {
+    let Some(done) = act(sender, id, action).await else {
+        return respond(conn, "503 Service Unavailable", &[], "text/plain", "Shutting down").await;
+    };
+    let result = ActionResult {
+        id: id.to_owned(),
+        action,
+        done,
+    };
+    match done {
+        true => respond_json(conn, &result).await,
+        false => respond_json_status(conn, "404 Not Found", &result).await,
+    }
+}
+
+// None when the main loop is gone
+async fn act(sender: &Sender<AdminRequest>, id: &str, action: Action) -> Option<bool> {
+    let (reply, done) = oneshot::channel();
+    let request = match action {
+        Action::Retry => AdminRequest::Retry(id.to_owned(), reply),
+        Action::Purge => AdminRequest::Purge(id.to_owned(), reply),
+    };
+    sender.send(request).await.ok()?;
+    done.await.ok()
+}
+
+async fn respond_json<T: Serialize>(conn: &mut TcpStream, value: &T) -> std::io::Result<()> {
+    respond_json_status(conn, "200 OK", value).await
+}
+
+async fn respond_json_status<T: Serialize>(conn: &mut TcpStream, status: &str, value: &T) -> std::io::Result<()> {
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => respond(conn, status, &[], "application/json", &body).await,
+        Err(e) => respond(conn, "500 Internal Server Error", &[], "text/plain", &e.to_string()).await,
+    }
+}
+
+async fn respond(
+    conn: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(body);
+    conn.write_all(response.as_bytes()).await?;
+    conn.flush().await
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render(status: &Status) -> String {
+    let mut page = String::from(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"15\">\
+         <title>MailRegulator</title><style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;\
+         margin-bottom:2em}td,th{border:1px solid #ccc;padding:4px 8px;text-align:left}form{display:inline}</style>\
+         </head><body><h1>MailRegulator</h1>",
+    );
+
+    page.push_str(&format!(
+        "<p>Rate limit: {} of {} sends available ({}/min). Relay circuit: {}. Waiting for digest: {}.</p>",
+        status.rate_available,
+        status.rate_burst,
+        status.rate_per_minute,
+        escape(&status.circuit),
+        status.digest
+    ));
+    let events = &status.events;
+    page.push_str(&format!(
+        "<p>Accepted {}, rejected {} malformed and {} unauthenticated. Sent {}, failed {}, expired {}.</p>",
+        events.accepted, events.rejected_format, events.rejected_auth, events.sent, events.failed, events.expired
+    ));
+
+    for (title, messages) in [("Queued", &status.queued), ("Held for quiet hours", &status.held)] {
+        page.push_str(&format!("<h2>{} ({})</h2><table><tr><th>Id</th><th>Client</th><th>Subject</th>", title, messages.len()));
+        page.push_str("<th>Age</th><th>Attempts</th><th>Next attempt</th><th></th></tr>");
+        for message in messages {
+            page.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}s</td><td>{}</td><td>{}s</td><td>",
+                escape(&message.id),
+                escape(message.client.as_deref().unwrap_or("-")),
+                escape(&message.subject),
+                message.age_seconds,
+                message.attempts,
+                message.next_attempt_seconds
+            ));
+            for action in ["retry", "purge"] {
+                page.push_str(&format!(
+                    "<form method=\"post\" action=\"/messages/{}/{}\"><button>{}</button></form> ",
+                    escape(&message.id),
+                    action,
+                    action
+                ));
+            }
+            page.push_str("</td></tr>");
+        }
+        page.push_str("</table>");
+    }
+
+    page.push_str("<h2>Recent activity</h2><table><tr><th>Time</th><th>Outcome</th><th>Id</th><th>Client</th>");
+    page.push_str("<th>Subject</th><th>Recipients</th><th>Error</th></tr>");
+    for event in status.recent.iter().rev() {
+        page.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&event.timestamp),
+            serde_json::to_string(&event.outcome).unwrap_or_default().trim_matches('"'),
+            escape(&event.message_id),
+            escape(event.client.as_deref().unwrap_or("-")),
+            escape(&event.subject),
+            escape(&event.recipients.join(", ")),
+            escape(event.error.as_deref().unwrap_or(""))
+        ));
+    }
+    page.push_str("</table>");
+
+    page.push_str(&format!("<h2>Errors ({})</h2><table><tr><th>Hash</th><th>Age</th><th>Error</th></tr>", status.errors.len()));
+    for error in &status.errors {
+        page.push_str(&format!(
+            "<tr><td>{}</td><td>{}s</td><td>{}</td></tr>",
+            escape(&error.hash),
+            error.age_seconds,
+            escape(&error.message)
+        ));
+    }
+    page.push_str("</table></body></html>");
+    page
+}
+
+// Where the command line reaches the admin API, a wildcard bind over loopback
+fn base_url(config: &AdminConfig) -> String {
+    let host = match config.bind {
+        IpAddr::V4(address) if address.is_unspecified() => String::from("127.0.0.1"),
+        IpAddr::V6(address) if address.is_unspecified() => String::from("[::1]"),
+        IpAddr::V6(address) => format!("[{}]", address),
+        IpAddr::V4(address) => address.to_string(),
+    };
+    format!("http://{}:{}", host, config.port)
+}
+
+// Asks a running server for its queue over the admin API, for `--queue`
+pub async fn fetch_queue(config: &AdminConfig) -> Result<QueueListing, ErrorArrayItem> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/api/queue", base_url(config)))
+        .basic_auth(&config.username, config.password.as_ref())
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("admin: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(ErrorArrayItem::new(Errors::GeneralError, format!("admin: {}", status)));
+    }
+    response
+        .json()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::InvalidType, format!("admin: {}", e)))
+}
+
+// Retries or purges one message on a running server, for `--retry` and `--purge`
+pub async fn request_action(config: &AdminConfig, id: &str, action: Action) -> Result<bool, ErrorArrayItem> {
+    let name = match action {
+        Action::Retry => "retry",
+        Action::Purge => "purge",
+    };
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/messages/{}/{}", base_url(config), id, name))
+        .basic_auth(&config.username, config.password.as_ref())
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("admin: {}", e)))?;
+
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    if !status.is_success() {
+        return Err(ErrorArrayItem::new(Errors::GeneralError, format!("admin: {}", status)));
+    }
+    Ok(true)
+}
+
+// Requeues dead letters on a running server, for `--requeue-dead`
+pub async fn request_requeue(config: &AdminConfig, selection: &Selection) -> Result<Vec<String>, ErrorArrayItem> {
+    let mut query: Vec<(&str, String)> = selection.ids.iter().map(|id| ("id", id.clone())).collect();
+    if selection.all {
+        query.push(("all", String::from("true")));
+    }
+    if let Some(since) = selection.since {
+        query.push(("since", since.to_rfc3339()));
+    }
+    if let Some(until) = selection.until {
+        query.push(("until", until.to_rfc3339()));
+    }
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/api/dead-letters/requeue", base_url(config)))
+        .query(&query)
+        .basic_auth(&config.username, config.password.as_ref())
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("admin: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(ErrorArrayItem::new(Errors::GeneralError, format!("admin: {}: {}", status, body)));
+    }
+    response
+        .json::<Requeued>()
+        .await
+        .map(|requeued| requeued.requeued)
+        .map_err(|e| ErrorArrayItem::new(Errors::InvalidType, format!("admin: {}", e)))
+}
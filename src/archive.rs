@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use chrono::{Local, NaiveDate};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::config::ArchiveConfig;
+
+// Stores the rendered message under a directory for the current local date
+pub async fn archive_eml(config: &ArchiveConfig, email: &[u8]) -> Result<(), ErrorArrayItem> {
+    let now = Local::now();
+    let directory = Path::new(&config.directory).join(now.format("%Y-%m-%d").to_string());
+    fs::create_dir_all(&directory).await.map_err(|e| {
+        ErrorArrayItem::new(Errors::CreatingDirectory, format!("archive: {}: {}", directory.display(), e))
+    })?;
+
+    let path = directory.join(format!("{}-{}.eml", now.format("%H%M%S"), Uuid::new_v4()));
+    fs::write(&path, email)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("archive: {}: {}", path.display(), e)))?;
+
+    log!(LogLevel::Debug, "Archived sent message as {}", path.display());
+    Ok(())
+}
+
+// Removes dated directories older than the retention period, anything not named like a date is left alone
+pub async fn prune_archive(config: &ArchiveConfig) -> Result<(), ErrorArrayItem> {
+    if config.retention_days == 0 {
+        return Ok(());
+    }
+
+    let cutoff = Local::now().date_naive() - chrono::Duration::days(config.retention_days as i64);
+    let mut entries = match fs::read_dir(&config.directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(ErrorArrayItem::new(Errors::ReadingFile, format!("archive: {}", e))),
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let date = match NaiveDate::parse_from_str(&name.to_string_lossy(), "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) => continue,
+        };
+
+        if date < cutoff {
+            fs::remove_dir_all(entry.path())
+                .await
+                .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("archive: {}: {}", entry.path().display(), e)))?;
+            log!(LogLevel::Info, "Removed archived mail from {}", date);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::io::Write;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::{log, log::LogLevel};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{config::AttachmentConfig, payload::EmailPayload};
+
+// Logs and other text compress well, images and archives don't
+fn is_text(content_type: &str, filename: &str) -> bool {
+    content_type.starts_with("text/")
+        || ["application/json", "application/xml"].contains(&content_type)
+        || [".log", ".txt", ".csv", ".json"].iter().any(|extension| filename.ends_with(extension))
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+// Gzips oversized text attachments when allowed, returning why the payload was refused if any are still too big
+pub fn enforce_limits(config: &AttachmentConfig, email: &mut EmailPayload) -> Result<(), String> {
+    let limit = config.max_size_kb as usize * 1024;
+    if limit == 0 {
+        return Ok(());
+    }
+
+    for attachment in email.attachments.iter_mut() {
+        let data = attachment.decode().map_err(|e| e.err_mesg.to_string())?;
+        if data.len() <= limit {
+            continue;
+        }
+
+        if config.compress_text && is_text(&attachment.content_type, &attachment.filename) {
+            let compressed = gzip(&data).map_err(|e| format!("attachment {}: {}", attachment.filename, e))?;
+            log!(
+                LogLevel::Info,
+                "Compressed attachment {} from {} to {} bytes",
+                attachment.filename,
+                data.len(),
+                compressed.len()
+            );
+            if compressed.len() <= limit {
+                attachment.filename = format!("{}.gz", attachment.filename);
+                attachment.content_type = String::from("application/gzip");
+                attachment.content = STANDARD.encode(&compressed);
+                continue;
+            }
+            return Err(format!(
+                "attachment {} is {} KB compressed, over the {} KB limit",
+                attachment.filename,
+                compressed.len() / 1024,
+                config.max_size_kb
+            ));
+        }
+
+        return Err(format!(
+            "attachment {} is {} KB, over the {} KB limit",
+            attachment.filename,
+            data.len() / 1024,
+            config.max_size_kb
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use dusa_collection_utils::{functions::create_hash, log, log::LogLevel};
+use serde::Serialize;
+
+use crate::{config::AuditConfig, payload::EmailPayload, report::Summary};
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Accepted,
+    Held,
+    Digested,
+    Delivered,
+    Failed,
+    Expired,
+    Spooled,
+    Suppressed,
+    #[serde(rename = "dead_lettered")]
+    DeadLettered,
+    // Removed from the queue by an operator
+    Purged,
+    // Back in the queue from the dead-letter store
+    Requeued,
+}
+
+// One JSON line per event, the subject is hashed so the log itself carries no alert content
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    message_id: &'a str,
+    outcome: Outcome,
+    client: Option<&'a str>,
+    correlation_id: Option<&'a str>,
+    recipients: &'a [String],
+    subject_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+// A recent outcome as shown on the admin dashboard, only ever kept in memory
+#[derive(Debug, Serialize, Clone)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub message_id: String,
+    pub outcome: Outcome,
+    pub client: Option<String>,
+    pub subject: String,
+    pub recipients: Vec<String>,
+    pub error: Option<String>,
+}
+
+// Append-only record of what was accepted and sent on whose behalf, kept apart from the debug log
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    config: AuditConfig,
+    // The latest `audit.recent` events, newest last, whether or not the log file is enabled
+    recent: Arc<Mutex<VecDeque<AuditEvent>>>,
+    // Tallied for the daily report until it takes them
+    summary: Arc<Mutex<Summary>>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig) -> Self {
+        Self {
+            config: config.clone(),
+            recent: Arc::default(),
+            summary: Arc::default(),
+        }
+    }
+
+    // Applies a reloaded config, the recent events and the report's tally carry over
+    pub fn reconfigured(&self, config: &AuditConfig) -> Self {
+        Self {
+            config: config.clone(),
+            recent: self.recent.clone(),
+            summary: self.summary.clone(),
+        }
+    }
+
+    pub fn recent(&self) -> Vec<AuditEvent> {
+        let recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        recent.iter().cloned().collect()
+    }
+
+    // Everything tallied since the last call, starting a fresh tally
+    pub fn take_summary(&self) -> Summary {
+        let mut summary = self.summary.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::mem::take(&mut *summary)
+    }
+
+    pub fn record(&self, message_id: &str, email: &EmailPayload, recipients: &[String], outcome: Outcome, error: Option<&str>) {
+        self.summary.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).count(email, outcome, error);
+
+        if self.config.recent > 0 {
+            let mut recent = self.recent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            while recent.len() >= self.config.recent {
+                recent.pop_front();
+            }
+            recent.push_back(AuditEvent {
+                timestamp: Utc::now().to_rfc3339(),
+                message_id: message_id.to_owned(),
+                outcome,
+                client: email.client.clone(),
+                subject: email.subject.to_string(),
+                recipients: recipients.to_vec(),
+                error: error.map(str::to_owned),
+            });
+        }
+
+        if !self.config.enabled {
+            return;
+        }
+
+        let record = AuditRecord {
+            timestamp: Utc::now().to_rfc3339(),
+            message_id,
+            outcome,
+            client: email.client.as_deref(),
+            correlation_id: email.correlation_id.as_deref(),
+            recipients,
+            subject_hash: create_hash(email.subject.to_string()).to_string(),
+            error,
+        };
+
+        if let Err(e) = self.append(&record) {
+            log!(LogLevel::Error, "Failed to write audit record for {}: {}", message_id, e);
+        }
+    }
+
+    fn append(&self, record: &AuditRecord) -> std::io::Result<()> {
+        let path = Path::new(&self.config.path);
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        self.rotate(path)?;
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)
+    }
+
+    // Shifts audit.log to audit.log.1 and so on once it passes the size limit, dropping the oldest
+    fn rotate(&self, path: &Path) -> std::io::Result<()> {
+        let size = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Ok(()),
+        };
+        if size < self.config.max_size_mb * 1024 * 1024 {
+            return Ok(());
+        }
+
+        let numbered = |index: usize| format!("{}.{}", path.display(), index);
+        for index in (1..self.config.keep).rev() {
+            if Path::new(&numbered(index)).exists() {
+                fs::rename(numbered(index), numbered(index + 1))?;
+            }
+        }
+        match self.config.keep {
+            0 => fs::remove_file(path),
+            _ => fs::rename(path, numbered(1)),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::config::AuthConfig;
+
+// A submission wrapped with the fields it is signed over, `message` is the payload as it would be sent unsigned
+#[derive(Debug, Serialize, Deserialize)]
+struct Signed {
+    timestamp: i64,
+    nonce: String,
+    // Hex HMAC-SHA256 of "{timestamp}.{nonce}.{message}"
+    signature: String,
+    message: String,
+    // Which of `auth.keys` signed it, the shared `auth.secret` when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+}
+
+// Nonces seen inside the accepted clock skew, anything older is refused on its timestamp instead
+#[derive(Debug, Default)]
+pub struct NonceCache {
+    seen: HashMap<String, i64>,
+}
+
+impl NonceCache {
+    fn prune(&mut self, now: i64, skew: i64) {
+        self.seen.retain(|_, timestamp| (now - *timestamp).abs() <= skew);
+    }
+}
+
+fn signature(secret: &str, timestamp: i64, nonce: &str, message: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC key of any size");
+    mac.update(format!("{}.{}.{}", timestamp, nonce, message).as_bytes());
+    mac
+}
+
+// Wraps a payload for a server that requires signed submissions, naming `key_id` when the key is one of its `auth.keys`
+pub fn sign(secret: &str, key_id: Option<&str>, message: &str) -> Result<String, ErrorArrayItem> {
+    let timestamp = Utc::now().timestamp();
+    let nonce = Uuid::new_v4().to_string();
+    let mac = signature(secret, timestamp, &nonce, message);
+    let signed = Signed {
+        timestamp,
+        nonce,
+        signature: hex::encode(mac.finalize().into_bytes()),
+        message: message.to_owned(),
+        key_id: key_id.map(str::to_owned),
+    };
+    serde_json::to_string(&signed).map_err(ErrorArrayItem::from)
+}
+
+fn refused(reason: impl Into<String>) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::AuthenticationError, format!("auth: {}", reason.into()))
+}
+
+// Checks the signature and freshness of a signed submission, returning the payload inside it and the id of the key
+// that signed it, None for the shared secret
+pub fn verify(
+    config: &AuthConfig,
+    nonces: &mut NonceCache,
+    payload: &str,
+) -> Result<(String, Option<String>), ErrorArrayItem> {
+    if config.secret.is_none() && config.keys.is_empty() {
+        return Ok((payload.to_owned(), None));
+    }
+
+    let signed: Signed = serde_json::from_str(payload).map_err(|_| refused("submission isn't signed"))?;
+    let secret = match &signed.key_id {
+        Some(id) => config.keys.get(id).ok_or_else(|| refused(format!("unknown key {}", id)))?,
+        None => config.secret.as_ref().ok_or_else(|| refused("submission names no key"))?,
+    };
+
+    let mac = signature(secret, signed.timestamp, &signed.nonce, &signed.message);
+    let signature = hex::decode(&signed.signature).map_err(|_| refused("malformed signature"))?;
+    mac.verify_slice(&signature).map_err(|_| refused("bad signature"))?;
+
+    // Only checked once the signature holds, so forged requests can't fill the cache
+    let now = Utc::now().timestamp();
+    let skew = config.max_skew_seconds as i64;
+    if (now - signed.timestamp).abs() > skew {
+        return Err(refused(format!("timestamp {} is outside the {}s window", signed.timestamp, skew)));
+    }
+    if signed.nonce.is_empty() {
+        return Err(refused("missing nonce"));
+    }
+
+    nonces.prune(now, skew);
+    if nonces.seen.insert(signed.nonce.clone(), signed.timestamp).is_some() {
+        return Err(refused(format!("nonce {} was already used", signed.nonce)));
+    }
+
+    Ok((signed.message, signed.key_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            secret: Some(String::from("shared")),
+            keys: HashMap::from([(String::from("ci"), String::from("ci-key"))]),
+            ..AuthConfig::default()
+        }
+    }
+
+    // A submission signed at `timestamp`, for checking the window without waiting on the clock
+    fn signed_at(secret: &str, timestamp: i64, nonce: &str, message: &str) -> String {
+        let mac = signature(secret, timestamp, nonce, message);
+        let signed = Signed {
+            timestamp,
+            nonce: nonce.to_owned(),
+            signature: hex::encode(mac.finalize().into_bytes()),
+            message: message.to_owned(),
+            key_id: None,
+        };
+        serde_json::to_string(&signed).unwrap()
+    }
+
+    #[test]
+    fn accepts_what_it_signed() {
+        let mut nonces = NonceCache::default();
+        let payload = sign("shared", None, "{}").unwrap();
+        assert_eq!(verify(&config(), &mut nonces, &payload).unwrap(), (String::from("{}"), None));
+
+        let payload = sign("ci-key", Some("ci"), "{}").unwrap();
+        assert_eq!(verify(&config(), &mut nonces, &payload).unwrap(), (String::from("{}"), Some(String::from("ci"))));
+    }
+
+    #[test]
+    fn passes_unsigned_submissions_only_without_keys() {
+        let mut nonces = NonceCache::default();
+        assert!(verify(&AuthConfig::default(), &mut nonces, "{}").is_ok());
+        assert!(verify(&config(), &mut nonces, "{}").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let mut nonces = NonceCache::default();
+        let tampered = sign("shared", None, "{\"to\":[\"a\"]}").unwrap().replace("[\\\"a\\\"]", "[\\\"b\\\"]");
+        assert!(verify(&config(), &mut nonces, &tampered).unwrap_err().err_mesg.contains("bad signature"));
+
+        let wrong_key = sign("guessed", None, "{}").unwrap();
+        assert!(verify(&config(), &mut nonces, &wrong_key).is_err());
+
+        let unknown = sign("ci-key", Some("deploy"), "{}").unwrap();
+        assert!(verify(&config(), &mut nonces, &unknown).unwrap_err().err_mesg.contains("unknown key"));
+    }
+
+    #[test]
+    fn rejects_replayed_nonces() {
+        let mut nonces = NonceCache::default();
+        let payload = sign("shared", None, "{}").unwrap();
+        assert!(verify(&config(), &mut nonces, &payload).is_ok());
+        assert!(verify(&config(), &mut nonces, &payload).unwrap_err().err_mesg.contains("already used"));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_skew() {
+        let mut nonces = NonceCache::default();
+        let now = Utc::now().timestamp();
+        for timestamp in [now - 301, now + 301] {
+            let payload = signed_at("shared", timestamp, "n1", "{}");
+            assert!(verify(&config(), &mut nonces, &payload).unwrap_err().err_mesg.contains("outside"));
+        }
+        assert!(verify(&config(), &mut nonces, &signed_at("shared", now - 299, "n2", "{}")).is_ok());
+        assert!(verify(&config(), &mut nonces, &signed_at("shared", now, "", "{}")).is_err());
+    }
+
+    #[test]
+    fn prunes_nonces_outside_the_skew() {
+        let mut nonces = NonceCache::default();
+        nonces.seen.insert(String::from("old"), 1_000);
+        nonces.seen.insert(String::from("recent"), 1_250);
+        nonces.prune(1_300, 100);
+        assert!(!nonces.seen.contains_key("old"));
+        assert!(nonces.seen.contains_key("recent"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use futures::StreamExt;
+use mailparse::{parse_headers, parse_mail, MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    config::BounceConfig,
+    imap::{imap_error, login},
+};
+
+// One failed recipient reported by a delivery status notification
+#[derive(Debug, Clone)]
+pub struct Bounce {
+    pub recipient: String,
+    // Enhanced status code, 5.x.x is permanent and 4.x.x temporary
+    pub status: String,
+    pub diagnostic: Option<String>,
+    // Our message id, from the returned Message-ID or a VERP return address
+    pub message_id: Option<String>,
+}
+
+impl Bounce {
+    pub fn is_hard(&self) -> bool {
+        self.status.starts_with('5')
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BounceRecord {
+    pub hard: u32,
+    pub soft: u32,
+    pub last_status: String,
+    pub last_diagnostic: Option<String>,
+    pub last_message_id: Option<String>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+// Bounce history per recipient address, persisted between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BounceStore {
+    pub recipients: HashMap<String, BounceRecord>,
+}
+
+impl BounceStore {
+    pub async fn load(path: &str) -> Self {
+        match fs::read_to_string(path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                log!(LogLevel::Error, "Ignoring unreadable bounce store {}: {}", path, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub async fn save(&self, path: &str) -> Result<(), ErrorArrayItem> {
+        let data = serde_json::to_vec_pretty(self).map_err(ErrorArrayItem::from)?;
+        fs::write(path, data)
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("bounces: {}: {}", path, e)))
+    }
+
+    pub fn record(&mut self, bounce: &Bounce) {
+        let record = self.recipients.entry(bounce.recipient.to_lowercase()).or_default();
+        match bounce.is_hard() {
+            true => record.hard += 1,
+            false => record.soft += 1,
+        }
+        record.last_status = bounce.status.clone();
+        record.last_diagnostic = bounce.diagnostic.clone();
+        record.last_message_id = bounce.message_id.clone();
+        record.last_seen = Some(Utc::now());
+    }
+}
+
+// Fetches unread mail from the bounce mailbox, fetching marks it read so each report is counted once
+pub async fn poll_bounces(config: &BounceConfig) -> Result<Vec<Bounce>, ErrorArrayItem> {
+    let mut session = login(&config.imap).await?;
+    session
+        .select(config.imap.mailbox.as_deref().unwrap_or("INBOX"))
+        .await
+        .map_err(imap_error)?;
+
+    let unseen = session.search("UNSEEN").await.map_err(imap_error)?;
+    let mut bounces = Vec::new();
+
+    if !unseen.is_empty() {
+        let set = unseen.iter().map(|seq| seq.to_string()).collect::<Vec<_>>().join(",");
+        let mut messages = session.fetch(set, "RFC822").await.map_err(imap_error)?;
+        while let Some(message) = messages.next().await {
+            let message = message.map_err(imap_error)?;
+            if let Some(body) = message.body() {
+                match parse_dsn(body) {
+                    Some(found) => bounces.extend(found),
+                    None => log!(LogLevel::Debug, "Skipping a message in the bounce mailbox that isn't a DSN"),
+                }
+            }
+        }
+    }
+
+    let _ = session.logout().await;
+    Ok(bounces)
+}
+
+// Reads the per-recipient fields of a multipart/report delivery status notification
+pub fn parse_dsn(raw: &[u8]) -> Option<Vec<Bounce>> {
+    let mail = parse_mail(raw).ok()?;
+    let status = find_part(&mail, "message/delivery-status")?.get_body_raw().ok()?;
+
+    // The original headers come back either alone or as a full message
+    let message_id = find_part(&mail, "text/rfc822-headers")
+        .or_else(|| find_part(&mail, "message/rfc822"))
+        .and_then(|part| part.get_body_raw().ok())
+        .and_then(|original| {
+            let (headers, _) = parse_headers(&original).ok()?;
+            headers.get_first_value("Message-ID")
+        })
+        .and_then(|id| our_message_id(&id))
+        .or_else(|| verp_message_id(&mail));
+
+    // Per-message fields come first, then one block per recipient
+    let status = String::from_utf8_lossy(&status).replace("\r\n", "\n");
+    let bounces: Vec<Bounce> = status
+        .split("\n\n")
+        .skip(1)
+        .filter_map(|block| {
+            let (fields, _) = parse_headers(block.as_bytes()).ok()?;
+            let action = fields.get_first_value("Action")?.to_lowercase();
+            if action != "failed" && action != "delayed" {
+                return None;
+            }
+
+            let recipient = fields
+                .get_first_value("Final-Recipient")
+                .or_else(|| fields.get_first_value("Original-Recipient"))?;
+            Some(Bounce {
+                recipient: address_of(&recipient),
+                status: fields.get_first_value("Status")?.trim().to_owned(),
+                diagnostic: fields.get_first_value("Diagnostic-Code"),
+                message_id: message_id.clone(),
+            })
+        })
+        .collect();
+
+    Some(bounces)
+}
+
+fn find_part<'a>(mail: &'a ParsedMail<'a>, mimetype: &str) -> Option<&'a ParsedMail<'a>> {
+    if mail.ctype.mimetype.eq_ignore_ascii_case(mimetype) {
+        return Some(mail);
+    }
+    mail.subparts.iter().find_map(|part| find_part(part, mimetype))
+}
+
+// "rfc822; user@example.com" to "user@example.com"
+fn address_of(field: &str) -> String {
+    field.rsplit(';').next().unwrap_or(field).trim().trim_matches(['<', '>']).to_owned()
+}
+
+// `<id@host>` to `id`, the queue id we put in outgoing Message-ID headers
+fn our_message_id(header: &str) -> Option<String> {
+    let id = header.trim().trim_matches(['<', '>']);
+    id.split('@').next().filter(|id| !id.is_empty()).map(str::to_owned)
+}
+
+// VERP returns arrive at `bounces+<id>@domain`
+fn verp_message_id(mail: &ParsedMail) -> Option<String> {
+    let to = mail.headers.get_first_value("To")?;
+    let local = address_of(&to).split('@').next()?.to_owned();
+    local.split_once('+').map(|(_, id)| id.to_owned())
+}
@@ -0,0 +1,74 @@
+use std::time::{Duration, Instant};
+
+use dusa_collection_utils::{log, log::LogLevel};
+
+use crate::{config::CircuitBreakerConfig, retry::Failure};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BreakerState {
+    #[default]
+    Closed,
+    // Sends to the relay are paused until the cool-down has passed
+    Open(Instant),
+    // One probe send decides whether to close or open again
+    HalfOpen,
+}
+
+// Stops relay sends after repeated connection or auth failures, mail keeps queueing meanwhile
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    state: BreakerState,
+    failures: u32,
+}
+
+impl CircuitBreaker {
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    // Whether a message may go to the relay now, moving to half-open once the cool-down has passed
+    pub fn allow(&mut self, config: &CircuitBreakerConfig) -> bool {
+        if !config.enabled {
+            return true;
+        }
+
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(opened) if opened.elapsed() >= Duration::from_secs(config.cooldown_seconds) => {
+                log!(LogLevel::Info, "Relay circuit half-open, sending a probe");
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open(_) | BreakerState::HalfOpen => false,
+        }
+    }
+
+    // Any reply from the relay, even a refusal, proves it is reachable
+    pub fn record(&mut self, config: &CircuitBreakerConfig, failure: Option<Failure>) -> bool {
+        if !config.enabled {
+            return false;
+        }
+
+        if failure != Some(Failure::Connection) {
+            if self.state != BreakerState::Closed {
+                log!(LogLevel::Info, "Relay answered, circuit closed");
+            }
+            self.state = BreakerState::Closed;
+            self.failures = 0;
+            return false;
+        }
+
+        self.failures += 1;
+        if self.state == BreakerState::HalfOpen || self.failures >= config.threshold {
+            log!(
+                LogLevel::Warn,
+                "Relay circuit open after {} consecutive failures, pausing sends for {}s",
+                self.failures,
+                config.cooldown_seconds
+            );
+            self.state = BreakerState::Open(Instant::now());
+            return true;
+        }
+        false
+    }
+}
@@ -0,0 +1,58 @@
+use dusa_collection_utils::{log, log::LogLevel};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    config::{AppConfig, ChannelConfig},
+    discord::post_discord,
+    matrix::post_matrix,
+    pagerduty::post_pagerduty,
+    payload::EmailPayload,
+    push::{post_gotify, post_ntfy},
+    retry::SendError,
+    slack::post_slack,
+    telegram::post_telegram,
+    webhook::post_webhook,
+};
+
+// Posts to every channel on the payload, keeping only the ones that failed so a retry doesn't repeat the rest
+pub async fn notify_channels(config: &AppConfig, email: &mut EmailPayload) -> Result<(), SendError> {
+    let mut failed: Vec<String> = Vec::new();
+    let mut last_error: Option<SendError> = None;
+
+    for name in &email.channels {
+        let channel = match config.channels.get(name) {
+            Some(channel) => channel,
+            None => {
+                log!(LogLevel::Error, "Dropping unknown channel: {}", name);
+                continue;
+            }
+        };
+
+        let result = async {
+            match channel {
+                ChannelConfig::Slack(slack) => post_slack(slack, email).await,
+                ChannelConfig::Discord(discord) => post_discord(discord, email).await,
+                ChannelConfig::Telegram(telegram) => post_telegram(telegram, email).await,
+                ChannelConfig::Matrix(matrix) => post_matrix(matrix, email).await,
+                ChannelConfig::Ntfy(ntfy) => post_ntfy(ntfy, email).await,
+                ChannelConfig::Gotify(gotify) => post_gotify(gotify, email).await,
+                ChannelConfig::PagerDuty(pagerduty) => post_pagerduty(pagerduty, email).await,
+                ChannelConfig::Webhook(webhook) => post_webhook(webhook, email).await,
+            }
+        }
+        .instrument(info_span!("channel", channel = %name))
+        .await;
+
+        if let Err(e) = result {
+            log!(LogLevel::Error, "Failed to notify channel {}: {}", name, e);
+            failed.push(name.clone());
+            last_error = Some(e);
+        }
+    }
+
+    email.channels = failed;
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::BTreeMap, path::Path};
+
+use lettre::{message::Mailbox, Address};
+
+use crate::{
+    config::{AppConfig, FilterConfig, TransportKind},
+    email::Keyring,
+    headers::reserved_header,
+    script::Script,
+    transport::transport_for,
+};
+
+// Recipient lists may name a group instead of an address
+fn check_recipients(config: &AppConfig, field: &str, recipients: &[String], problems: &mut Vec<String>) {
+    for recipient in recipients {
+        if config.groups.contains_key(recipient) {
+            continue;
+        }
+        if let Err(e) = recipient.parse::<Mailbox>() {
+            problems.push(format!("{}: invalid address {:?}: {}", field, recipient, e));
+        }
+    }
+}
+
+fn check_channels(config: &AppConfig, field: &str, channels: &[String], problems: &mut Vec<String>) {
+    for channel in channels {
+        if !config.channels.contains_key(channel) {
+            problems.push(format!("{}: unknown channel {:?}", field, channel));
+        }
+    }
+}
+
+// Envelope senders are bare addresses, without a display name
+fn check_return_path(field: &str, return_path: &Option<String>, problems: &mut Vec<String>) {
+    if let Some(address) = return_path {
+        if let Err(e) = address.parse::<Address>() {
+            problems.push(format!("{}: invalid address {:?}: {}", field, address, e));
+        }
+    }
+}
+
+fn check_sender(config: &AppConfig, field: &str, address: &str, problems: &mut Vec<String>) {
+    let mailbox = match address.parse::<Mailbox>() {
+        Ok(mailbox) => mailbox,
+        Err(e) => {
+            problems.push(format!("{}: invalid address {:?}: {}", field, address, e));
+            return;
+        }
+    };
+    let domains = &config.allowlist.from_domains;
+    if !domains.is_empty() && !domains.iter().any(|allowed| allowed.eq_ignore_ascii_case(mailbox.email.domain())) {
+        problems.push(format!("{}: {} is outside allowlist.from_domains", field, address));
+    }
+}
+
+// Names go out as is, so anything lettre would reject is caught here instead of on every send
+fn check_headers(field: &str, headers: &BTreeMap<String, String>, problems: &mut Vec<String>) {
+    for (name, value) in headers {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_graphic() && c != ':') {
+            problems.push(format!("{}: invalid header name {:?}", field, name));
+        }
+        if reserved_header(name) {
+            problems.push(format!("{}: {} is set by the server and can't be configured", field, name));
+        }
+        if value.contains(['\r', '\n']) {
+            problems.push(format!("{}.{}: value must be a single line", field, name));
+        }
+    }
+}
+
+fn check_file(field: &str, path: &str, problems: &mut Vec<String>) {
+    if !Path::new(path).is_file() {
+        problems.push(format!("{}: {} does not exist", field, path));
+    }
+}
+
+// Collects every problem in the loaded config rather than stopping at the first
+pub fn check_config(config: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let smtp = &config.smtp;
+
+    check_sender(config, "smtp.from", &smtp.from, &mut problems);
+    check_return_path("smtp.return_path", &smtp.return_path, &mut problems);
+    check_headers("headers.custom", &config.headers.custom, &mut problems);
+    if config.senders.enabled {
+        for (client, address) in &config.senders.clients {
+            check_sender(config, &format!("senders.clients.{}", client), address, &mut problems);
+        }
+        if let Some(subaddress) = &config.senders.subaddress {
+            check_sender(config, "senders.subaddress", &subaddress.replace("{client}", "client"), &mut problems);
+        }
+    }
+    check_recipients(config, "smtp.to", &smtp.to, &mut problems);
+    if smtp.to.is_empty() {
+        problems.push(String::from("smtp.to: no default recipients"));
+    }
+
+    let mut transports = vec![config.app.transport];
+    for kind in config.rules.iter().filter_map(|rule| rule.transport) {
+        if !transports.contains(&kind) {
+            transports.push(kind);
+        }
+    }
+    if transports.contains(&TransportKind::Smtp) {
+        if smtp.server.is_empty() {
+            problems.push(String::from("smtp.server: required by the smtp transport"));
+        }
+        if smtp.port == 0 {
+            problems.push(String::from("smtp.port: must be between 1 and 65535"));
+        }
+    }
+    for kind in transports {
+        match transport_for(config, kind, None) {
+            Ok(transport) if !transport.carries_mime() => {
+                if smtp.dkim.is_some() {
+                    problems.push(format!("smtp.dkim: the {:?} transport can't carry DKIM signatures", kind));
+                }
+                if !smtp.smime.is_empty() {
+                    problems.push(format!("smtp.smime: the {:?} transport can't carry S/MIME signatures", kind));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(e.err_mesg.to_string()),
+        }
+    }
+
+    if let Some(path) = &smtp.tls_ca_path {
+        check_file("smtp.tls_ca_path", path, &mut problems);
+    }
+    for (name, path) in &config.assets {
+        check_file(&format!("assets.{}", name), path, &mut problems);
+    }
+    if let Err(e) = Keyring::load(config) {
+        problems.push(format!("keys: {}", e.err_mesg));
+    }
+    if let Err(e) = Script::load(&config.scripting) {
+        problems.push(e.err_mesg.to_string());
+    }
+
+    for (group, members) in &config.groups {
+        check_recipients(config, &format!("groups.{}", group), members, &mut problems);
+    }
+    for (severity, recipients) in &config.routing.severity {
+        check_recipients(config, &format!("routing.severity.{}", severity), recipients, &mut problems);
+    }
+    let layouts = config.templates.categories.iter().map(|(category, template)| (format!("categories.{}", category), template));
+    let layouts = layouts.chain(config.templates.severity.iter().map(|(severity, template)| (format!("severity.{}", severity), template)));
+    for (name, template) in layouts.chain(config.templates.generic.iter().map(|template| (String::from("generic"), template))) {
+        if template.text.is_none() && template.html.is_none() {
+            problems.push(format!("templates.{}: needs a text or html layout", name));
+        }
+    }
+    if config.localization.enabled && config.localization.default_locale.trim().is_empty() {
+        problems.push(String::from("localization.default_locale: must not be empty"));
+    }
+    for rule in &config.rules {
+        check_recipients(config, &format!("rules.{}.to", rule.name), &rule.to, &mut problems);
+        check_channels(config, &format!("rules.{}.channels", rule.name), &rule.channels, &mut problems);
+        check_return_path(&format!("rules.{}.return_path", rule.name), &rule.return_path, &mut problems);
+        check_headers(&format!("rules.{}.headers", rule.name), &rule.headers, &mut problems);
+        if rule.replace_email && rule.channels.is_empty() {
+            problems.push(format!("rules.{}: replace_email without any channels", rule.name));
+        }
+    }
+
+    for filter in &config.filters {
+        match filter {
+            FilterConfig::Size(size) if size.max_kb == 0 => {
+                problems.push(String::from("filters.size: max_kb must be at least 1"))
+            }
+            FilterConfig::Dedup(dedup) if dedup.window_seconds == 0 => {
+                problems.push(String::from("filters.dedup: window_seconds must be at least 1"))
+            }
+            FilterConfig::Exec(exec) if exec.command.is_empty() => {
+                problems.push(String::from("filters.exec: command is empty"))
+            }
+            FilterConfig::Exec(exec) if exec.timeout_seconds == 0 => {
+                problems.push(format!("filters.{}: timeout_seconds must be at least 1", filter.name()))
+            }
+            _ => {}
+        }
+    }
+
+    if config.escalation.enabled {
+        check_channels(config, "escalation.channels", &config.escalation.channels, &mut problems);
+        check_recipients(config, "escalation.notify", &config.escalation.notify, &mut problems);
+        if config.escalation.sms && config.twilio.is_none() {
+            problems.push(String::from("escalation.sms: requires a [twilio] section"));
+        }
+    }
+    if config.error_digest.enabled {
+        check_recipients(config, "error_digest.to", &config.error_digest.to, &mut problems);
+    }
+    if config.report.enabled || config.report.weekly {
+        check_recipients(config, "report.to", &config.report.to, &mut problems);
+    }
+    if config.report.enabled && config.report.top == 0 {
+        problems.push(String::from("report.top: must list at least one entry"));
+    }
+    if config.report.weekly && config.report.surge_factor <= 1.0 {
+        problems.push(String::from("report.surge_factor: must be greater than 1"));
+    }
+    if config.heartbeat.enabled {
+        check_recipients(config, "heartbeat.to", &config.heartbeat.to, &mut problems);
+        if config.heartbeat.interval_hours == 0 {
+            problems.push(String::from("heartbeat.interval_hours: must be at least 1"));
+        }
+    }
+    if config.monitor.enabled {
+        check_channels(config, "monitor.channels", &config.monitor.channels, &mut problems);
+    }
+    check_recipients(config, "self_test.canary_to", &config.self_test.canary_to, &mut problems);
+    if config.journal.enabled && config.journal.units.is_empty() {
+        problems.push(String::from("journal.units: no units listed, every excerpt request will be refused"));
+    }
+    if config.suppression.enabled {
+        check_recipients(config, "suppression.addresses", &config.suppression.addresses, &mut problems);
+    }
+
+    if config.retry.multiplier < 1.0 {
+        problems.push(String::from("retry.multiplier: must be at least 1.0"));
+    }
+    if config.retry.max_lifetime_seconds == 0 {
+        problems.push(String::from("retry.max_lifetime_seconds: must be at least 1"));
+    }
+
+    if config.circuit_breaker.enabled && config.circuit_breaker.threshold == 0 {
+        problems.push(String::from("circuit_breaker.threshold: must be at least 1"));
+    }
+
+    if config.app.loop_interval_seconds == 0 {
+        problems.push(String::from("app.loop_interval_seconds: must be at least 1"));
+    }
+    if config.rate_limit.per_minute == 0 {
+        problems.push(String::from("rate_limit.per_minute: must be at least 1"));
+    }
+    if config.rate_limit.burst == 0 {
+        problems.push(String::from("rate_limit.burst: must be at least 1"));
+    }
+    if let Some(per_recipient) = &config.rate_limit.per_recipient {
+        if per_recipient.per_minute == 0 || per_recipient.burst == 0 {
+            problems.push(String::from("rate_limit.per_recipient: per_minute and burst must be at least 1"));
+        }
+    }
+    if config.connections.max_total == 0 || config.connections.max_per_peer == 0 {
+        problems.push(String::from("connections: max_total and max_per_peer must be at least 1"));
+    }
+    if config.connections.read_timeout_seconds == 0 {
+        problems.push(String::from("connections.read_timeout_seconds: must be at least 1"));
+    }
+    if config.bans.enabled && config.bans.max_failures == 0 {
+        problems.push(String::from("bans.max_failures: must be at least 1"));
+    }
+    if config.admin.enabled && config.admin.password.as_deref().unwrap_or_default().is_empty() {
+        problems.push(String::from("admin.password: required when the dashboard is enabled"));
+    }
+
+    let submission = &config.submission;
+    if submission.enabled {
+        if submission.users.is_empty() {
+            problems.push(String::from("submission.users: at least one login is required"));
+        }
+        match (&submission.tls_cert_path, &submission.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                check_file("submission.tls_cert_path", cert, &mut problems);
+                check_file("submission.tls_key_path", key, &mut problems);
+            }
+            (None, None) => {}
+            _ => problems.push(String::from("submission: tls_cert_path and tls_key_path must be set together")),
+        }
+        if submission.require_tls && (submission.tls_cert_path.is_none() || submission.tls_key_path.is_none()) {
+            problems.push(String::from("submission.require_tls: needs tls_cert_path and tls_key_path"));
+        }
+        if submission.command_timeout_seconds == 0 {
+            problems.push(String::from("submission.command_timeout_seconds: must be at least 1"));
+        }
+        if submission.max_recipients == 0 {
+            problems.push(String::from("submission.max_recipients: must be at least 1"));
+        }
+    }
+    if config.app.workers == 0 {
+        problems.push(String::from("app.workers: must be at least 1"));
+    }
+
+    problems
+}
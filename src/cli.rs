@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+
+use clap::Parser;
+use dusa_collection_utils::log::LogLevel;
+use mail_regulator::parse_time;
+
+// Command line overrides, anything not given falls back to the config files
+#[derive(Parser, Debug)]
+#[command(version, about = "Queues and delivers notification mail for Artisan services")]
+pub struct Args {
+    /// Config file to load, the extension is optional
+    #[arg(long, default_value = "Config")]
+    pub config: String,
+
+    /// Address the listener binds to, overrides app.bind
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+
+    /// Port the listener binds to, overrides app.port
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// error, warn, info, debug or trace
+    #[arg(long, value_parser = parse_log_level)]
+    pub log_level: Option<LogLevel>,
+
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Read a message from stdin and queue it on the running server, like sendmail
+    #[arg(long)]
+    pub submit: bool,
+
+    /// With --submit, take recipients from the To, Cc and Bcc headers
+    #[arg(short = 't', requires = "submit")]
+    pub read_recipients: bool,
+
+    /// With --submit, recipients for the message
+    #[arg(requires = "submit")]
+    pub recipients: Vec<String>,
+
+    /// List what the running server has queued and its recent errors, over the admin API
+    #[arg(long)]
+    pub queue: bool,
+
+    /// Send a queued or held message on the next round, ignoring its backoff
+    #[arg(long, value_name = "ID", conflicts_with = "purge")]
+    pub retry: Option<String>,
+
+    /// Drop a queued or held message for good
+    #[arg(long, value_name = "ID")]
+    pub purge: Option<String>,
+
+    /// Put dead-lettered mail back in the running server's queue, chosen with --all, --id, --since and --until
+    #[arg(long)]
+    pub requeue_dead: bool,
+
+    /// With --requeue-dead, every dead letter
+    #[arg(long, requires = "requeue_dead")]
+    pub all: bool,
+
+    /// With --requeue-dead, a dead letter to requeue, may be repeated
+    #[arg(long = "id", value_name = "ID", requires = "requeue_dead")]
+    pub ids: Vec<String>,
+
+    /// With --requeue-dead, only mail that failed at or after this date or RFC 3339 time
+    #[arg(long, value_parser = parse_time, requires = "requeue_dead")]
+    pub since: Option<DateTime<Utc>>,
+
+    /// With --requeue-dead, only mail that failed before this date or RFC 3339 time
+    #[arg(long, value_parser = parse_time, requires = "requeue_dead")]
+    pub until: Option<DateTime<Utc>>,
+
+    /// Send everything to an embedded SMTP server on localhost that prints what it receives
+    #[arg(long)]
+    pub debug_smtp: bool,
+}
+
+fn parse_log_level(level: &str) -> Result<LogLevel, String> {
+    match level.to_lowercase().as_str() {
+        "error" => Ok(LogLevel::Error),
+        "warn" | "warning" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        "trace" => Ok(LogLevel::Trace),
+        _ => Err(format!("unknown log level {:?}", level)),
+    }
+}
@@ -1,28 +1,1487 @@
-use std::fmt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
 
+use ::config::{Config, Environment, File, Map};
 use colored::Colorize;
-use serde::Deserialize;
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Tz;
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    payload::{Priority, Severity},
+    protocol::LegacyFormat,
+};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
     pub smtp: SmtpConfig,
     pub app: AppSettings,
+    #[serde(default)]
+    pub digest: DigestConfig,
+    #[serde(default)]
+    pub routing: RoutingConfig,
+    #[serde(default)]
+    pub templates: TemplatesConfig,
+    #[serde(default)]
+    pub localization: LocalizationConfig,
+    #[serde(default)]
+    pub senders: SendersConfig,
+    #[serde(default)]
+    pub allowlist: AllowlistConfig,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    // Checks and rewrites every submission passes through before it is queued, in order
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    #[serde(default)]
+    pub scripting: ScriptingConfig,
+    // Named distribution lists, referenced by name anywhere a recipient is accepted
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    // Image files attached inline when an HTML body references cid:<name>
+    #[serde(default)]
+    pub assets: HashMap<String, String>,
+    #[serde(default)]
+    pub quiet_hours: QuietHoursConfig,
+    #[serde(default)]
+    pub pgp: PgpConfig,
+    #[serde(default)]
+    pub file: FileTransportConfig,
+    #[serde(default)]
+    pub maildir: MaildirConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    #[serde(default)]
+    pub attachments: AttachmentConfig,
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub connections: ConnectionConfig,
+    #[serde(default)]
+    pub bans: BanConfig,
+    #[serde(default)]
+    pub inbox: InboxConfig,
+    #[serde(default)]
+    pub submission: SubmissionConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub suppression: SuppressionConfig,
+    // Append delivered mail to a Sent folder over IMAP
+    pub imap: Option<ImapConfig>,
+    // Poll a bounce mailbox for delivery status notifications
+    pub bounces: Option<BounceConfig>,
+    pub ses: Option<SesConfig>,
+    pub sendgrid: Option<SendGridConfig>,
+    pub mailgun: Option<MailgunConfig>,
+    // Texted when a critical email expires without being delivered
+    pub twilio: Option<TwilioConfig>,
+    // Fetch the SMTP username and password from Vault instead of the config
+    pub vault: Option<VaultConfig>,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    #[serde(default)]
+    pub escalation: EscalationConfig,
+    #[serde(default)]
+    pub error_digest: ErrorDigestConfig,
+    #[serde(default)]
+    pub report: ReportConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub monitor: MonitorConfig,
+    #[serde(default)]
+    pub self_test: SelfTestConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub statsd: StatsdConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    // Non-email destinations, referenced by name from rules and payloads
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChannelConfig {
+    Slack(SlackConfig),
+    Discord(DiscordConfig),
+    Telegram(TelegramConfig),
+    Matrix(MatrixConfig),
+    Ntfy(NtfyConfig),
+    Gotify(GotifyConfig),
+    PagerDuty(PagerDutyConfig),
+    Webhook(WebhookConfig),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SlackConfig {
+    pub webhook_url: String,
+    // Section text in Slack mrkdwn, supports {subject} {body} {client} {tags} {priority}
+    #[serde(default = "default_channel_template")]
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiscordConfig {
+    pub webhook_url: String,
+    // Embed description, supports {subject} {body} {client} {tags} {priority}
+    #[serde(default = "default_channel_template")]
+    pub template: String,
+    // Overrides the name set on the webhook
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TelegramConfig {
+    pub bot_token: String,
+    // Numeric chat id, or @name for public channels
+    pub chat_id: String,
+    #[serde(default = "default_titled_template")]
+    pub template: String,
+    // Mail below this priority isn't posted
+    #[serde(default = "default_telegram_priority")]
+    pub min_priority: Priority,
+    // Point at a self-hosted Bot API server instead
+    #[serde(default = "default_telegram_api")]
+    pub api_url: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MatrixConfig {
+    pub homeserver: String,
+    pub access_token: String,
+    // Internal room id, e.g. !abcdef:matrix.org
+    pub room_id: String,
+    #[serde(default = "default_titled_template")]
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NtfyConfig {
+    // Server root, e.g. https://ntfy.sh
+    pub server: String,
+    pub topic: String,
+    // Access token for protected topics
+    pub token: Option<String>,
+    #[serde(default = "default_channel_template")]
+    pub template: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct GotifyConfig {
+    pub server: String,
+    pub app_token: String,
+    #[serde(default = "default_channel_template")]
+    pub template: String,
+}
+
+// Only mail with critical severity or priority raises an event
+#[derive(Debug, Deserialize, Clone)]
+pub struct PagerDutyConfig {
+    // Integration key of an Events API v2 service
+    pub routing_key: String,
+    #[serde(default = "default_pagerduty_summary")]
+    pub summary: String,
+    #[serde(default = "default_pagerduty_url")]
+    pub events_url: String,
+}
+
+fn default_pagerduty_summary() -> String {
+    "{subject}".to_owned()
+}
+
+fn default_pagerduty_url() -> String {
+    "https://events.pagerduty.com/v2/enqueue".to_owned()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    // Signs the body with HMAC-SHA256 when set
+    pub secret: Option<String>,
+    #[serde(default = "default_signature_header")]
+    pub signature_header: String,
+    // Extra request headers, e.g. an API key expected by the receiver
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_signature_header() -> String {
+    "X-Signature-256".to_owned()
+}
+
+fn default_titled_template() -> String {
+    "{subject}\n\n{body}".to_owned()
+}
+
+fn default_telegram_priority() -> Priority {
+    Priority::Critical
+}
+
+fn default_telegram_api() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+fn default_channel_template() -> String {
+    "{body}".to_owned()
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct SmtpConfig {
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    // Read the credentials from files instead, relative paths resolve against $CREDENTIALS_DIRECTORY
+    pub username_file: Option<String>,
+    pub password_file: Option<String>,
     pub server: String,
     pub port: u16,
-    pub to: String,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    // Name expected on the relay certificate when it differs from `server`
+    pub tls_domain: Option<String>,
+    #[serde(default)]
+    pub tls_min_version: TlsMinVersion,
+    // Extra PEM root certificates trusted for the relay, e.g. an internal CA
+    pub tls_ca_path: Option<String>,
+    // Skips certificate verification entirely, never enable this in production
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    #[serde(default)]
+    pub delivery: DeliveryMode,
+    // Send through the relay when direct MX delivery to a domain fails
+    #[serde(default = "default_true")]
+    pub mx_fallback_to_relay: bool,
+    // Hand mail to the local MTA when the relay can't be reached
+    #[serde(default)]
+    pub sendmail_fallback: bool,
+    // Defaults to `sendmail` on the PATH
+    pub sendmail_command: Option<String>,
+    // Name announced in EHLO, defaults to the local hostname
+    pub ehlo_name: Option<String>,
+    // Local address outbound connections are made from on multi-homed hosts
+    pub bind_address: Option<IpAddr>,
+    #[serde(deserialize_with = "string_or_list")]
+    pub to: Vec<String>,
     pub from: String,
+    // Envelope MAIL FROM where bounces are sent, defaults to the From address
+    pub return_path: Option<String>,
+    // Authenticate with XOAUTH2 using a client-credentials token instead of the password
+    pub oauth2: Option<OAuth2Settings>,
+    pub dkim: Option<DkimSettings>,
+    // S/MIME certificates keyed by the From address they sign for
+    #[serde(default)]
+    pub smime: Vec<SmimeSettings>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SmimeSettings {
+    pub from: String,
+    pub cert_path: String,
+    pub key_path: String,
+    pub chain_path: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+// Whether mail goes through the relay or straight to the recipient's MX hosts
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    #[default]
+    Relay,
+    Mx,
+}
+
+// How the connection to the relay is secured
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Implicit TLS, usually port 465
+    #[default]
+    Tls,
+    // Plaintext upgraded with STARTTLS, usually port 587
+    Starttls,
+    // No encryption at all, only for trusted local relays
+    None,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub enum TlsMinVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OAuth2Settings {
+    #[serde(default)]
+    pub tenant_id: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // Defaults to the Microsoft identity platform endpoint for `tenant_id`
+    pub token_url: Option<String>,
+    #[serde(default = "default_oauth2_scope")]
+    pub scope: String,
+}
+
+fn default_oauth2_scope() -> String {
+    String::from("https://outlook.office365.com/.default")
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DkimSettings {
+    pub selector: String,
+    pub domain: String,
+    pub private_key_path: String,
+    #[serde(default)]
+    pub algorithm: DkimAlgorithm,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppSettings {
+    // Listener address, --bind and --port take precedence
+    #[serde(default = "default_bind")]
+    pub bind: IpAddr,
+    #[serde(default = "default_port")]
+    pub port: u16,
     pub loop_interval_seconds: u64,
-    pub rate_limit: usize,
+    // Sends in flight at once within a round
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    // Take turns between submitting clients instead of sending strictly in arrival order
+    #[serde(default = "default_true")]
+    pub fair_scheduling: bool,
+    #[serde(default)]
+    pub transport: TransportKind,
+    // How long shutdown keeps trying to send the queue before spooling the rest
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout_seconds: u64,
+    // Unsent messages are written here on shutdown and requeued on start
+    #[serde(default = "default_spool_path")]
+    pub spool_path: String,
+    // Messages refused permanently are written here, one JSON file each
+    #[serde(default = "default_dead_letter_path")]
+    pub dead_letter_path: String,
+    // SIGUSR2 diagnostics are also written here when set
+    pub diagnostics_path: Option<String>,
+    // Failures kept in the persisted state's error log
+    #[serde(default = "default_error_log_size")]
+    pub error_log_size: usize,
+    // Log hashes and lengths instead of subjects and bodies
+    #[serde(default = "default_true")]
+    pub redact_logs: bool,
+}
+
+fn default_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+fn default_port() -> u16 {
+    1827
+}
+
+fn default_workers() -> usize {
+    4
+}
+
+fn default_error_log_size() -> usize {
+    50
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_spool_path() -> String {
+    "spool.json".to_owned()
+}
+
+fn default_dead_letter_path() -> String {
+    "dead-letter".to_owned()
+}
+
+// Where rendered messages end up
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Smtp,
+    // Pipe messages to `smtp.sendmail_command`
+    Sendmail,
+    // Write messages to `file.directory` instead of sending them
+    File,
+    // Deliver into the maildir at `maildir.path`
+    Maildir,
+    // Amazon SES v2 HTTP API
+    Ses,
+    // SendGrid v3 mail/send API
+    #[serde(rename = "sendgrid")]
+    SendGrid,
+    // Mailgun messages.mime API
+    Mailgun,
+}
+
+// AppRole login and the secret holding the SMTP credentials, KV v1, v2 and leased secrets all work
+#[derive(Debug, Deserialize, Clone)]
+pub struct VaultConfig {
+    pub address: String,
+    pub role_id: String,
+    pub secret_id: String,
+    #[serde(default = "default_vault_auth_mount")]
+    pub auth_mount: String,
+    // Path under /v1, e.g. secret/data/mailregulator/smtp
+    pub secret_path: String,
+    #[serde(default = "default_vault_username_key")]
+    pub username_key: String,
+    #[serde(default = "default_vault_password_key")]
+    pub password_key: String,
+    // How often secrets without a lease are re-read
+    #[serde(default = "default_vault_refresh")]
+    pub refresh_minutes: u64,
+}
+
+fn default_vault_auth_mount() -> String {
+    "approle".to_owned()
+}
+
+fn default_vault_username_key() -> String {
+    "username".to_owned()
+}
+
+fn default_vault_password_key() -> String {
+    "password".to_owned()
+}
+
+fn default_vault_refresh() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TwilioConfig {
+    pub account_sid: String,
+    pub auth_token: String,
+    // Sending number in E.164 form
+    pub from: String,
+    #[serde(deserialize_with = "string_or_list")]
+    pub to: Vec<String>,
+    #[serde(default = "default_sms_template")]
+    pub template: String,
+}
+
+fn default_sms_template() -> String {
+    "Undelivered critical alert: {subject}".to_owned()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MailgunConfig {
+    pub domain: String,
+    pub api_key: String,
+    #[serde(default)]
+    pub region: MailgunRegion,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MailgunRegion {
+    #[default]
+    Us,
+    Eu,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SendGridConfig {
+    pub api_key: String,
+    #[serde(default = "default_sendgrid_endpoint")]
+    pub endpoint: String,
+}
+
+fn default_sendgrid_endpoint() -> String {
+    String::from("https://api.sendgrid.com/v3/mail/send")
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SesConfig {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub configuration_set: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FileTransportConfig {
+    pub directory: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MaildirConfig {
+    pub path: String,
+    // Keep a copy of everything delivered by another transport
+    pub archive: bool,
+}
+
+impl Default for MaildirConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("/var/spool/MailRegulator/Maildir"),
+            archive: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImapConfig {
+    pub server: String,
+    #[serde(default = "default_imap_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    pub username: String,
+    pub password: String,
+    // Defaults to Sent for appends and INBOX for bounce polling
+    pub mailbox: Option<String>,
+}
+
+fn default_imap_port() -> u16 {
+    993
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BounceConfig {
+    pub imap: ImapConfig,
+    #[serde(default = "default_bounce_interval")]
+    pub interval_minutes: u64,
+    // Bounce history per recipient
+    #[serde(default = "default_bounce_store")]
+    pub store_path: String,
+}
+
+fn default_bounce_interval() -> u64 {
+    5
+}
+
+fn default_bounce_store() -> String {
+    "bounces.json".to_owned()
+}
+
+// Caps on open connections, anything past them is refused straight away
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    pub max_total: usize,
+    pub max_per_peer: usize,
+    // How long a client gets to send its whole submission before the connection is dropped
+    pub read_timeout_seconds: u64,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_total: 256,
+            max_per_peer: 16,
+            read_timeout_seconds: 30,
+        }
+    }
+}
+
+// SMTP listener for mail clients, submissions go through the same rules and queue as protocol clients
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SubmissionConfig {
+    pub enabled: bool,
+    pub bind: IpAddr,
+    pub port: u16,
+    // Username to password, AUTH is required before any mail is taken
+    pub users: HashMap<String, String>,
+    // PEM certificate and PKCS#8 key, STARTTLS is offered when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // Refuse AUTH until STARTTLS, startup fails without the certificate and key
+    pub require_tls: bool,
+    pub max_size_kb: u64,
+    // A client silent this long mid-session is disconnected
+    pub command_timeout_seconds: u64,
+    // RCPT commands accepted per message
+    pub max_recipients: usize,
+}
+
+impl Default for SubmissionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 587,
+            users: HashMap::new(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            require_tls: true,
+            max_size_kb: 10 * 1024,
+            command_timeout_seconds: 300,
+            max_recipients: 100,
+        }
+    }
+}
+
+// Web dashboard for looking at and acting on the queue, read at startup only
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub bind: IpAddr,
+    pub port: u16,
+    // HTTP basic auth, the dashboard won't start without a password
+    pub username: String,
+    pub password: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8026,
+            username: String::from("admin"),
+            password: None,
+        }
+    }
+}
+
+// Directory polled for dropped submissions, for tools that can only write files
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct InboxConfig {
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("inbox"),
+        }
+    }
+}
+
+// Temporary bans for peers whose submissions keep getting rejected
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct BanConfig {
+    pub enabled: bool,
+    pub max_failures: u32,
+    pub window_seconds: u64,
+    pub ban_seconds: u64,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_failures: 5,
+            window_seconds: 60,
+            ban_seconds: 600,
+        }
+    }
+}
+
+// Signed submissions, with a timestamp and nonce so captured messages can't be replayed
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    // Shared HMAC key, unsigned submissions are accepted when unset and `keys` is empty
+    pub secret: Option<String>,
+    // Per-client HMAC keys by id, a submission signed with one is known by that id to `senders`
+    pub keys: HashMap<String, String>,
+    // Which of the server's `keys` `secret` is, for the submit command
+    pub key_id: Option<String>,
+    // How far a submission's timestamp may be from the server clock
+    pub max_skew_seconds: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            keys: HashMap::new(),
+            key_id: None,
+            max_skew_seconds: 300,
+        }
+    }
+}
+
+// Formats accepted besides OPTIMIZED, anything else gets a SIDEGRADE asking for a resend
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    pub legacy: Vec<LegacyFormat>,
+    // Compressed payloads bigger than this once inflated are rejected
+    pub max_decompressed_kb: u64,
+    // Limits on submissions split across frames
+    pub max_chunks: u32,
+    pub max_message_kb: u64,
+    pub chunk_timeout_seconds: u64,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            legacy: LegacyFormat::ALL.to_vec(),
+            max_decompressed_kb: 16 * 1024,
+            max_chunks: 256,
+            max_message_kb: 16 * 1024,
+            chunk_timeout_seconds: 30,
+        }
+    }
+}
+
+// Size cap on each submitted attachment
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AttachmentConfig {
+    // 0 disables the limit
+    pub max_size_kb: u64,
+    // Gzip oversized text attachments such as logs before giving up on them
+    pub compress_text: bool,
+}
+
+impl Default for AttachmentConfig {
+    fn default() -> Self {
+        Self {
+            max_size_kb: 10 * 1024,
+            compress_text: true,
+        }
+    }
+}
+
+// Journal excerpts clients can ask to have attached
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct JournalConfig {
+    pub enabled: bool,
+    // Upper bound on the lines a client can request
+    pub max_lines: u32,
+    // Units clients may read, empty allows none
+    pub units: Vec<String>,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_lines: 200,
+            units: Vec::new(),
+        }
+    }
+}
+
+// What the server adds to every outgoing message
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HeadersConfig {
+    // X-Artisan-Client with the submitting client's name
+    pub client_header: bool,
+    // Prefix subjects with [client]
+    pub client_subject_prefix: bool,
+    // Rewrites every subject, supports {env} {hostname} {client} {subject}
+    pub subject_template: Option<String>,
+    // Filled into {env}
+    pub environment: String,
+    // List-Id for recipients' filters, supports {env} {client}, like "{client}.alerts.artisanhosting.net"
+    pub list_id: Option<String>,
+    // X-Category with the payload's category, or its severity when it has none
+    pub category_header: bool,
+    // Auto-Submitted: auto-generated, so autoresponders don't reply
+    pub auto_submitted: bool,
+    // Static headers like X-Mailer added to every message
+    pub custom: BTreeMap<String, String>,
+    // IANA zone the Date and X-Submitted-At headers are written in, like "Europe/Berlin", the host's when unset
+    pub timezone: Option<Tz>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            client_header: true,
+            client_subject_prefix: false,
+            subject_template: None,
+            environment: String::from("production"),
+            list_id: None,
+            category_header: true,
+            auto_submitted: true,
+            custom: BTreeMap::new(),
+            timezone: None,
+        }
+    }
+}
+
+// The From address each client sends as, so one service can't pass its mail off as another's
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SendersConfig {
+    pub enabled: bool,
+    // Authenticated client to its From address: an `auth.keys` id, an SMTP user or the peer IP of a client signing with
+    // the shared secret or not at all
+    pub clients: HashMap<String, String>,
+    // For clients without an entry, like "alerts+{client}@artisanhosting.net". Otherwise they send as `smtp.from`
+    pub subaddress: Option<String>,
+}
+
+// Domains mail may be sent from and to, checked when a submission is accepted. Any domain when empty
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AllowlistConfig {
+    pub from_domains: Vec<String>,
+    // Applies to recipients named by the submission or its routing rule, `smtp.to` and severity routes are trusted
+    pub to_domains: Vec<String>,
+}
+
+// Addresses that never receive mail, submissions only to them are refused
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SuppressionConfig {
+    pub enabled: bool,
+    // Runtime additions such as hard bounces are persisted here
+    pub path: String,
+    // Manual entries, always suppressed while listed
+    pub addresses: Vec<String>,
+    // Suppress a recipient as soon as a hard bounce is seen for it
+    pub hard_bounces: bool,
+}
+
+impl Default for SuppressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("suppression.json"),
+            addresses: Vec::new(),
+            hard_bounces: true,
+        }
+    }
+}
+
+// Dated .eml copies of everything delivered, independent of the transport
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    pub directory: String,
+    // Dated directories older than this are removed, 0 keeps them forever
+    pub retention_days: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: String::from("/var/spool/MailRegulator/archive"),
+            retention_days: 30,
+        }
+    }
+}
+
+impl Default for FileTransportConfig {
+    fn default() -> Self {
+        Self {
+            directory: String::from("/var/spool/MailRegulator/outbox"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DigestConfig {
+    pub enabled: bool,
+    pub interval_minutes: u64,
+    pub subject: String,
+    pub item_template: String,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_minutes: 30,
+            subject: String::from("Digest: {count} notifications"),
+            item_template: String::from("{index}. [{priority}] {subject}\n{body}\n"),
+        }
+    }
+}
+
+// Token bucket shared by every send
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // Sustained sends per minute
+    pub per_minute: u32,
+    // Sends allowed at once after a quiet spell
+    pub burst: u32,
+    // Separate allowance for each destination address
+    pub per_recipient: Option<RecipientRateLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_minute: 24,
+            burst: 5,
+            per_recipient: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RecipientRateLimit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+// When failed sends are retried and when the queue gives up on a message
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub initial_delay_seconds: u64,
+    pub multiplier: f64,
+    pub max_delay_seconds: u64,
+    // Dead-letter after this many failed attempts, 0 retries until the lifetime runs out
+    pub max_attempts: u32,
+    // Messages still queued after this long are discarded
+    pub max_lifetime_seconds: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_seconds: 2,
+            multiplier: 2.0,
+            max_delay_seconds: 120,
+            max_attempts: 0,
+            max_lifetime_seconds: 300,
+        }
+    }
+}
+
+// Pauses relay sends after consecutive connection or auth failures
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    pub enabled: bool,
+    // Consecutive failures that open the circuit
+    pub threshold: u32,
+    // How long the circuit stays open before a probe send
+    pub cooldown_seconds: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 3,
+            cooldown_seconds: 60,
+        }
+    }
+}
+
+// What happens to a message once email delivery has failed `after_attempts` times
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EscalationConfig {
+    pub enabled: bool,
+    pub after_attempts: u32,
+    // Channels the message is re-routed to
+    pub channels: Vec<String>,
+    // Also text the message through [twilio]
+    pub sms: bool,
+    // Operators told about the escalation, by address or group
+    pub notify: Vec<String>,
+}
+
+impl Default for EscalationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            after_attempts: 3,
+            channels: Vec::new(),
+            sms: false,
+            notify: Vec::new(),
+        }
+    }
+}
+
+// Mails operators a summary of send failures once enough pile up or the interval passes
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ErrorDigestConfig {
+    pub enabled: bool,
+    // Send as soon as this many failures are recorded
+    pub threshold: usize,
+    // Otherwise send whatever was recorded at this interval
+    pub interval_minutes: u64,
+    // Defaults to `smtp.to`
+    pub to: Vec<String>,
+    pub subject: String,
+}
+
+impl Default for ErrorDigestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold: 10,
+            interval_minutes: 60,
+            to: Vec::new(),
+            subject: String::from("MailRegulator: {count} delivery failures"),
+        }
+    }
+}
+
+// Mails operators a summary of the day's traffic once a day, and optionally a weekly comparison per client
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ReportConfig {
+    pub enabled: bool,
+    // Local time of day the report goes out, covering everything since the last one
+    pub at: NaiveTime,
+    // Defaults to `smtp.to`
+    pub to: Vec<String>,
+    pub subject: String,
+    // Clients, subjects and relay errors listed in each section
+    pub top: usize,
+    pub weekly: bool,
+    pub weekly_day: Weekday,
+    pub weekly_subject: String,
+    // Flag clients whose weekly volume grew by this factor, once it reaches `surge_minimum`
+    pub surge_factor: f64,
+    pub surge_minimum: u64,
+    // Two weeks of per-client daily volumes
+    pub history_path: String,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            at: NaiveTime::from_hms_opt(8, 0, 0).unwrap_or_default(),
+            to: Vec::new(),
+            subject: String::from("MailRegulator: daily summary for {date}"),
+            top: 5,
+            weekly: false,
+            weekly_day: Weekday::Mon,
+            weekly_subject: String::from("MailRegulator: weekly trends to {date}, {surges} surging"),
+            surge_factor: 3.0,
+            surge_minimum: 50,
+            history_path: String::from("report-history.json"),
+        }
+    }
+}
+
+// Dead-man's-switch mail sent on a fixed interval for an external monitor to expect
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub enabled: bool,
+    pub interval_hours: u64,
+    // Defaults to `smtp.to`, usually the monitor's inbound address instead
+    pub to: Vec<String>,
+    // Supports {hostname}
+    pub subject: String,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 6,
+            to: Vec::new(),
+            subject: String::from("MailRegulator heartbeat from {hostname}"),
+        }
+    }
+}
+
+// OpenTelemetry export of the message lifecycle
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    // OTLP/HTTP traces endpoint
+    pub endpoint: String,
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            endpoint: "http://localhost:4318/v1/traces".to_owned(),
+            service_name: env!("CARGO_PKG_NAME").to_owned(),
+        }
+    }
+}
+
+// Compliance trail of accepted and delivered mail, rotated by size
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub max_size_mb: u64,
+    // Rotated files kept alongside the current one
+    pub keep: usize,
+    // Events kept in memory for the admin dashboard
+    pub recent: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "audit.log".to_owned(),
+            max_size_mb: 10,
+            keep: 5,
+            recent: 100,
+        }
+    }
+}
+
+// Counters and timings pushed to a StatsD or DogStatsD agent
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct StatsdConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub prefix: String,
+    // Append `tags` in the DogStatsD format
+    pub dogstatsd: bool,
+    pub tags: Vec<String>,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".to_owned(),
+            port: 8125,
+            prefix: "mailregulator".to_owned(),
+            dogstatsd: false,
+            tags: Vec::new(),
+        }
+    }
+}
+
+// Checks run before the service reports ready
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    pub enabled: bool,
+    // Exit instead of starting with a broken transport
+    pub fail_fast: bool,
+    // Mail a short notice once the checks pass
+    pub canary: bool,
+    // Defaults to `smtp.to`
+    pub canary_to: Vec<String>,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            fail_fast: true,
+            canary: false,
+            canary_to: Vec::new(),
+        }
+    }
+}
+
+// Limits on the queue the maintenance loop watches itself against
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MonitorConfig {
+    pub enabled: bool,
+    pub max_queue_depth: usize,
+    pub max_age_seconds: u64,
+    // Channels alerted when a limit is crossed, email is skipped since it's likely what's stuck
+    pub channels: Vec<String>,
+    // Minimum time between repeated alerts while the queue stays unhealthy
+    pub cooldown_minutes: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_queue_depth: 100,
+            max_age_seconds: 240,
+            channels: Vec::new(),
+            cooldown_minutes: 30,
+        }
+    }
+}
+
+// Maps a payload severity to the addresses that should receive it
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RoutingConfig {
+    pub severity: HashMap<Severity, Vec<String>>,
+}
+
+// Body layouts for mail no rule template applied to, picked by category, then severity, then `generic`
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct TemplatesConfig {
+    pub generic: Option<BodyTemplate>,
+    pub categories: HashMap<String, BodyTemplate>,
+    pub severity: HashMap<Severity, BodyTemplate>,
+}
+
+// Supports {subject} {body} {client} {tags} {priority} {category}, an HTML layout is sent alongside the text
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct BodyTemplate {
+    pub text: Option<String>,
+    pub html: Option<String>,
+    // Variants for recipients in other locales, keyed like `localization.boilerplate`
+    pub locales: HashMap<String, BodyTemplate>,
+}
+
+// Which language each recipient reads, and the header and footer text sent to them in it
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LocalizationConfig {
+    pub enabled: bool,
+    pub default_locale: String,
+    // Address, or `@domain` for a whole domain, to locale
+    pub recipients: HashMap<String, String>,
+    pub boilerplate: HashMap<String, Boilerplate>,
+}
+
+impl Default for LocalizationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            default_locale: String::from("en"),
+            recipients: HashMap::new(),
+            boilerplate: HashMap::new(),
+        }
+    }
+}
+
+// Wrapped around the text body, and the HTML body when there is one. Supports the template placeholders
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Boilerplate {
+    pub header: Option<String>,
+    pub footer: Option<String>,
+    pub html_header: Option<String>,
+    pub html_footer: Option<String>,
+}
+
+// Windows during which non-critical mail is held until the window closes
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct QuietHoursConfig {
+    pub enabled: bool,
+    // Release held mail into the digest rather than sending it individually
+    pub into_digest: bool,
+    pub windows: Vec<QuietWindow>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuietWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+}
+
+// Armored public key paths for recipients whose mail must be encrypted
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PgpConfig {
+    pub keys: HashMap<String, String>,
+}
+
+// Routing script run after the rules, re-read on SIGHUP
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScriptingConfig {
+    pub enabled: bool,
+    pub path: String,
+    // Stops runaway scripts, roughly one per expression evaluated
+    pub max_operations: u64,
+}
+
+impl Default for ScriptingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::from("/etc/MailRegulator/routing.rhai"),
+            max_operations: 100_000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FilterConfig {
+    Size(SizeFilter),
+    Dedup(DedupFilter),
+    Headers(HeaderFilter),
+    Exec(ExecFilter),
+}
+
+impl FilterConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            FilterConfig::Size(_) => "size",
+            FilterConfig::Dedup(_) => "dedup",
+            FilterConfig::Headers(_) => "headers",
+            FilterConfig::Exec(exec) => exec.command.first().map_or("exec", String::as_str),
+        }
+    }
+}
+
+// Refuses messages whose body, HTML and attachments together are bigger than this
+#[derive(Debug, Deserialize, Clone)]
+pub struct SizeFilter {
+    pub max_kb: u64,
+}
+
+// Refuses a message identical to one filed within the window
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DedupFilter {
+    pub window_seconds: u64,
+}
+
+impl Default for DedupFilter {
+    fn default() -> Self {
+        Self { window_seconds: 300 }
+    }
+}
+
+// Sets and removes headers on the outgoing message
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HeaderFilter {
+    pub set: HashMap<String, String>,
+    pub remove: Vec<String>,
+}
+
+// An external command given the message as JSON on stdin
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ExecFilter {
+    // Program and arguments, run without a shell
+    pub command: Vec<String>,
+    pub timeout_seconds: u64,
+}
+
+impl Default for ExecFilter {
+    fn default() -> Self {
+        Self {
+            command: Vec::new(),
+            timeout_seconds: 10,
+        }
+    }
+}
+
+// A routing rule, every pattern given must match for the rule to apply
+#[derive(Debug, Deserialize, Clone)]
+pub struct RuleConfig {
+    pub name: String,
+    pub subject: Option<Pattern>,
+    pub client: Option<Pattern>,
+    pub tag: Option<Pattern>,
+    // Matches when the payload has any of these severities
+    #[serde(default)]
+    pub severity: Vec<Severity>,
+    #[serde(default)]
+    pub to: Vec<String>,
+    pub template: Option<String>,
+    // HTML body sent alongside the text, inline images are referenced as cid:<name>
+    pub html_template: Option<String>,
+    pub priority: Option<Priority>,
+    // Send matching mail through a different backend than `app.transport`
+    pub transport: Option<TransportKind>,
+    // Envelope sender for matching mail instead of `smtp.return_path`
+    pub return_path: Option<String>,
+    // Channels that also receive matching mail
+    #[serde(default)]
+    pub channels: Vec<String>,
+    // Post to `channels` only, without sending the email
+    #[serde(default)]
+    pub replace_email: bool,
+    // Static headers added to matching mail, like ticket routing for the helpdesk
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+// Regex compiled once while the config is loaded
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        Regex::new(&raw).map(Pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+// Accepts either a single address or a list of them
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(address) => vec![address],
+        OneOrMany::Many(addresses) => addresses,
+    })
 }
 
 // Implementing Display for AppConfig
@@ -30,34 +1489,571 @@ impl fmt::Display for AppConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "{}:\n{}\n\n{}:\n{}",
+            "{}:\n{}\n\n{}:\n{}\n\n{}:\n{}\n\n{}:\n{}",
             "SMTP Configuration".blue().bold(),
             self.smtp,
             "Application Settings".green().bold(),
-            self.app
-        )
+            self.app,
+            "Digest Settings".green().bold(),
+            self.digest,
+            "Routing".green().bold(),
+            self.routing
+        )?;
+
+        write!(f, "\n\n{}:\n{}", "Quiet Hours".green().bold(), self.quiet_hours)?;
+
+        if !self.templates.categories.is_empty() || !self.templates.severity.is_empty() || self.templates.generic.is_some() {
+            let mut layouts: Vec<String> = self.templates.categories.keys().cloned().collect();
+            layouts.sort();
+            layouts.extend(self.templates.severity.keys().map(|severity| severity.to_string()));
+            if self.templates.generic.is_some() {
+                layouts.push(String::from("generic"));
+            }
+            write!(f, "\n  {}: {}", "Templates".green().bold(), layouts.join(", "))?;
+        }
+
+        if self.localization.enabled {
+            let mut locales: Vec<&String> = self.localization.boilerplate.keys().collect();
+            locales.sort();
+            write!(
+                f,
+                "\n  {}: default {}, {} recipient mappings, boilerplate for {}",
+                "Localization".green().bold(),
+                self.localization.default_locale,
+                self.localization.recipients.len(),
+                match locales.is_empty() {
+                    true => String::from("none"),
+                    false => locales.iter().map(|locale| locale.as_str()).collect::<Vec<_>>().join(", "),
+                }
+            )?;
+        }
+
+        write!(
+            f,
+            "\n  {}: {}/min, burst {}",
+            "Rate Limit".green().bold(),
+            self.rate_limit.per_minute,
+            self.rate_limit.burst
+        )?;
+
+        if let Some(per_recipient) = &self.rate_limit.per_recipient {
+            write!(
+                f,
+                " ({}/min, burst {} per recipient)",
+                per_recipient.per_minute,
+                per_recipient.burst
+            )?;
+        }
+
+        write!(
+            f,
+            "\n  {}: {}s x{} up to {}s, {} attempts, {}s lifetime",
+            "Retry".green().bold(),
+            self.retry.initial_delay_seconds,
+            self.retry.multiplier,
+            self.retry.max_delay_seconds,
+            match self.retry.max_attempts {
+                0 => "unlimited".to_owned(),
+                attempts => attempts.to_string(),
+            },
+            self.retry.max_lifetime_seconds
+        )?;
+
+        if self.app.transport == TransportKind::File {
+            write!(f, "\n  {}: {}", "File Transport".green().bold(), self.file.directory)?;
+        }
+
+        if self.app.transport == TransportKind::Maildir || self.maildir.archive {
+            write!(
+                f,
+                "\n  {}: {} (archive: {})",
+                "Maildir".green().bold(),
+                self.maildir.path,
+                self.maildir.archive
+            )?;
+        }
+
+        if let Some(ses) = &self.ses {
+            write!(
+                f,
+                "\n  {}: {} (key {}, secret ********)",
+                "SES".green().bold(),
+                ses.region,
+                ses.access_key_id
+            )?;
+        }
+
+        if let Some(sendgrid) = &self.sendgrid {
+            write!(f, "\n  {}: {} (key ********)", "SendGrid".green().bold(), sendgrid.endpoint)?;
+        }
+
+        if let Some(mailgun) = &self.mailgun {
+            write!(
+                f,
+                "\n  {}: {} ({:?}, key ********)",
+                "Mailgun".green().bold(),
+                mailgun.domain,
+                mailgun.region
+            )?;
+        }
+
+        for (address, path) in &self.pgp.keys {
+            write!(f, "\n  {} {}: {}", "PGP Key".green().bold(), address, path)?;
+        }
+
+        if self.circuit_breaker.enabled {
+            write!(
+                f,
+                "\n  {}: open after {} failures for {}s",
+                "Circuit Breaker".green().bold(),
+                self.circuit_breaker.threshold,
+                self.circuit_breaker.cooldown_seconds
+            )?;
+        }
+
+        if self.escalation.enabled {
+            write!(
+                f,
+                "\n  {}: after {} attempts -> [{}] (sms: {}, notify: {})",
+                "Escalation".green().bold(),
+                self.escalation.after_attempts,
+                self.escalation.channels.join(", "),
+                self.escalation.sms,
+                self.escalation.notify.join(", ")
+            )?;
+        }
+
+        if self.monitor.enabled {
+            write!(
+                f,
+                "\n  {}: depth {}, age {}s -> [{}]",
+                "Queue Monitor".green().bold(),
+                self.monitor.max_queue_depth,
+                self.monitor.max_age_seconds,
+                self.monitor.channels.join(", ")
+            )?;
+        }
+
+        if self.telemetry.enabled {
+            write!(
+                f,
+                "\n  {}: {} as {}",
+                "Tracing".green().bold(),
+                self.telemetry.endpoint,
+                self.telemetry.service_name
+            )?;
+        }
+
+        if let Some(imap) = &self.imap {
+            write!(
+                f,
+                "\n  {}: {}:{} {}",
+                "IMAP Sent Folder".green().bold(),
+                imap.server,
+                imap.port,
+                imap.mailbox.as_deref().unwrap_or("Sent")
+            )?;
+        }
+
+        if let Some(bounces) = &self.bounces {
+            write!(
+                f,
+                "\n  {}: {}:{} every {} minutes",
+                "Bounce Polling".green().bold(),
+                bounces.imap.server,
+                bounces.imap.port,
+                bounces.interval_minutes
+            )?;
+        }
+
+        write!(
+            f,
+            "\n  {}: {} open, {} per peer, {}s to send",
+            "Connections".green().bold(),
+            self.connections.max_total,
+            self.connections.max_per_peer,
+            self.connections.read_timeout_seconds
+        )?;
+
+        if self.submission.enabled {
+            write!(
+                f,
+                "\n  {}: {}:{}, {} users, STARTTLS {}",
+                "SMTP Submission".green().bold(),
+                self.submission.bind,
+                self.submission.port,
+                self.submission.users.len(),
+                self.submission.tls_cert_path.is_some()
+            )?;
+        }
+
+        if self.admin.enabled {
+            write!(
+                f,
+                "\n  {}: http://{}:{} as {} (password ********)",
+                "Admin Dashboard".green().bold(),
+                self.admin.bind,
+                self.admin.port,
+                self.admin.username
+            )?;
+        }
+
+        if self.inbox.enabled {
+            write!(f, "\n  {}: {}", "Inbox".green().bold(), self.inbox.path)?;
+        }
+
+        if self.bans.enabled {
+            write!(
+                f,
+                "\n  {}: {}s after {} rejections in {}s",
+                "Bans".green().bold(),
+                self.bans.ban_seconds,
+                self.bans.max_failures,
+                self.bans.window_seconds
+            )?;
+        }
+
+        write!(
+            f,
+            "\n  {}: {}",
+            "Auth".green().bold(),
+            match self.auth.secret.is_some() || !self.auth.keys.is_empty() {
+                true => format!(
+                    "HMAC signed, {} client keys, {}s replay window",
+                    self.auth.keys.len(),
+                    self.auth.max_skew_seconds
+                ),
+                false => String::from("disabled"),
+            }
+        )?;
+
+        write!(
+            f,
+            "\n  {}: optimized, legacy {:?}, zstd payloads up to {} KB, {} chunks up to {} KB",
+            "Protocol".green().bold(),
+            self.protocol.legacy,
+            self.protocol.max_decompressed_kb,
+            self.protocol.max_chunks,
+            self.protocol.max_message_kb
+        )?;
+
+        write!(
+            f,
+            "\n  {}: {} KB max (gzip text: {})",
+            "Attachments".green().bold(),
+            self.attachments.max_size_kb,
+            self.attachments.compress_text
+        )?;
+
+        write!(
+            f,
+            "\n  {}: {} (max {} lines, {})",
+            "Journal".green().bold(),
+            self.journal.enabled,
+            self.journal.max_lines,
+            match self.journal.units.is_empty() {
+                true => String::from("no units"),
+                false => self.journal.units.join(", "),
+            }
+        )?;
+
+        write!(
+            f,
+            "\n  {}: client header {}, client prefix {}, category header {}, auto-submitted {}",
+            "Headers".green().bold(),
+            self.headers.client_header,
+            self.headers.client_subject_prefix,
+            self.headers.category_header,
+            self.headers.auto_submitted
+        )?;
+
+        if let Some(template) = &self.headers.subject_template {
+            write!(f, " ({} in {})", template, self.headers.environment)?;
+        }
+
+        if let Some(list_id) = &self.headers.list_id {
+            write!(f, ", List-Id {}", list_id)?;
+        }
+
+        if let Some(timezone) = &self.headers.timezone {
+            write!(f, ", dated in {}", timezone)?;
+        }
+
+        if self.senders.enabled {
+            write!(
+                f,
+                "\n  {}: {} mapped clients, others as {}",
+                "Senders".green().bold(),
+                self.senders.clients.len(),
+                self.senders.subaddress.as_deref().unwrap_or(&self.smtp.from)
+            )?;
+        }
+
+        if !self.allowlist.from_domains.is_empty() || !self.allowlist.to_domains.is_empty() {
+            let listed = |domains: &[String]| match domains.is_empty() {
+                true => String::from("any"),
+                false => domains.join(", "),
+            };
+            write!(
+                f,
+                "\n  {}: from {}, to {}",
+                "Domain Allowlist".green().bold(),
+                listed(&self.allowlist.from_domains),
+                listed(&self.allowlist.to_domains)
+            )?;
+        }
+
+        if self.suppression.enabled {
+            write!(
+                f,
+                "\n  {}: {} ({} manual, hard bounces: {})",
+                "Suppression List".green().bold(),
+                self.suppression.path,
+                self.suppression.addresses.len(),
+                self.suppression.hard_bounces
+            )?;
+        }
+
+        if self.archive.enabled {
+            write!(
+                f,
+                "\n  {}: {} for {} days",
+                "Sent Archive".green().bold(),
+                self.archive.directory,
+                self.archive.retention_days
+            )?;
+        }
+
+        if self.audit.enabled {
+            write!(
+                f,
+                "\n  {}: {} ({} MB x {})",
+                "Audit Log".green().bold(),
+                self.audit.path,
+                self.audit.max_size_mb,
+                self.audit.keep
+            )?;
+        }
+
+        if self.statsd.enabled {
+            write!(
+                f,
+                "\n  {}: {}:{} as {}",
+                "StatsD".green().bold(),
+                self.statsd.host,
+                self.statsd.port,
+                self.statsd.prefix
+            )?;
+        }
+
+        if self.self_test.enabled {
+            write!(
+                f,
+                "\n  {}: fail fast {}, canary {}",
+                "Self-Test".green().bold(),
+                self.self_test.fail_fast,
+                self.self_test.canary
+            )?;
+        }
+
+        if self.error_digest.enabled {
+            write!(
+                f,
+                "\n  {}: every {} minutes or {} failures",
+                "Error Digest".green().bold(),
+                self.error_digest.interval_minutes,
+                self.error_digest.threshold
+            )?;
+        }
+
+        if self.report.enabled {
+            write!(f, "\n  {}: daily at {}", "Daily Report".green().bold(), self.report.at.format("%H:%M"))?;
+        }
+
+        if self.heartbeat.enabled {
+            write!(f, "\n  {}: every {} hours", "Heartbeat".green().bold(), self.heartbeat.interval_hours)?;
+        }
+
+        if self.report.weekly {
+            write!(
+                f,
+                "\n  {}: {} at {}, flagging {}x growth",
+                "Weekly Report".green().bold(),
+                self.report.weekly_day,
+                self.report.at.format("%H:%M"),
+                self.report.surge_factor
+            )?;
+        }
+
+        if let Some(vault) = &self.vault {
+            write!(
+                f,
+                "\n  {}: {} {}",
+                "Vault Credentials".green().bold(),
+                vault.address,
+                vault.secret_path
+            )?;
+        }
+
+        if let Some(twilio) = &self.twilio {
+            write!(
+                f,
+                "\n  {}: {} -> {} (token ********)",
+                "SMS Fallback".green().bold(),
+                twilio.from,
+                twilio.to.join(", ")
+            )?;
+        }
+
+        for (name, channel) in &self.channels {
+            let kind = match channel {
+                ChannelConfig::Slack(_) => "slack",
+                ChannelConfig::Discord(_) => "discord",
+                ChannelConfig::Telegram(_) => "telegram",
+                ChannelConfig::Matrix(_) => "matrix",
+                ChannelConfig::Ntfy(_) => "ntfy",
+                ChannelConfig::Gotify(_) => "gotify",
+                ChannelConfig::PagerDuty(_) => "pagerduty",
+                ChannelConfig::Webhook(_) => "webhook",
+            };
+            write!(f, "\n  {} {}: {}", "Channel".green().bold(), name, kind)?;
+        }
+
+        for (name, path) in &self.assets {
+            write!(f, "\n  {} {}: {}", "Inline Asset".green().bold(), name, path)?;
+        }
+
+        for (name, members) in &self.groups {
+            write!(f, "\n  {} {}: {}", "Group".green().bold(), name, members.join(", "))?;
+        }
+
+        if self.scripting.enabled {
+            write!(f, "\n  {}: {}", "Routing Script".green().bold(), self.scripting.path)?;
+        }
+
+        if !self.filters.is_empty() {
+            let names: Vec<&str> = self.filters.iter().map(FilterConfig::name).collect();
+            write!(f, "\n  {}: {}", "Filters".green().bold(), names.join(" -> "))?;
+        }
+
+        for rule in &self.rules {
+            write!(f, "\n\n{}:\n{}", "Rule".green().bold(), rule)?;
+        }
+        Ok(())
+    }
+}
+
+impl SmtpConfig {
+    // Replaces the username and password with the contents of their secret files
+    pub fn load_secret_files(&mut self) -> Result<(), ErrorArrayItem> {
+        if let Some(path) = &self.username_file {
+            self.username = read_secret("smtp.username_file", path)?;
+        }
+        if let Some(path) = &self.password_file {
+            self.password = read_secret("smtp.password_file", path)?;
+        }
+        Ok(())
     }
 }
 
+fn read_secret(field: &str, path: &str) -> Result<String, ErrorArrayItem> {
+    let path = match (Path::new(path).is_relative(), std::env::var_os("CREDENTIALS_DIRECTORY")) {
+        (true, Some(directory)) => Path::new(&directory).join(path),
+        _ => PathBuf::from(path),
+    };
+
+    std::fs::read_to_string(&path)
+        .map(|secret| secret.trim_end_matches(['\r', '\n']).to_owned())
+        .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("{}: {}: {}", field, path.display(), e)))
+}
+
 // Implementing Display for SmtpConfig
 impl fmt::Display for SmtpConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}",
+            "  {}: {}\n  {}: ********\n  {}: {}\n  {}: {}\n  {}: {:?}\n  {}: {}\n  {}: {}",
             "Username".cyan().bold(),
             self.username,
-            "Password".red().bold(),
-            "********", // Hide actual password
+            "Password".red().bold(), // Hide actual password
             "Server".cyan().bold(),
             self.server,
             "Port".cyan().bold(),
             self.port,
+            "Security".cyan().bold(),
+            self.security,
             "Recipient Email (To)".yellow().bold(),
-            self.to,
+            self.to.join(", "),
             "Sender Email (From)".yellow().bold(),
             self.from
-        )
+        )?;
+
+        if self.delivery == DeliveryMode::Mx {
+            write!(
+                f,
+                "\n  {}: direct to MX (relay fallback: {})",
+                "Delivery".cyan().bold(),
+                self.mx_fallback_to_relay
+            )?;
+        }
+
+        if self.sendmail_fallback {
+            write!(
+                f,
+                "\n  {}: {}",
+                "Sendmail Fallback".cyan().bold(),
+                self.sendmail_command.as_deref().unwrap_or("sendmail")
+            )?;
+        }
+
+        if let Some(return_path) = &self.return_path {
+            write!(f, "\n  {}: {}", "Return-Path".cyan().bold(), return_path)?;
+        }
+
+        if let Some(ehlo_name) = &self.ehlo_name {
+            write!(f, "\n  {}: {}", "EHLO Name".cyan().bold(), ehlo_name)?;
+        }
+
+        if let Some(bind_address) = &self.bind_address {
+            write!(f, "\n  {}: {}", "Source Address".cyan().bold(), bind_address)?;
+        }
+
+        if let Some(ca_path) = &self.tls_ca_path {
+            write!(f, "\n  {}: {}", "TLS CA".cyan().bold(), ca_path)?;
+        }
+
+        if self.danger_accept_invalid_certs {
+            write!(
+                f,
+                "\n  {}",
+                "WARNING: certificate verification is disabled".red().bold()
+            )?;
+        }
+
+        if let Some(oauth2) = &self.oauth2 {
+            write!(
+                f,
+                "\n  {}: client {} (secret ********)",
+                "OAuth2".cyan().bold(),
+                oauth2.client_id
+            )?;
+        }
+
+        if let Some(dkim) = &self.dkim {
+            write!(
+                f,
+                "\n  {}: {}._domainkey.{} ({})",
+                "DKIM".cyan().bold(),
+                dkim.selector,
+                dkim.domain,
+                dkim.private_key_path
+            )?;
+        }
+
+        for smime in &self.smime {
+            write!(f, "\n  {}: {} ({})", "S/MIME".cyan().bold(), smime.from, smime.cert_path)?;
+        }
+        Ok(())
     }
 }
 
@@ -66,11 +2062,239 @@ impl fmt::Display for AppSettings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "  {}: {}\n  {}: {}",
+            "  {}: {}:{}\n  {}: {}\n  {}: {}\n  {}: {}\n  {}: {:?}\n  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}",
+            "Listen".magenta().bold(),
+            self.bind,
+            self.port,
             "Loop Interval (seconds)".magenta().bold(),
             self.loop_interval_seconds,
-            "Rate Limit".magenta().bold(),
-            self.rate_limit
+            "Workers".magenta().bold(),
+            self.workers,
+            "Fair Scheduling".magenta().bold(),
+            self.fair_scheduling,
+            "Transport".magenta().bold(),
+            self.transport,
+            "Drain Timeout (seconds)".magenta().bold(),
+            self.drain_timeout_seconds,
+            "Spool".magenta().bold(),
+            self.spool_path,
+            "Dead Letters".magenta().bold(),
+            self.dead_letter_path,
+            "Redact Logs".magenta().bold(),
+            self.redact_logs
         )
     }
 }
+
+// Implementing Display for DigestConfig
+impl fmt::Display for DigestConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "  {}: {}\n  {}: {}\n  {}: {}",
+            "Enabled".magenta().bold(),
+            self.enabled,
+            "Interval (minutes)".magenta().bold(),
+            self.interval_minutes,
+            "Subject".magenta().bold(),
+            self.subject
+        )
+    }
+}
+
+// Implementing Display for RoutingConfig
+impl fmt::Display for RoutingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.severity.is_empty() {
+            return write!(f, "  {}", "No severity routes".magenta());
+        }
+
+        let routes: Vec<String> = self
+            .severity
+            .iter()
+            .map(|(severity, to)| format!("  {}: {}", severity.to_string().magenta().bold(), to.join(", ")))
+            .collect();
+        write!(f, "{}", routes.join("\n"))
+    }
+}
+
+// Implementing Display for RuleConfig
+impl fmt::Display for RuleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pattern = |p: &Option<Pattern>| p.as_ref().map_or("*".to_owned(), |p| p.0.to_string());
+        write!(
+            f,
+            "  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}\n  {}: {}",
+            "Name".magenta().bold(),
+            self.name,
+            "Subject".magenta().bold(),
+            pattern(&self.subject),
+            "Client".magenta().bold(),
+            pattern(&self.client),
+            "Tag".magenta().bold(),
+            pattern(&self.tag),
+            "Recipients".magenta().bold(),
+            self.to.join(", ")
+        )?;
+
+        if !self.severity.is_empty() {
+            write!(f, "\n  {}: {:?}", "Severity".magenta().bold(), self.severity)?;
+        }
+
+        if let Some(return_path) = &self.return_path {
+            write!(f, "\n  {}: {}", "Return-Path".magenta().bold(), return_path)?;
+        }
+
+        if !self.channels.is_empty() {
+            write!(
+                f,
+                "\n  {}: {}{}",
+                "Channels".magenta().bold(),
+                self.channels.join(", "),
+                if self.replace_email { " (instead of email)" } else { "" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Implementing Display for QuietHoursConfig
+impl fmt::Display for QuietHoursConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "  {}: {}\n  {}: {}",
+            "Enabled".magenta().bold(),
+            self.enabled,
+            "Into Digest".magenta().bold(),
+            self.into_digest
+        )?;
+
+        for window in &self.windows {
+            write!(
+                f,
+                "\n  {}: {} - {} {:?}",
+                "Window".magenta().bold(),
+                window.start,
+                window.end,
+                window.days
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Every list setting outside an array of tables, their overrides are split on commas
+const ENV_LIST_KEYS: &[&str] = &[
+    "smtp.to",
+    "twilio.to",
+    "escalation.channels",
+    "escalation.notify",
+    "error_digest.to",
+    "report.to",
+    "heartbeat.to",
+    "allowlist.from_domains",
+    "allowlist.to_domains",
+    "monitor.channels",
+    "self_test.canary_to",
+    "suppression.addresses",
+    "journal.units",
+    "protocol.legacy",
+    "statsd.tags",
+];
+
+// Maps of lists, `MAILSERVER_GROUPS__OPS=a,b` sets the one entry
+const ENV_LIST_MAPS: &[&str] = &["groups", "routing.severity"];
+
+fn is_list_key(key: &str) -> bool {
+    ENV_LIST_KEYS.contains(&key) || key.rsplit_once('.').is_some_and(|(parent, _)| ENV_LIST_MAPS.contains(&parent))
+}
+
+// "MAILSERVER_STATSD__TAGS" gives "statsd.tags"
+fn env_key(variable: &str) -> String {
+    variable["MAILSERVER_".len()..].to_lowercase().replace("__", ".")
+}
+
+// `MAILSERVER_SMTP__PASSWORD` overrides `smtp.password`, values stay strings so secrets like "0123" survive intact
+fn env_overrides() -> Vec<Environment> {
+    let (lists, scalars): (Map<String, String>, Map<String, String>) = std::env::vars()
+        .filter(|(key, _)| key.starts_with("MAILSERVER_"))
+        .partition(|(key, _)| is_list_key(&env_key(key)));
+    let list_keys: Vec<String> = lists.keys().map(|key| env_key(key)).collect();
+
+    let environment = || Environment::with_prefix("MAILSERVER").prefix_separator("_").separator("__");
+    vec![
+        environment().source(Some(scalars)),
+        list_keys
+            .iter()
+            .fold(environment().try_parsing(true).list_separator(","), |env, key| env.with_list_parse_key(key))
+            .source(Some(lists)),
+    ]
+}
+
+pub fn load_app_config(path: &str) -> Result<AppConfig, Box<dyn Error>> {
+    let settings = Config::builder()
+        .add_source(File::with_name(path))
+        .add_source(env_overrides())
+        .build()?;
+
+    let mut config: AppConfig = settings.try_deserialize()?;
+    config.smtp.load_secret_files().map_err(|e| e.to_string())?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_list_overrides_from_the_environment() {
+        let path = std::env::temp_dir().join(format!("mailregulator-env-{}.toml", std::process::id()));
+        let toml = r#"
+[smtp]
+server = "localhost"
+port = 25
+to = "ops@example.com"
+from = "regulator@example.com"
+
+[app]
+loop_interval_seconds = 1
+redact_logs = false
+"#;
+        std::fs::write(&path, toml).unwrap();
+        let overrides = [
+            ("MAILSERVER_STATSD__TAGS", "env:prod,role:mail"),
+            ("MAILSERVER_SMTP__TO", "a@example.com,b@example.com"),
+            ("MAILSERVER_GROUPS__OPS", "c@example.com,d@example.com"),
+            ("MAILSERVER_ROUTING__SEVERITY__CRITICAL", "pager@example.com,ops@example.com"),
+            ("MAILSERVER_STATSD__PREFIX", "mail,regulator"),
+        ];
+        for (variable, value) in overrides {
+            std::env::set_var(variable, value);
+        }
+        let loaded = load_app_config(path.to_str().unwrap());
+        for (variable, _) in overrides {
+            std::env::remove_var(variable);
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let config = loaded.unwrap();
+        let list = |addresses: &[&str]| addresses.iter().map(|address| address.to_string()).collect::<Vec<_>>();
+        assert_eq!(config.statsd.tags, list(&["env:prod", "role:mail"]));
+        assert_eq!(config.smtp.to, list(&["a@example.com", "b@example.com"]));
+        assert_eq!(config.groups["ops"], list(&["c@example.com", "d@example.com"]));
+        assert_eq!(config.routing.severity[&Severity::Critical], list(&["pager@example.com", "ops@example.com"]));
+        // Scalars keep their commas
+        assert_eq!(config.statsd.prefix, "mail,regulator");
+    }
+
+    #[test]
+    fn treats_only_list_settings_as_lists() {
+        assert!(is_list_key("statsd.tags"));
+        assert!(is_list_key("groups.ops"));
+        assert!(is_list_key("routing.severity.critical"));
+        assert!(!is_list_key("smtp.password"));
+        assert!(!is_list_key("groups"));
+        assert!(!is_list_key("submission.users.backups"));
+    }
+}
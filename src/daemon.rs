@@ -0,0 +1,952 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::{
+    common::{update_state, wind_down_state},
+    state_persistence::{AppState, StatePersistence},
+    timestamp::current_timestamp,
+    version::{aml_version, str_to_version},
+};
+use chrono::{Local, NaiveDate};
+use colored::Colorize;
+use dusa_collection_utils::{
+    errors::ErrorArrayItem,
+    functions::{create_hash, truncate},
+    log,
+    log::{set_log_level, LogLevel},
+    rwarc::LockWithTimeout,
+    stringy::Stringy,
+    types::PathType,
+    version::{SoftwareVersion, Version, VersionCode},
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, Notify},
+    time::{interval, sleep, timeout, MissedTickBehavior},
+};
+
+use crate::{
+    admin::{admin_monitor, admin_status, AdminRequest},
+    archive::prune_archive,
+    audit::{AuditLog, Outcome},
+    bounces::{poll_bounces, BounceStore},
+    config::{load_app_config, AppConfig, DeliveryMode, SmtpSecurity, TransportKind, VaultConfig},
+    diagnostics::diagnostics,
+    email::Keyring,
+    errordigest::{compose_error_digest, record_error, ErrorEmail},
+    heartbeat::{compose_heartbeat, heartbeat_due},
+    inbox::scan_inbox,
+    limits::{accept_monitor, BanList, Connections},
+    metrics::Metrics,
+    monitor::{alert_health, check_health},
+    oauth::TokenCache,
+    payload::EmailPayload,
+    queue::{drain_queue, purge_message, record_audit, requeue_dead_letters, retry_message, TimedEmail},
+    quiet::is_quiet,
+    receiver::{file_submission, health, receive, spawn_connection, Filed, Intake, Received, Shared},
+    report::{compose_report, compose_weekly, record_day, report_due, weekly_due},
+    runner::{Dispatch, QueueRunner},
+    script::Script,
+    selftest::self_test,
+    signals::{diagnostics_monitor, reload_monitor, shutdown_monitor},
+    smtp_sink::SmtpSink,
+    spool::{load_spool, save_spool},
+    statsd::StatsD,
+    submission::{submission_monitor, Submitted},
+    suppression::SuppressionList,
+    systemd::{notify_ready, notify_status, notify_stopping, notify_watchdog, watchdog_interval},
+    telemetry::{init_tracing, shutdown_tracing},
+    transport::transport_for,
+    vault::VaultCredentials,
+};
+
+// What the command line decides about a server run, everything else comes from the config
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Re-read on SIGHUP
+    pub config: String,
+    // Wins over the level from Overrides.toml
+    pub log_level: Option<LogLevel>,
+    // Send everything to an embedded SMTP sink on localhost that prints what it receives
+    pub debug_smtp: bool,
+    // The listener address came from the command line, a reload keeps it without a warning
+    pub pinned_listener: bool,
+}
+
+// Runs the server until a shutdown signal, when the queue is drained and whatever is left spooled. Only returns by
+// exiting the process
+pub async fn run(mut app_config: AppConfig, options: Options) {
+    let debug_sink = match options.debug_smtp {
+        true => match SmtpSink::printing().start(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await {
+            Ok(address) => {
+                use_debug_sink(&mut app_config, address);
+                println!("{} sending all mail to the SMTP sink on {}", "debug-smtp:".yellow().bold(), address);
+                Some(address)
+            }
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to start the debug SMTP sink: {}", e);
+                std::process::exit(1);
+            }
+        },
+        false => None,
+    };
+
+    let tracer_provider = init_tracing(&app_config.telemetry, app_config.app.redact_logs);
+    let mut statsd = StatsD::new(&app_config.statsd);
+    let mut audit = AuditLog::new(&app_config.audit);
+    let mut suppressions = SuppressionList::load(&app_config.suppression).await;
+
+    // Load the DKIM, S/MIME and PGP keys if any are configured
+    let mut keyring = match Keyring::load(&app_config) {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to load keys: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut script = match Script::load(&app_config.scripting) {
+        Ok(script) => script,
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to load routing script: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // XOAUTH2 access tokens are fetched lazily and refreshed before they expire
+    let mut oauth_tokens = TokenCache::default();
+    let mut vault = VaultCredentials::default();
+    let mut runner = QueueRunner::new(&app_config);
+    let mut last_error_digest = Instant::now();
+    let mut last_health_alert: Option<Instant> = None;
+    let mut last_archive_prune: Option<NaiveDate> = None;
+    let mut last_heartbeat: Option<Instant> = None;
+    // Starting after the report time waits for tomorrow's rather than sending one covering a few seconds
+    let mut last_report = Some(Local::now().date_naive()).filter(|_| Local::now().time() >= app_config.report.at);
+    let mut last_bounce_poll: Option<Instant> = None;
+
+    let default_config = match artisan_middleware::config::AppConfig::new() {
+        Ok(mut data_loaded) => {
+            data_loaded.git = None;
+            data_loaded.database = None;
+            data_loaded.app_name = Stringy::from(env!("CARGO_PKG_NAME").to_string());
+            
+            let raw_version: SoftwareVersion = {
+                // defining the version
+                let library_version: Version = aml_version();
+                let software_version: Version = str_to_version(env!("CARGO_PKG_VERSION"), Some(VersionCode::Production));
+                
+                SoftwareVersion {
+                    application: software_version,
+                    library: library_version,
+                }
+            };
+                
+            data_loaded.version = serde_json::to_string(&raw_version)
+                .unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_string());
+
+            data_loaded
+        }
+        Err(e) => {
+            log!(LogLevel::Error, "Error loading config: {}", e);
+            std::process::exit(1);
+        }
+    };
+    set_log_level(options.log_level.unwrap_or(default_config.log_level));
+
+    //  Initialize app state
+    let state_path: PathType = StatePersistence::get_state_path(&default_config);
+    let mut state = match StatePersistence::load_state(&state_path).await {
+        Ok(mut loaded_data) => {
+            log!(LogLevel::Info, "Loaded previous state data");
+            log!(LogLevel::Trace, "Previous state data: {:#?}", loaded_data);
+            loaded_data.is_active = false;
+            loaded_data.data = String::from("Initializing");
+            loaded_data.version = {
+                let library: Version = aml_version();
+                let application = Version::new(env!("CARGO_PKG_VERSION"), VersionCode::Production);
+
+                SoftwareVersion {
+                    application,
+                    library,
+                }
+            };
+            loaded_data.config.debug_mode = default_config.debug_mode;
+            loaded_data.last_updated = current_timestamp();
+            loaded_data.config.log_level = default_config.log_level;
+            set_log_level(loaded_data.config.log_level);
+            // The error trail survives restarts for post-mortems, only trimmed to size
+            let excess = loaded_data.error_log.len().saturating_sub(app_config.app.error_log_size);
+            loaded_data.error_log.drain(..excess);
+            update_state(&mut loaded_data, &state_path, None).await;
+            loaded_data
+        }
+        Err(e) => {
+            log!(LogLevel::Warn, "No previous state loaded, creating new one");
+            log!(LogLevel::Debug, "Error loading previous state: {}", e);
+            let mut state = AppState {
+                name: env!("CARGO_PKG_NAME").to_owned(),
+                version: {
+                    let library: Version = aml_version();
+                    let application =
+                        Version::new(env!("CARGO_PKG_VERSION"), VersionCode::Production);
+
+                    SoftwareVersion {
+                        application,
+                        library,
+                    }
+                },
+                data: String::new(),
+                last_updated: current_timestamp(),
+                event_counter: 0,
+                is_active: false,
+                error_log: vec![],
+                config: default_config.clone(),
+                system_application: true,
+            };
+            state.is_active = false;
+            state.data = String::from("Initializing");
+            state.config.debug_mode = true;
+            state.last_updated = current_timestamp();
+            state.config.log_level = default_config.log_level;
+            set_log_level(state.config.log_level);
+            state.error_log.clear();
+            update_state(&mut state, &state_path, None).await;
+            state
+        }
+    };
+
+    apply_log_level(options.log_level, &mut state);
+
+    // The event counters carry on from the last run's saved metrics
+    let mut metrics = Metrics::restore(&state.data);
+
+    // Listening for the signals
+    let reload_flag = Arc::new(Notify::new());
+    let shutdown_flag = Arc::new(Notify::new());
+    let diagnostics_flag = Arc::new(Notify::new());
+    let execution: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+
+    // Spawn separate tasks that might signal to the main loop
+    let reload_flag_clone = reload_flag.clone();
+    reload_monitor(reload_flag_clone);
+
+    let shutdown_flag_clone = shutdown_flag.clone();
+    shutdown_monitor(shutdown_flag_clone);
+
+    diagnostics_monitor(diagnostics_flag.clone());
+
+    // Arrays to store email data and errors
+    let spooled: Vec<TimedEmail> = load_spool(&app_config.app.spool_path)
+        .await
+        .into_iter()
+        .map(TimedEmail::new)
+        .collect();
+    let mut spooled_ids: HashSet<String> = spooled.iter().map(|timed| timed.id.clone()).collect();
+    let shared = Shared::new(spooled);
+    let errors: LockWithTimeout<Vec<ErrorEmail>> = LockWithTimeout::new(Vec::new());
+
+    // Defining the listeners
+    let tcp_listener: TcpListener = match TcpListener::bind((app_config.app.bind, app_config.app.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to bind {}:{}: {}", app_config.app.bind, app_config.app.port, e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(settings) = app_config.vault.clone() {
+        if let Err(e) = rotate_credentials(&mut app_config, &mut vault, &settings).await {
+            push_error_log(&mut state, app_config.app.error_log_size, "Vault credentials", &e);
+        }
+    }
+
+    // Readiness waits for the default transport check, a failure only stops startup when the self-test asks for it
+    let access_token = match &app_config.smtp.oauth2 {
+        Some(settings) => oauth_tokens.access_token(settings).await.ok(),
+        None => None,
+    };
+    let verified = match app_config.self_test.enabled {
+        true => self_test(&app_config, &keyring, access_token.as_deref()).await,
+        false => match transport_for(&app_config, app_config.app.transport, access_token.as_deref()) {
+            Ok(transport) => transport.verify().await,
+            Err(e) => Err(e),
+        },
+    };
+    match verified {
+        Ok(_) => notify_ready(&format!("Listening on {}:{}", app_config.app.bind, app_config.app.port)),
+        Err(e) if app_config.self_test.enabled && app_config.self_test.fail_fast => {
+            log!(LogLevel::Error, "Startup self-test failed: {}", e);
+            notify_status(&format!("Startup self-test failed: {}", e));
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log!(LogLevel::Warn, "Transport check failed: {}", e);
+            notify_ready(&format!("Listening on {}:{}, transport check failed: {}", app_config.app.bind, app_config.app.port, e));
+        }
+    }
+
+    // Pinged from its own arm at half WatchdogSec, the tick arm may not win a round while traffic keeps arriving.
+    // Still on the main loop, so a loop that has really stopped stops pinging
+    let watchdog = watchdog_interval();
+    let mut watchdog_ticker = interval(watchdog.map_or(Duration::from_secs(3600), |period| period / 2));
+    watchdog_ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Accepted connections wait here for their turn, each holding a permit until it has been handled
+    let (connection_sender, mut connections) = mpsc::channel(app_config.connections.max_total.max(1));
+    // What each connection's task read, waiting to be filed
+    let (received_sender, mut received) = mpsc::channel::<Received>(app_config.connections.max_total.max(1));
+    // Messages from the SMTP listener are filed here like any other submission
+    let (submission_sender, mut submissions) = mpsc::channel::<Submitted>(64);
+    let connection_counts = Connections::default();
+    let bans = BanList::default();
+    if app_config.submission.enabled {
+        let started = submission_monitor(
+            app_config.submission.clone(),
+            app_config.connections.clone(),
+            app_config.bans.clone(),
+            connection_counts.clone(),
+            bans.clone(),
+            submission_sender,
+        )
+        .await;
+        if let Err(e) = started {
+            log!(LogLevel::Error, "Failed to start SMTP submission: {}", e);
+        }
+    }
+
+    // Dashboard requests are answered by the main loop, which owns the queue
+    let (admin_sender, mut admin_requests) = mpsc::channel::<AdminRequest>(16);
+    if app_config.admin.enabled {
+        let dead_letter_path = app_config.app.dead_letter_path.clone();
+        if let Err(e) = admin_monitor(app_config.admin.clone(), dead_letter_path, app_config.app.redact_logs, admin_sender).await {
+            log!(LogLevel::Error, "Failed to start the admin dashboard: {}", e);
+        }
+    }
+
+    accept_monitor(tcp_listener, app_config.connections.clone(), connection_counts, bans.clone(), connection_sender);
+
+    loop {
+        tokio::select! {
+            _ = watchdog_ticker.tick(), if watchdog.is_some() => {
+                notify_watchdog();
+            },
+            Some((conn, peer, permit)) = connections.recv() => {
+                if execution.load(Ordering::Relaxed) {
+                    let read_timeout = Duration::from_secs(app_config.connections.read_timeout_seconds);
+                    let protocol = app_config.protocol.clone();
+                    let redact = app_config.app.redact_logs;
+                    spawn_connection(conn, peer, permit, protocol, read_timeout, redact, received_sender.clone());
+                }
+            },
+            Some(received) = received.recv() => {
+                let intake = Intake::new(&app_config, &audit, &suppressions, script.as_ref(), &shared);
+                let (peer, result) = match received {
+                    Received::Ping(reply) => {
+                        let _ = reply.send(health(&intake).await);
+                        continue;
+                    }
+                    Received::Payload { header, negotiated, payload, peer, reply } => {
+                        let filed = receive(&intake, &header, negotiated, &payload, peer).await;
+                        let _ = reply.send(filed.clone());
+                        (peer, filed)
+                    }
+                    Received::Failed(peer, e) => (peer, Err(e)),
+                };
+
+                // One bad connection gets an error reply, never takes the service down
+                match result {
+                    Err(e) => {
+                        statsd.incr("messages.rejected");
+                        metrics.events.reject(&e);
+                        log!(LogLevel::Error, "Rejected submission from {}: {}", peer, e);
+                        if bans.record_failure(&app_config.bans, peer.ip()) {
+                            statsd.incr("connections.banned");
+                        }
+                        record_error(&errors, &e).await;
+                        push_error_log(&mut state, app_config.app.error_log_size, &format!("submission from {}", peer), &e);
+                    }
+                    Ok(Filed::Accepted) => {
+                        statsd.incr("messages.received");
+                        metrics.events.accepted += 1;
+                    }
+                    Ok(_) => statsd.incr("messages.received"),
+                }
+
+                state.event_counter += 1;
+                save_state(&mut state, &state_path, &metrics).await;
+            },
+            Some(submitted) = submissions.recv() => {
+                let intake = Intake::new(&app_config, &audit, &suppressions, script.as_ref(), &shared);
+                let identity = submitted.identity.as_deref();
+                let filed = file_submission(&intake, submitted.email, &submitted.source, identity).await;
+                match &filed {
+                    Ok(Filed::Accepted) => {
+                        statsd.incr("messages.received");
+                        metrics.events.accepted += 1;
+                    }
+                    Ok(Filed::Refused(_)) => statsd.incr("messages.received"),
+                    Err(e) => {
+                        statsd.incr("messages.rejected");
+                        metrics.events.reject(e);
+                        log!(LogLevel::Error, "Rejected submission from {}: {}", submitted.source, e);
+                    }
+                }
+                let _ = submitted.reply.send(filed);
+            },
+            Some(request) = admin_requests.recv() => match request {
+                AdminRequest::Status(reply) => {
+                    let status = admin_status(&app_config, &audit, &mut runner.bucket, &runner.breaker, &metrics, &shared, &errors).await;
+                    let _ = reply.send(status);
+                }
+                AdminRequest::Retry(id, reply) => {
+                    let found = match retry_message(&shared.emails, &shared.held, &id).await {
+                        Ok(found) => found,
+                        Err(e) => {
+                            log!(LogLevel::Error, "Failed to lock the queue for a retry: {}", e);
+                            false
+                        }
+                    };
+                    if found {
+                        log!(LogLevel::Info, "Retrying {} on the next round at an operator's request", id);
+                    }
+                    let _ = reply.send(found);
+                }
+                AdminRequest::Requeue(selection, reply) => {
+                    let requeued = requeue_dead_letters(&app_config, &audit, &shared.emails, &selection).await;
+                    let _ = reply.send(requeued.map_err(|e| e.err_mesg.to_string()));
+                }
+                AdminRequest::Purge(id, reply) => {
+                    let purged = match purge_message(&shared.emails, &shared.held, &id).await {
+                        Ok(purged) => purged,
+                        Err(e) => {
+                            log!(LogLevel::Error, "Failed to lock the queue for a purge: {}", e);
+                            None
+                        }
+                    };
+                    if let Some(timed) = &purged {
+                        log!(LogLevel::Info, "Purged {} at an operator's request", id);
+                        record_audit(&audit, &app_config, timed, Outcome::Purged, None);
+                    }
+                    let _ = reply.send(purged.is_some());
+                }
+            },
+            _ = reload_flag.notified() => {
+                execution.store(false, Ordering::Relaxed);
+                // sleep to ensure the other threads paused execution
+                sleep(Duration::from_secs(2)).await;
+
+                // Queued, held and digested mail all survive a reload, only config and credentials are re-read
+                save_state(&mut state, &state_path, &metrics).await;
+
+                match load_app_config(&options.config) {
+                    Ok(mut config) => {
+                        // Vault credentials are fetched before the swap so no send goes out without them
+                        vault.clear();
+                        if let Some(settings) = config.vault.clone() {
+                            if rotate_credentials(&mut config, &mut vault, &settings).await.is_err() {
+                                config.smtp.username = app_config.smtp.username.clone();
+                                config.smtp.password = app_config.smtp.password.clone();
+                            }
+                        }
+                        if config.smtp.username != app_config.smtp.username || config.smtp.password != app_config.smtp.password {
+                            log!(LogLevel::Info, "Rotated SMTP credentials, queued mail will use them on its next attempt");
+                        }
+
+                        // The socket stays bound where it started, a new address needs a restart
+                        if (config.app.bind, config.app.port) != (app_config.app.bind, app_config.app.port) && !options.pinned_listener {
+                            log!(LogLevel::Warn, "Listener address changed to {}:{}, restart to apply", config.app.bind, config.app.port);
+                        }
+                        config.app.bind = app_config.app.bind;
+                        config.app.port = app_config.app.port;
+
+                        if let Some(address) = debug_sink {
+                            use_debug_sink(&mut config, address);
+                        }
+
+                        log!(LogLevel::Info, "Reloaded configuration");
+                        notify_status("Reloaded configuration");
+                        app_config = config;
+                    }
+                    Err(e) => log!(LogLevel::Error, "Failed to reload configuration, keeping previous settings: {}", e),
+                }
+
+                // Pick up rotated keys, keeping the old ones if the new keys are unusable
+                match Keyring::load(&app_config) {
+                    Ok(loaded) => keyring = loaded,
+                    Err(e) => log!(LogLevel::Error, "Failed to reload keys, keeping previous keys: {}", e),
+                }
+                match Script::load(&app_config.scripting) {
+                    Ok(loaded) => script = loaded,
+                    Err(e) => log!(LogLevel::Error, "Failed to reload routing script, keeping the previous one: {}", e),
+                }
+                oauth_tokens.clear();
+                statsd = StatsD::new(&app_config.statsd);
+                audit = audit.reconfigured(&app_config.audit);
+                suppressions = SuppressionList::load(&app_config.suppression).await;
+
+                // Load the application configuration
+                let default_config = match artisan_middleware::config::AppConfig::new() {
+                    Ok(mut data_loaded) => {
+                        data_loaded.git = None;
+                        data_loaded.database = None;
+                        data_loaded.app_name =
+                            Stringy::from(env!("CARGO_PKG_NAME").to_string());
+                        data_loaded.version = env!("CARGO_PKG_VERSION").to_string();
+                        data_loaded
+                    }
+                    Err(e) => {
+                        log!(LogLevel::Error, "Error loading config, keeping previous settings: {}", e);
+                        state.config.clone()
+                    }
+                };
+
+                // Initialize app state
+                state = match StatePersistence::load_state(&state_path).await {
+                    Ok(mut loaded_data) => {
+                        log!(LogLevel::Info, "Loaded previous state data");
+                        log!(LogLevel::Trace, "Previous state data: {:#?}", loaded_data);
+                        loaded_data.is_active = false;
+                        loaded_data.data = String::from("Initializing");
+                        loaded_data.config.debug_mode = default_config.debug_mode;
+                        loaded_data.last_updated = current_timestamp();
+                        loaded_data.config.log_level = default_config.log_level;
+                        set_log_level(loaded_data.config.log_level);
+                        loaded_data
+                    }
+                    Err(e) => {
+                        log!(LogLevel::Warn, "No previous state loaded, creating new one");
+                        log!(LogLevel::Debug, "Error loading previous state: {}", e);
+                        let mut state = AppState {
+                            name: env!("CARGO_PKG_NAME").to_owned(),
+                            version: {
+                                let library: Version = aml_version();
+                                let application = Version::new(env!("CARGO_PKG_VERSION"), VersionCode::Production);
+
+                                SoftwareVersion{ application, library }
+                            },
+                            data: String::new(),
+                            last_updated: current_timestamp(),
+                            event_counter: 0,
+                            is_active: false,
+                            error_log: vec![],
+                            config: default_config.clone(),
+                            system_application: true
+                        };
+                        state.is_active = false;
+                        state.data = String::from("Initializing");
+                        state.config.debug_mode = true;
+                        state.last_updated = current_timestamp();
+                        state.config.log_level = default_config.log_level;
+                        set_log_level(state.config.log_level);
+                        state.error_log.clear();
+
+                        state
+                    }
+                };
+
+                apply_log_level(options.log_level, &mut state);
+                save_state(&mut state, &state_path, &metrics).await;
+
+                execution.store(true, Ordering::Relaxed);
+            },
+            _ = diagnostics_flag.notified() => {
+                let report = diagnostics(&app_config, &state, &runner.breaker, &metrics, &shared, &errors).await;
+                log!(LogLevel::Info, "Diagnostics:\n{}", report);
+
+                if let Some(path) = &app_config.app.diagnostics_path {
+                    if let Err(e) = tokio::fs::write(path, &report).await {
+                        log!(LogLevel::Error, "Failed to write diagnostics to {}: {}", path, e);
+                    }
+                }
+            },
+            _ = shutdown_flag.notified() => {
+                execution.store(false, Ordering::Relaxed);
+                log!(LogLevel::Info, "Shutting down, draining the queue");
+                notify_stopping();
+                let mut unsent: Vec<EmailPayload> = Vec::new();
+
+                match shared.emails.try_write_with_timeout(None).await {
+                    Ok(mut email_vec) => {
+                        // Whatever the digest was holding goes out now rather than being lost
+                        if let Ok(mut digest) = shared.digest.try_write_with_timeout(None).await {
+                            if !digest.is_empty() {
+                                let (subject, body) = digest.flush(&app_config.digest);
+                                email_vec.push(TimedEmail::new(EmailPayload::new(subject, body)));
+                            }
+                        }
+
+                        let deadline = Duration::from_secs(app_config.app.drain_timeout_seconds);
+                        let until = Instant::now() + deadline;
+                        if timeout(deadline, drain_queue(&app_config, &keyring, &audit, &suppressions, &mut oauth_tokens, &mut email_vec, until)).await.is_err() {
+                            log!(LogLevel::Warn, "Drain deadline reached with {} messages left", email_vec.len());
+                        }
+                        unsent.extend(email_vec.drain(..).map(|timed| {
+                            record_audit(&audit, &app_config, &timed, Outcome::Spooled, None);
+                            timed.email
+                        }));
+                    }
+                    Err(e) => log!(LogLevel::Error, "Failed to lock the queue for draining: {}", e),
+                }
+
+                // Quiet hours still apply, so held mail is spooled rather than sent
+                if let Ok(mut held) = shared.held.try_write_with_timeout(None).await {
+                    unsent.extend(held.drain(..).map(|timed| {
+                        record_audit(&audit, &app_config, &timed, Outcome::Spooled, None);
+                        timed.email
+                    }));
+                }
+
+                if let Err(e) = save_spool(&app_config.app.spool_path, &unsent).await {
+                    log!(LogLevel::Error, "Failed to spool {} unsent messages: {}", unsent.len(), e);
+                }
+
+                wind_down_state(&mut state, &state_path).await;
+                shutdown_tracing(tracer_provider);
+                std::process::exit(0);
+
+            },
+            _ = sleep(Duration::from_secs(app_config.app.loop_interval_seconds)) => {
+                if app_config.inbox.enabled {
+                    let intake = Intake::new(&app_config, &audit, &suppressions, script.as_ref(), &shared);
+                    let queued = scan_inbox(&app_config.inbox, &intake).await;
+                    if queued > 0 {
+                        statsd.count("messages.received", queued);
+                        metrics.events.accepted += queued as u64;
+                    }
+                }
+
+                // Lock the errors vector
+                log!(LogLevel::Trace, "Locking email_errors");
+                let mut email_errors = match errors.try_write().await {
+                    Ok(vec) => vec,
+                    Err(_) => {
+                        log!(
+                            LogLevel::Error,
+                            "Failed to acquire write lock on the error counter"
+                        );
+                        continue;
+                    }
+                };
+
+                // Lock the emails vector
+                log!(LogLevel::Trace, "Locking email_array");
+                let mut email_vec = match shared.emails.try_write().await {
+                    Ok(vec) => vec,
+                    Err(_) => {
+                        log!(
+                            LogLevel::Error,
+                            "Failed to acquire write lock on emails vector"
+                        );
+                        email_errors.push(ErrorEmail::new("Failed to lock email array".to_owned()));
+                        continue;
+                    }
+                };
+
+                let quiet = is_quiet(&app_config.quiet_hours);
+
+                // Release mail held during quiet hours once the window has closed
+                if !quiet {
+                    if let Ok(mut held) = shared.held.try_write().await {
+                        if !held.is_empty() {
+                            log!(LogLevel::Info, "Quiet hours over, releasing {} held emails", held.len());
+                            if app_config.quiet_hours.into_digest {
+                                if let Ok(mut digest) = shared.digest.try_write().await {
+                                    held.drain(..).for_each(|timed| digest.push(timed.email));
+                                    let (subject, body) = digest.flush(&app_config.digest);
+                                    email_vec.push(TimedEmail::new(EmailPayload::new(subject, body)));
+                                }
+                            } else {
+                                for mut timed in held.drain(..) {
+                                    timed.received_at = Instant::now();
+                                    email_vec.push(timed);
+                                }
+                            }
+                        }
+                    }
+                }
+
+                // Fold the held non-critical mail into a single queued summary
+                if app_config.digest.enabled && !quiet {
+                    if let Ok(mut digest) = shared.digest.try_write().await {
+                        if digest.is_due(&app_config.digest) {
+                            let (subject, body) = digest.flush(&app_config.digest);
+                            log!(LogLevel::Info, "Queueing digest: {}", subject);
+                            email_vec.push(TimedEmail::new(EmailPayload::new(subject, body)));
+                        }
+                    }
+                }
+
+                if let Some(settings) = app_config.vault.clone() {
+                    if let Err(e) = rotate_credentials(&mut app_config, &mut vault, &settings).await {
+                        email_errors.push(ErrorEmail::new(e.to_string()));
+                        push_error_log(&mut state, app_config.app.error_log_size, "Vault credentials", &e);
+                    }
+                }
+
+                let access_token = match &app_config.smtp.oauth2 {
+                    Some(settings) => match oauth_tokens.access_token(settings).await {
+                        Ok(token) => Some(token),
+                        Err(e) => {
+                            log!(LogLevel::Error, "Failed to obtain OAuth2 token, not sending this round: {}", e);
+                            email_errors.push(ErrorEmail::new(e.to_string()));
+                            push_error_log(&mut state, app_config.app.error_log_size, "OAuth2 token", &e);
+                            save_state(&mut state, &state_path, &metrics).await;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                log!(LogLevel::Trace, "Starting timeout processing");
+                let logged_errors = state.error_log.len();
+                let dispatch = Dispatch {
+                    app_config: &app_config,
+                    keyring: &keyring,
+                    audit: &audit,
+                    suppressions: &suppressions,
+                    statsd: &statsd,
+                    access_token: access_token.as_deref(),
+                    transport: None,
+                };
+                for (subject, e) in runner.run_round(&dispatch, &mut email_vec, &mut metrics).await {
+                    email_errors.push(ErrorEmail::new(e.to_string()));
+                    push_error_log(&mut state, app_config.app.error_log_size, &subject, &e);
+                }
+
+                // The spool is rewritten as the messages it held leave the queue, and removed with the last of them
+                if !spooled_ids.is_empty() {
+                    let before = spooled_ids.len();
+                    spooled_ids.retain(|id| email_vec.iter().any(|timed| &timed.id == id));
+                    if spooled_ids.len() != before {
+                        let remaining: Vec<EmailPayload> = email_vec
+                            .iter()
+                            .filter(|timed| spooled_ids.contains(&timed.id))
+                            .map(|timed| timed.email.clone())
+                            .collect();
+                        if let Err(e) = save_spool(&app_config.app.spool_path, &remaining).await {
+                            log!(LogLevel::Error, "Failed to checkpoint the spool: {}", e);
+                        }
+                    }
+                }
+
+                // Summarise accumulated failures for operators, then start counting afresh
+                if app_config.error_digest.enabled
+                    && !email_errors.is_empty()
+                    && (email_errors.len() >= app_config.error_digest.threshold
+                        || last_error_digest.elapsed() >= Duration::from_secs(app_config.error_digest.interval_minutes * 60))
+                {
+                    log!(LogLevel::Info, "Queueing error digest for {} failures", email_errors.len());
+                    // A failing digest shouldn't escalate and feed more errors into the next one
+                    email_vec.push(TimedEmail::notice(compose_error_digest(&app_config.error_digest, &email_errors)));
+                    email_errors.clear();
+                }
+
+                // The interval runs from the first failure after a quiet spell
+                if email_errors.is_empty() {
+                    last_error_digest = Instant::now();
+                }
+
+                // The tally is taken daily even with the report off so it can't grow without bound. The report itself
+                // is sent like any other notice and counted in tomorrow's
+                if report_due(&app_config.report, last_report) {
+                    let today = Local::now().date_naive();
+                    let summary = audit.take_summary();
+                    if app_config.report.enabled {
+                        log!(LogLevel::Info, "Queueing the daily report");
+                        email_vec.push(TimedEmail::notice(compose_report(&app_config.report, &summary, app_config.app.redact_logs)));
+                    }
+                    if app_config.report.weekly {
+                        match record_day(&app_config.report.history_path, today, &summary).await {
+                            Ok(history) if weekly_due(&app_config.report, today) => {
+                                log!(LogLevel::Info, "Queueing the weekly report");
+                                email_vec.push(TimedEmail::notice(compose_weekly(&app_config.report, &history, today)));
+                            }
+                            Ok(_) => (),
+                            Err(e) => log!(LogLevel::Warn, "Failed to record the day for the weekly report: {}", e),
+                        }
+                    }
+                    last_report = Some(today);
+                }
+
+                if heartbeat_due(&app_config.heartbeat, last_heartbeat) {
+                    log!(LogLevel::Debug, "Queueing a heartbeat");
+                    let depth = email_vec.len();
+                    email_vec.push(TimedEmail::notice(compose_heartbeat(&app_config.heartbeat, depth)));
+                    last_heartbeat = Some(Instant::now());
+                }
+
+                statsd.gauge("queue.depth", email_vec.len());
+
+                // Expire old archived mail once a day
+                let today = Local::now().date_naive();
+                if app_config.archive.enabled && last_archive_prune != Some(today) {
+                    if let Err(e) = prune_archive(&app_config.archive).await {
+                        log!(LogLevel::Warn, "Failed to prune the sent archive: {}", e);
+                    }
+                    last_archive_prune = Some(today);
+                }
+
+                if let Some(bounces) = &app_config.bounces {
+                    let interval = Duration::from_secs(bounces.interval_minutes * 60);
+                    if last_bounce_poll.is_none_or(|polled| polled.elapsed() >= interval) {
+                        last_bounce_poll = Some(Instant::now());
+                        let mut suppressions_changed = false;
+                        match poll_bounces(bounces).await {
+                            Ok(found) if !found.is_empty() => {
+                                let mut store = BounceStore::load(&bounces.store_path).await;
+                                for bounce in &found {
+                                    log!(
+                                        LogLevel::Warn,
+                                        "{} bounce for {} ({}) on message {}",
+                                        if bounce.is_hard() { "Hard" } else { "Soft" },
+                                        bounce.recipient,
+                                        bounce.status,
+                                        bounce.message_id.as_deref().unwrap_or("unknown")
+                                    );
+                                    statsd.incr(if bounce.is_hard() { "bounces.hard" } else { "bounces.soft" });
+                                    store.record(bounce);
+
+                                    if bounce.is_hard()
+                                        && app_config.suppression.enabled
+                                        && app_config.suppression.hard_bounces
+                                        && suppressions.add(&bounce.recipient, &format!("hard bounce {}", bounce.status))
+                                    {
+                                        log!(LogLevel::Info, "Suppressed {} after a hard bounce", bounce.recipient);
+                                        suppressions_changed = true;
+                                    }
+                                }
+                                if let Err(e) = store.save(&bounces.store_path).await {
+                                    log!(LogLevel::Error, "Failed to save bounce history: {}", e);
+                                }
+                            }
+                            Ok(_) => log!(LogLevel::Debug, "No new bounces"),
+                            Err(e) => log!(LogLevel::Warn, "Failed to poll the bounce mailbox: {}", e),
+                        }
+
+                        if suppressions_changed {
+                            if let Err(e) = suppressions.save(&app_config.suppression.path).await {
+                                log!(LogLevel::Error, "Failed to save the suppression list: {}", e);
+                            }
+                        }
+                    }
+                }
+
+                // Watch our own queue and flag trouble in the persisted state
+                let oldest = email_vec.iter().map(|timed| timed.received_at.elapsed()).max();
+                metrics.health = None;
+                if app_config.monitor.enabled {
+                    let health = match check_health(&app_config.monitor, email_vec.len(), oldest) {
+                        Some(problem) => {
+                            let cooldown = Duration::from_secs(app_config.monitor.cooldown_minutes * 60);
+                            if last_health_alert.is_none_or(|alerted| alerted.elapsed() >= cooldown) {
+                                if let Err(e) = alert_health(&app_config, &problem).await {
+                                    email_errors.push(ErrorEmail::new(e.to_string()));
+                                }
+                                last_health_alert = Some(Instant::now());
+                            }
+                            format!("Unhealthy: {}", problem)
+                        }
+                        None => {
+                            last_health_alert = None;
+                            String::from("Healthy")
+                        }
+                    };
+
+                    metrics.health = Some(health);
+                }
+
+                let held_count = shared.held.try_read().await.map(|held| held.len()).unwrap_or_default();
+                let digest_count = shared.digest.try_read().await.map(|digest| digest.len()).unwrap_or_default();
+                metrics.set_queue(email_vec.len(), held_count, digest_count, oldest);
+                metrics.set_circuit(runner.breaker.state());
+
+                // Persist when this round added to the trail or changed what the manager is shown
+                if metrics.to_json() != state.data || state.error_log.len() != logged_errors {
+                    save_state(&mut state, &state_path, &metrics).await;
+                }
+
+                if email_errors.is_empty() {
+                    log!(LogLevel::Debug, "No errors reported");
+                } else {
+                    log!(LogLevel::Warn, "Current errors: {}", email_errors.len());
+                }
+
+                drop(email_errors);
+                drop(email_vec);
+                log!(LogLevel::Trace, "Resting");
+            },
+        }
+    }
+}
+
+// Adds a "timestamp [hash] subject: error" entry to the persisted state, dropping the oldest past `limit`
+fn push_error_log(state: &mut AppState, limit: usize, subject: &str, error: &ErrorArrayItem) {
+    let hash = truncate(&*create_hash(error.to_string()), 10).to_owned();
+    state.error_log.push(ErrorArrayItem::new(
+        error.err_type,
+        format!("{} [{}] {}: {}", current_timestamp(), hash, subject, error.err_mesg),
+    ));
+
+    let excess = state.error_log.len().saturating_sub(limit);
+    state.error_log.drain(..excess);
+}
+
+// Swaps in new SMTP credentials when the Vault lease is due, the transport is built per send so nothing else needs rebuilding
+async fn rotate_credentials(
+    app_config: &mut AppConfig,
+    vault: &mut VaultCredentials,
+    settings: &VaultConfig,
+) -> Result<(), ErrorArrayItem> {
+    match vault.rotate(settings).await {
+        Ok(Some((username, password))) => {
+            log!(LogLevel::Info, "Loaded SMTP credentials from Vault");
+            app_config.smtp.username = username;
+            app_config.smtp.password = password;
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to refresh Vault credentials, keeping the current ones: {}", e);
+            Err(e)
+        }
+    }
+}
+
+// Points every send at the `--debug-smtp` sink, dropping anything that would reach real mail infrastructure
+fn use_debug_sink(config: &mut AppConfig, sink: SocketAddr) {
+    config.app.transport = TransportKind::Smtp;
+    for rule in config.rules.iter_mut() {
+        rule.transport = None;
+    }
+
+    let smtp = &mut config.smtp;
+    smtp.delivery = DeliveryMode::Relay;
+    smtp.server = sink.ip().to_string();
+    smtp.port = sink.port();
+    smtp.security = SmtpSecurity::None;
+    smtp.bind_address = None;
+    smtp.oauth2 = None;
+    smtp.sendmail_fallback = false;
+}
+
+// Every save carries the latest metrics, so the manager shows the queue rather than a placeholder
+async fn save_state(state: &mut AppState, state_path: &PathType, metrics: &Metrics) {
+    state.data = metrics.to_json();
+    update_state(state, state_path, None).await;
+}
+
+// `--log-level` wins over the level from Overrides.toml
+fn apply_log_level(level: Option<LogLevel>, state: &mut AppState) {
+    if let Some(level) = level {
+        state.config.log_level = level;
+        set_log_level(level);
+    }
+}
@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::payload::EmailPayload;
+
+// A message given up on, kept with the reason so it can be inspected or requeued by hand
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub failed_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub error: String,
+    pub email: EmailPayload,
+}
+
+// Writes one `<id>.json` file per message into the dead-letter directory
+pub async fn dead_letter(directory: &str, letter: &DeadLetter) -> Result<(), ErrorArrayItem> {
+    fs::create_dir_all(directory).await.map_err(|e| {
+        ErrorArrayItem::new(Errors::CreatingDirectory, format!("dead letter: {}: {}", directory, e))
+    })?;
+
+    let path = Path::new(directory).join(format!("{}.json", letter.id));
+    let data = serde_json::to_vec_pretty(letter).map_err(ErrorArrayItem::from)?;
+    fs::write(&path, data)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("dead letter: {}: {}", path.display(), e)))?;
+
+    log!(LogLevel::Warn, "Dead-lettered message {} to {}", letter.id, path.display());
+    Ok(())
+}
+
+// Which dead letters to requeue. Nothing is picked unless `all` is set or at least one id or bound is given
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub all: bool,
+    pub ids: Vec<String>,
+    pub since: Option<DateTime<Utc>>,
+    // Exclusive
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.ids.is_empty() && self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn matches(&self, letter: &DeadLetter) -> bool {
+        !self.is_empty()
+            && (self.ids.is_empty() || self.ids.contains(&letter.id))
+            && self.since.is_none_or(|since| letter.failed_at >= since)
+            && self.until.is_none_or(|until| letter.failed_at < until)
+    }
+}
+
+// RFC 3339, or a plain date meaning midnight UTC
+pub fn parse_time(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| format!("{:?} is neither a date nor an RFC 3339 time", text))
+}
+
+// Every readable dead letter with the file it came from, oldest first. Unreadable files are skipped and left alone
+pub async fn load_dead_letters(directory: &str) -> Result<Vec<(PathBuf, DeadLetter)>, ErrorArrayItem> {
+    let mut entries = match fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(ErrorArrayItem::new(
+                Errors::ReadingFile,
+                format!("dead letter: {}: {}", directory, e),
+            ))
+        }
+    };
+
+    let mut letters = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let parsed = fs::read(&path)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_slice::<DeadLetter>(&data).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(letter) => letters.push((path, letter)),
+            Err(e) => log!(LogLevel::Warn, "Skipping dead letter {}: {}", path.display(), e),
+        }
+    }
+
+    letters.sort_by_key(|(_, letter)| letter.failed_at);
+    Ok(letters)
+}
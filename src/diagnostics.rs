@@ -0,0 +1,79 @@
+use artisan_middleware::state_persistence::AppState;
+use dusa_collection_utils::rwarc::LockWithTimeout;
+
+use crate::{
+    admin::redacted,
+    breaker::{BreakerState, CircuitBreaker},
+    config::AppConfig,
+    errordigest::ErrorEmail,
+    metrics::Metrics,
+    receiver::Shared,
+};
+
+// Snapshot of the queues and settings for debugging a running instance, read-only so it never blocks sending for long
+pub async fn diagnostics(
+    app_config: &AppConfig,
+    state: &AppState,
+    breaker: &CircuitBreaker,
+    metrics: &Metrics,
+    shared: &Shared,
+    errors: &LockWithTimeout<Vec<ErrorEmail>>,
+) -> String {
+    let mut report = format!("Events handled: {}\n", state.event_counter);
+    let events = &metrics.events;
+    report.push_str(&format!(
+        "  accepted {}, rejected (format) {}, rejected (auth) {}, sent {}, failed {}, expired {}\n",
+        events.accepted, events.rejected_format, events.rejected_auth, events.sent, events.failed, events.expired
+    ));
+    report.push_str(&match breaker.state() {
+        BreakerState::Closed => String::from("Relay circuit: closed\n"),
+        BreakerState::Open(opened) => format!("Relay circuit: open for {}s\n", opened.elapsed().as_secs()),
+        BreakerState::HalfOpen => String::from("Relay circuit: half-open\n"),
+    });
+
+    match shared.emails.try_read().await {
+        Ok(queue) => {
+            report.push_str(&format!("Queued: {}\n", queue.len()));
+            if let Some(oldest) = queue.iter().map(|timed| timed.received_at).min() {
+                report.push_str(&format!("Oldest queued: {}s\n", oldest.elapsed().as_secs()));
+            }
+            for timed in queue.iter() {
+                report.push_str(&format!(
+                    "  - {} ({}s, {} attempts)\n",
+                    redacted(&timed.email.subject, app_config.app.redact_logs),
+                    timed.received_at.elapsed().as_secs(),
+                    timed.attempts
+                ));
+            }
+        }
+        Err(e) => report.push_str(&format!("Queued: unavailable ({})\n", e)),
+    }
+
+    match shared.held.try_read().await {
+        Ok(held) => report.push_str(&format!("Held for quiet hours: {}\n", held.len())),
+        Err(e) => report.push_str(&format!("Held for quiet hours: unavailable ({})\n", e)),
+    }
+
+    match shared.digest.try_read().await {
+        Ok(digest) => report.push_str(&format!("Waiting for digest: {}\n", digest.len())),
+        Err(e) => report.push_str(&format!("Waiting for digest: unavailable ({})\n", e)),
+    }
+
+    match errors.try_read().await {
+        Ok(errors) => {
+            report.push_str(&format!("Errors: {}\n", errors.len()));
+            for error in errors.iter() {
+                report.push_str(&format!(
+                    "  - [{}] {}s ago: {}\n",
+                    error.hash,
+                    error.occoured_at.elapsed().as_secs(),
+                    error.subject.as_deref().unwrap_or("unknown")
+                ));
+            }
+        }
+        Err(e) => report.push_str(&format!("Errors: unavailable ({})\n", e)),
+    }
+
+    report.push_str(&format!("\n{}\n", app_config));
+    report
+}
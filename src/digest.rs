@@ -0,0 +1,65 @@
+use std::time::{Duration, Instant};
+
+use crate::{config::DigestConfig, payload::EmailPayload};
+
+// Holds non-urgent emails until the digest interval elapses
+#[derive(Debug)]
+pub struct Digest {
+    items: Vec<EmailPayload>,
+    last_flush: Instant,
+}
+
+impl Default for Digest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Self {
+            items: Vec::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, email: EmailPayload) {
+        self.items.push(email);
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn is_due(&self, config: &DigestConfig) -> bool {
+        !self.items.is_empty()
+            && self.last_flush.elapsed() >= Duration::from_secs(config.interval_minutes * 60)
+    }
+
+    // Renders every held item into a single subject and body, emptying the digest
+    pub fn flush(&mut self, config: &DigestConfig) -> (String, String) {
+        let items: Vec<EmailPayload> = self.items.drain(..).collect();
+        self.last_flush = Instant::now();
+
+        let subject = config.subject.replace("{count}", &items.len().to_string());
+
+        let mut body = String::new();
+        for (index, item) in items.iter().enumerate() {
+            body.push_str(
+                &config
+                    .item_template
+                    .replace("{index}", &(index + 1).to_string())
+                    .replace("{priority}", &item.priority.to_string())
+                    .replace("{subject}", &item.subject)
+                    .replace("{body}", &item.body),
+            );
+            body.push('\n');
+        }
+
+        (subject, body)
+    }
+}
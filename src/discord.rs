@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use crate::{
+    config::DiscordConfig,
+    retry::SendError,
+    payload::{EmailPayload, Priority},
+};
+
+// Discord rejects embed titles and descriptions past these lengths
+const TITLE_LIMIT: usize = 256;
+const DESCRIPTION_LIMIT: usize = 4096;
+
+fn truncate_chars(text: &str, limit: usize) -> String {
+    match text.char_indices().nth(limit) {
+        Some((index, _)) => text[..index].to_owned(),
+        None => text.to_owned(),
+    }
+}
+
+fn colour(priority: Priority) -> u32 {
+    match priority {
+        Priority::Low => 0x95a5a6,
+        Priority::Normal => 0x3498db,
+        Priority::High => 0xe67e22,
+        Priority::Critical => 0xe74c3c,
+    }
+}
+
+// Posts an embed to a webhook. A 429 is returned with Discord's wait for the retry scheduler rather than slept
+// through here, where it would hold up every other send
+pub async fn post_discord(config: &DiscordConfig, email: &EmailPayload) -> Result<(), SendError> {
+    let mut fields: Vec<Value> = vec![json!({ "name": "Priority", "value": email.priority.to_string(), "inline": true })];
+    if let Some(client) = &email.client {
+        fields.push(json!({ "name": "Client", "value": client, "inline": true }));
+    }
+    if !email.tags.is_empty() {
+        fields.push(json!({ "name": "Tags", "value": email.tags.join(", "), "inline": true }));
+    }
+
+    let mut request = json!({
+        "embeds": [{
+            "title": truncate_chars(&email.subject, TITLE_LIMIT),
+            "description": truncate_chars(&email.render(&config.template), DESCRIPTION_LIMIT),
+            "color": colour(email.priority),
+            "fields": fields,
+        }],
+    });
+    if let Some(username) = &config.username {
+        request["username"] = json!(username);
+    }
+
+    let response = reqwest::Client::new()
+        .post(&config.webhook_url)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("discord: {}", e)))?;
+
+    let status = response.status();
+    if status.is_success() {
+        log!(LogLevel::Debug, "Discord accepted message ({})", status);
+        return Ok(());
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        // The body carries the wait in seconds, fractional
+        let retry_after = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|value| value["retry_after"].as_f64())
+            .unwrap_or(1.0);
+        log!(LogLevel::Warn, "Discord rate limited, retry after {:.2}s", retry_after);
+        let wait = Duration::from_secs_f64(retry_after.clamp(0.0, 3600.0));
+        return Err(SendError::http("discord", status, &format!("retry after {}s", retry_after)).with_retry_after(wait));
+    }
+
+    Err(SendError::http("discord", status, &body))
+}
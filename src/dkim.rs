@@ -0,0 +1,46 @@
+use std::fs;
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey};
+
+use crate::config::{DkimAlgorithm, SmtpConfig};
+
+// Reads the configured DKIM key from disk, returns None when signing is disabled
+pub fn load_dkim(config: &SmtpConfig) -> Result<Option<DkimConfig>, ErrorArrayItem> {
+    let settings = match &config.dkim {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+
+    let private_key = fs::read_to_string(&settings.private_key_path).map_err(|e| {
+        ErrorArrayItem::new(
+            Errors::ReadingFile,
+            format!("dkim: {}: {}", settings.private_key_path, e),
+        )
+    })?;
+
+    let algorithm = match settings.algorithm {
+        DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+        DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+    };
+
+    let signing_key = DkimSigningKey::new(private_key.trim(), algorithm)
+        .map_err(|e| ErrorArrayItem::new(Errors::InvalidKey, format!("dkim: {}", e)))?;
+
+    log!(
+        LogLevel::Info,
+        "Loaded DKIM key for {} (selector {})",
+        settings.domain,
+        settings.selector
+    );
+
+    Ok(Some(DkimConfig::default_config(
+        settings.selector.clone(),
+        settings.domain.clone(),
+        signing_key,
+    )))
+}
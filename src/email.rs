@@ -1,51 +1,536 @@
+use std::{borrow::Cow, collections::HashMap, fs, net::IpAddr, path::Path, time::Duration};
 
 use dusa_collection_utils::{errors::{ErrorArrayItem, Errors}, log::LogLevel, log};
-use lettre::{address::AddressError, transport::smtp::authentication::Credentials, Message, SmtpTransport, Transport};
+use lettre::{
+    address::{Address, AddressError, Envelope},
+    message::{
+        dkim::DkimConfig,
+        header::{ContentType, HeaderName, HeaderValue},
+        Attachment, Mailbox, MultiPart, SinglePart,
+    },
+    transport::smtp::{
+        authentication::{Credentials, Mechanism, DEFAULT_MECHANISMS},
+        client::{AsyncSmtpConnection, Certificate, Tls, TlsParameters, TlsVersion},
+        extension::ClientId,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{info_span, Instrument};
 
-use crate::config::AppConfig;
+use crate::{
+    archive::archive_eml,
+    config::{AppConfig, SmtpConfig, SmtpSecurity, TlsMinVersion, TransportKind},
+    dkim::load_dkim,
+    encryption::{load_pgp, PgpKeys},
+    headers::decorate,
+    imap::append_sent,
+    locale::{localize, recipient_locale},
+    maildir::write_maildir,
+    payload::EmailPayload,
+    retry::{Failure, SendError},
+    smime::{load_smime, SmimeSigner},
+    transport::{transport_for, MailTransport, Outgoing, Sendmail},
+};
 
-pub fn send_email(config: &AppConfig, subject: String, body: String) -> Result<(), ErrorArrayItem> {
+// Key material used to sign and encrypt outgoing mail, re-read on SIGHUP
+#[derive(Debug, Default)]
+pub struct Keyring {
+    pub dkim: Option<DkimConfig>,
+    pub smime: Vec<SmimeSigner>,
+    pub pgp: PgpKeys,
+}
+
+impl Keyring {
+    pub fn load(config: &AppConfig) -> Result<Self, ErrorArrayItem> {
+        Ok(Self {
+            dkim: load_dkim(&config.smtp)?,
+            smime: load_smime(&config.smtp.smime)?,
+            pgp: load_pgp(&config.pgp)?,
+        })
+    }
+}
+
+// Sends a copy to each group of recipients, adding every address that accepted its copy to `delivered` so a retry
+// after a later group fails can leave them out. `transport` replaces the backend the config or payload would pick
+pub async fn send_email(
+    config: &AppConfig,
+    keyring: &Keyring,
+    access_token: Option<&str>,
+    transport: Option<&dyn MailTransport>,
+    payload: &EmailPayload,
+    to: &[String],
+    delivered: &mut Vec<String>,
+) -> Result<(), SendError> {
+    let configured;
+    let transport: &dyn MailTransport = match transport {
+        Some(transport) => transport,
+        None => {
+            configured = transport_for(config, payload.transport.unwrap_or(config.app.transport), access_token)?;
+            configured.as_ref()
+        }
+    };
+    let payload = &decorate(config, payload);
+
+    let mut recipients: Vec<Mailbox> = Vec::new();
+    for recipient in to {
+        recipients.push(recipient.parse().map_err(|e: AddressError| {
+            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e))
+        })?);
+    }
+
+    let from: Mailbox = payload.from.as_ref().unwrap_or(&config.smtp.from).parse().map_err(|e: AddressError| {
+        ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e))
+    })?;
+
+    // Bounces go to the envelope sender, which may differ from the From header
+    let return_path: Option<Address> = match payload.return_path.as_ref().or(config.smtp.return_path.as_ref()) {
+        Some(address) => Some(address.parse().map_err(|e: AddressError| {
+            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: return path {}: {}", address, e))
+        })?),
+        None => None,
+    };
+
+    // PGP copies, S/MIME and DKIM signatures only survive a transport that carries the MIME we build
+    if !transport.carries_mime() {
+        let sender = from.email.to_string().to_lowercase();
+        let refused = if recipients.iter().any(|mailbox| keyring.pgp.has_key(mailbox.email.as_ref())) {
+            Some("PGP recipients")
+        } else if keyring.smime.iter().any(|signer| signer.from == sender) {
+            Some("S/MIME signed mail")
+        } else if keyring.dkim.is_some() {
+            Some("DKIM signed mail")
+        } else {
+            None
+        };
+        if let Some(what) = refused {
+            return Err(SendError::new(
+                ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("mailer: refusing to send {} through the {:?} transport", what, transport.kind()),
+                ),
+                Failure::Permanent,
+            ));
+        }
+    }
+
+    // Each locale gets its own copy too, in that language, and each domain when the transport delivers per domain
+    let mut groups: Vec<(&str, bool, String, Vec<Mailbox>)> = Vec::new();
+    for mailbox in recipients {
+        let locale = match config.localization.enabled {
+            true => recipient_locale(&config.localization, mailbox.email.as_ref()),
+            false => "",
+        };
+        let encrypt = keyring.pgp.has_key(mailbox.email.as_ref());
+        let domain = match transport.splits_by_domain() {
+            true => mailbox.email.domain().to_lowercase(),
+            false => String::new(),
+        };
+        match groups
+            .iter_mut()
+            .find(|(existing, encrypted, at, _)| *existing == locale && *encrypted == encrypt && *at == domain)
+        {
+            Some((_, _, _, group)) => group.push(mailbox),
+            None => groups.push((locale, encrypt, domain, vec![mailbox])),
+        }
+    }
+
+    for (locale, encrypt, _, group) in groups {
+        let localized: Cow<EmailPayload> = match config.localization.enabled {
+            true => Cow::Owned(localize(&config.localization, locale, payload)),
+            false => Cow::Borrowed(payload),
+        };
+        let payload = &*localized;
+
+        let email = build_email(keyring, &config.assets, &from, &group, payload, encrypt)?;
+        let formatted = email.formatted();
+        let envelope = match &return_path {
+            Some(address) => Envelope::new(
+                Some(address.clone()),
+                group.iter().map(|mailbox| mailbox.email.clone()).collect(),
+            )
+            .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e)))?,
+            None => email.envelope().clone(),
+        };
+        let message = Outgoing {
+            envelope: &envelope,
+            formatted: &formatted,
+            from: &from,
+            to: &group,
+            payload,
+        };
+        deliver(config, transport, &message)
+            .instrument(info_span!("transport", kind = ?transport.kind(), recipients = group.len(), encrypted = encrypt))
+            .await?;
+        delivered.extend(group.iter().map(|mailbox| mailbox.email.to_string().to_lowercase()));
+    }
+
+    Ok(())
+}
+
+// A MIME entity that can be signed, encrypted or used as the message body as is
+pub enum Entity {
+    Single(SinglePart),
+    Multi(MultiPart),
+}
+
+impl Entity {
+    pub fn formatted(&self) -> Vec<u8> {
+        match self {
+            Entity::Single(part) => part.formatted(),
+            Entity::Multi(part) => part.formatted(),
+        }
+    }
+}
+
+fn content_type(filename: &str, value: &str) -> Result<ContentType, ErrorArrayItem> {
+    ContentType::parse(value)
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}: {}", filename, e)))
+}
+
+// Guessed from the extension, for config assets which carry no content type of their own
+fn asset_type(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+// The HTML body with its inline images as multipart/related, the text body as the alternative
+fn html_entity(assets: &HashMap<String, String>, payload: &EmailPayload, html: &str) -> Result<MultiPart, ErrorArrayItem> {
+    let mut inline: Vec<SinglePart> = Vec::new();
+    for attachment in payload.attachments.iter().filter(|attachment| attachment.content_id.is_some()) {
+        let content_id = attachment.content_id.clone().unwrap_or_default();
+        inline.push(
+            Attachment::new_inline(content_id)
+                .body(attachment.decode()?, content_type(&attachment.filename, &attachment.content_type)?),
+        );
+    }
+
+    // Config assets are only read when the template actually references them
+    for (name, path) in assets {
+        if html.contains(&format!("cid:{}", name)) {
+            let data = fs::read(path)
+                .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("mailer: asset {}: {}: {}", name, path, e)))?;
+            inline.push(Attachment::new_inline(name.clone()).body(data, content_type(path, asset_type(path))?));
+        }
+    }
+
+    let text = SinglePart::plain(payload.body.to_string());
+    let html = SinglePart::html(html.to_owned());
+    if inline.is_empty() {
+        return Ok(MultiPart::alternative().singlepart(text).singlepart(html));
+    }
+
+    let related = inline
+        .into_iter()
+        .fold(MultiPart::related().singlepart(html), |related, part| related.singlepart(part));
+    Ok(MultiPart::alternative().singlepart(text).multipart(related))
+}
+
+// The body text, or text and HTML, plus any attachments
+fn body_entity(assets: &HashMap<String, String>, payload: &EmailPayload) -> Result<Entity, ErrorArrayItem> {
+    let body = match &payload.html {
+        Some(html) => Entity::Multi(html_entity(assets, payload, html)?),
+        None => Entity::Single(SinglePart::plain(payload.body.to_string())),
+    };
+
+    // Inline images already went into the HTML part, without HTML they're ordinary attachments
+    let attached: Vec<_> = payload
+        .attachments
+        .iter()
+        .filter(|attachment| payload.html.is_none() || attachment.content_id.is_none())
+        .collect();
+    if attached.is_empty() {
+        return Ok(body);
+    }
+
+    let mut mixed = match body {
+        Entity::Single(part) => MultiPart::mixed().singlepart(part),
+        Entity::Multi(part) => MultiPart::mixed().multipart(part),
+    };
+    for attachment in attached {
+        mixed = mixed.singlepart(
+            Attachment::new(attachment.filename.clone())
+                .body(attachment.decode()?, content_type(&attachment.filename, &attachment.content_type)?),
+        );
+    }
+
+    Ok(Entity::Multi(mixed))
+}
+
+fn build_email(
+    keyring: &Keyring,
+    assets: &HashMap<String, String>,
+    from: &Mailbox,
+    to: &[Mailbox],
+    payload: &EmailPayload,
+    encrypt: bool,
+) -> Result<Message, ErrorArrayItem> {
     log!(LogLevel::Trace, "Constructing email");
     // Build the email
-    let email = Message::builder()
-        .to(config.smtp.to.parse().map_err(|e: AddressError| {
-            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e.to_string()))
-        })?)
-        .from(config.smtp.from.parse().map_err(|e: AddressError| {
-            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e.to_string()))
-        })?)
-        .subject(subject)
-        .body(body)
-        .map_err(|e| {
-            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e.to_string()))
+    let mut builder = Message::builder();
+    let addresses: Vec<String> = to.iter().map(|mailbox| mailbox.email.to_string()).collect();
+    for mailbox in to {
+        builder = builder.to(mailbox.clone());
+    }
+
+    let mut entity = body_entity(assets, payload)?;
+
+    if let Some(signer) = keyring
+        .smime
+        .iter()
+        .find(|signer| signer.from == from.email.to_string().to_lowercase())
+    {
+        entity = Entity::Multi(signer.sign(entity)?);
+    }
+
+    // Sign first so the signature is only visible to the recipient
+    if encrypt {
+        entity = Entity::Multi(keyring.pgp.encrypt(&addresses, &entity.formatted())?);
+    }
+
+    // lettre folds the Subject and uses RFC 2047 words only for the parts that aren't plain ASCII
+    let mut builder = builder.from(from.clone()).subject(payload.subject.to_string());
+    if let Some(reply_to) = &payload.reply_to {
+        let mailbox: Mailbox = reply_to
+            .parse()
+            .map_err(|e| ErrorArrayItem::new(Errors::InvalidType, format!("mailer: reply_to {}: {}", reply_to, e)))?;
+        builder = builder.reply_to(mailbox);
+    }
+    let mut email = match entity {
+        Entity::Single(part) => builder.singlepart(part),
+        Entity::Multi(part) => builder.multipart(part),
+    }
+    .map_err(|e| {
+        ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e))
+    })?;
+
+    for (name, value) in &payload.headers {
+        let name = HeaderName::new_from_ascii(name.clone()).map_err(|e| {
+            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}: {}", name, e))
         })?;
+        email.headers_mut().insert_raw(HeaderValue::new(name, value.clone()));
+    }
+
+    // DKIM goes last so it covers the final headers
+    if let Some(dkim) = &keyring.dkim {
+        email.sign(dkim);
+    }
+
+    Ok(email)
+}
+
+// Connection settings for the relay derived from `SmtpConfig`
+struct Relay {
+    security: SmtpSecurity,
+    tls: Option<TlsParameters>,
+    hello: ClientId,
+    credentials: Credentials,
+    mechanisms: Vec<Mechanism>,
+}
+
+// Errors without an SMTP reply mean the relay itself couldn't be reached
+pub fn mailer_error(e: lettre::transport::smtp::Error) -> ErrorArrayItem {
+    let kind = match e.is_permanent() || e.is_transient() || e.is_response() {
+        true => Errors::GeneralError,
+        false => Errors::ConnectionError,
+    };
+    ErrorArrayItem::new(kind, format!("mailer: {}", e))
+}
+
+// Classifies a failed SMTP exchange by the reply code lettre parsed, before it is flattened into a message
+pub fn smtp_error(e: lettre::transport::smtp::Error) -> SendError {
+    let failure = match e.status().map(u16::from) {
+        // A refused login says nothing about the message, every send would fail the same way
+        Some(454 | 530 | 534 | 535) => Failure::Connection,
+        Some(_) if e.is_permanent() => Failure::Permanent,
+        Some(_) => Failure::Transient,
+        // A reply that couldn't be parsed still came from a relay that is up
+        None if e.is_response() => Failure::Transient,
+        None => Failure::Connection,
+    };
+    SendError::new(mailer_error(e), failure)
+}
+
+fn relay_settings(config: &SmtpConfig, access_token: Option<&str>) -> Result<Relay, ErrorArrayItem> {
+    let tls = match config.security {
+        SmtpSecurity::None => None,
+        _ => {
+            let domain = config.tls_domain.clone().unwrap_or_else(|| config.server.clone());
+            let mut parameters = TlsParameters::builder(domain)
+                .set_min_tls_version(match config.tls_min_version {
+                    TlsMinVersion::Tls10 => TlsVersion::Tlsv10,
+                    TlsMinVersion::Tls11 => TlsVersion::Tlsv11,
+                    TlsMinVersion::Tls12 => TlsVersion::Tlsv12,
+                });
+
+            if let Some(ca_path) = &config.tls_ca_path {
+                let pem = fs::read(ca_path).map_err(|e| {
+                    ErrorArrayItem::new(Errors::ReadingFile, format!("mailer: {}: {}", ca_path, e))
+                })?;
+                parameters = parameters.add_root_certificate(Certificate::from_pem(&pem).map_err(mailer_error)?);
+            }
+
+            if config.danger_accept_invalid_certs {
+                log!(LogLevel::Warn, "Relay certificate verification is disabled");
+                parameters = parameters.dangerous_accept_invalid_certs(true);
+            }
+
+            Some(parameters.build().map_err(mailer_error)?)
+        }
+    };
+
+    // The SMTP credentials, XOAUTH2 swaps the password for a bearer token
+    let (credentials, mechanisms) = match access_token {
+        Some(token) => (
+            Credentials::new(config.username.to_owned(), token.to_owned()),
+            vec![Mechanism::Xoauth2],
+        ),
+        None => (
+            Credentials::new(config.username.to_owned(), config.password.to_owned()),
+            DEFAULT_MECHANISMS.to_vec(),
+        ),
+    };
+
+    let hello = match &config.ehlo_name {
+        Some(name) => ClientId::Domain(name.clone()),
+        None => ClientId::default(),
+    };
+
+    Ok(Relay {
+        security: config.security,
+        tls,
+        hello,
+        credentials,
+        mechanisms,
+    })
+}
+
+// Builds the relay connection using the configured transport security
+fn build_transport(config: &SmtpConfig, relay: Relay) -> AsyncSmtpTransport<Tokio1Executor> {
+    let builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.server)
+        .port(config.port)
+        .hello_name(relay.hello)
+        .credentials(relay.credentials)
+        .authentication(relay.mechanisms);
+
+    match (relay.security, relay.tls) {
+        (SmtpSecurity::Starttls, Some(tls)) => builder.tls(Tls::Required(tls)),
+        (SmtpSecurity::Tls, Some(tls)) => builder.tls(Tls::Wrapper(tls)),
+        _ => builder.tls(Tls::None),
+    }
+    .build()
+}
+
+// The pooled transport can't pick a source address, so bound sends drive the connection directly
+async fn send_from(
+    config: &SmtpConfig,
+    relay: Relay,
+    source: IpAddr,
+    envelope: &Envelope,
+    email: &[u8],
+) -> Result<(), lettre::transport::smtp::Error> {
+    let wrapper = match relay.security {
+        SmtpSecurity::Tls => relay.tls.clone(),
+        _ => None,
+    };
+
+    let mut conn = AsyncSmtpConnection::connect_tokio1(
+        (config.server.as_str(), config.port),
+        Some(Duration::from_secs(60)),
+        &relay.hello,
+        wrapper,
+        Some(source),
+    )
+    .await?;
 
-    // The SMTP credentials
-    let creds = Credentials::new(config.smtp.username.to_owned(), config.smtp.password.to_owned());
+    if let (SmtpSecurity::Starttls, Some(tls)) = (relay.security, relay.tls) {
+        conn.starttls(tls, &relay.hello).await?;
+    }
 
-    let mailer = SmtpTransport::relay("mail.ramfield.net")
-        .map_err(|e| {
-            ErrorArrayItem::new(Errors::GeneralError, format!("mailer: {}", e.to_string()))
-        })?
-        .credentials(creds)
-        .build();
+    conn.auth(&relay.mechanisms, &relay.credentials).await?;
+    conn.send(envelope, email).await?;
+    conn.quit().await?;
+    Ok(())
+}
 
+// Connects and authenticates to the relay without sending anything
+pub async fn verify_relay(config: &SmtpConfig, access_token: Option<&str>) -> Result<(), ErrorArrayItem> {
+    let relay = relay_settings(config, access_token)?;
+
+    match build_transport(config, relay).test_connection().await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ErrorArrayItem::new(
+            Errors::ConnectionError,
+            format!("mailer: {}:{} did not answer NOOP", config.server, config.port),
+        )),
+        Err(e) => Err(mailer_error(e)),
+    }
+}
+
+// Hands a rendered message to the configured relay
+pub async fn send_via_relay(
+    config: &SmtpConfig,
+    access_token: Option<&str>,
+    envelope: &Envelope,
+    email: &[u8],
+) -> Result<(), SendError> {
+    let relay = relay_settings(config, access_token)?;
+
+    match config.bind_address {
+        Some(source) => send_from(config, relay, source, envelope, email).await,
+        None => build_transport(config, relay)
+            .send_raw(envelope, email)
+            .await
+            .map(|_| ()),
+    }
+    .map_err(smtp_error)
+}
+
+async fn deliver(config: &AppConfig, transport: &dyn MailTransport, message: &Outgoing<'_>) -> Result<(), SendError> {
     // Send the email
-    log!(LogLevel::Trace, "Match statement before sending email");
-    let d = match mailer.send(&email) {
+    log!(LogLevel::Trace, "Sending email through the {:?} transport", transport.kind());
+    let result = transport.send(message).await;
+
+    // Fall back to the local MTA only when the relay was unreachable, not when it refused the mail
+    let result = match result {
+        Err(e) if transport.kind() == TransportKind::Smtp && e.error.err_type == Errors::ConnectionError && config.smtp.sendmail_fallback => {
+            log!(LogLevel::Warn, "Relay unreachable, handing message to sendmail: {}", e);
+            Sendmail { config: &config.smtp }.send(message).await
+        }
+        result => result,
+    };
+
+    let d = match result {
         Ok(_) => {
             log!(LogLevel::Info, "Email sent successfully.");
+            if config.maildir.archive && transport.kind() != TransportKind::Maildir {
+                if let Err(e) = write_maildir(&config.maildir.path, message.formatted).await {
+                    log!(LogLevel::Warn, "Failed to archive sent message: {}", e);
+                }
+            }
+            if config.archive.enabled {
+                if let Err(e) = archive_eml(&config.archive, message.formatted).await {
+                    log!(LogLevel::Warn, "Failed to archive sent message: {}", e);
+                }
+            }
+            if let Some(imap) = &config.imap {
+                if let Err(e) = append_sent(imap, message.formatted).await {
+                    log!(LogLevel::Warn, "Failed to append sent message over IMAP: {}", e);
+                }
+            }
             Ok(())
         }
         Err(e) => {
             log!(LogLevel::Error, "Failed to send email: {}", e);
-            Err(ErrorArrayItem::new(
-                Errors::GeneralError,
-                format!("mailer: {}", e.to_string()),
-            ))
+            Err(e)
         }
     };
 
     log!(LogLevel::Trace, "Email processed returning");
     d
-}
\ No newline at end of file
+}
@@ -0,0 +1,93 @@
+use std::{collections::HashMap, fs};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::message::{
+    header::{ContentDisposition, ContentType},
+    MultiPart, SinglePart,
+};
+use pgp::{
+    crypto::sym::SymmetricKeyAlgorithm, types::PublicKeyTrait, ArmorOptions, Deserializable,
+    Message, SignedPublicKey, SignedPublicSubKey,
+};
+
+use crate::config::PgpConfig;
+
+// Public keys of recipients that want their mail encrypted, keyed by lowercase address
+#[derive(Debug, Default)]
+pub struct PgpKeys {
+    keys: HashMap<String, SignedPublicKey>,
+}
+
+pub fn load_pgp(config: &PgpConfig) -> Result<PgpKeys, ErrorArrayItem> {
+    let mut keys = HashMap::new();
+
+    for (address, path) in &config.keys {
+        let armored = fs::read_to_string(path).map_err(|e| {
+            ErrorArrayItem::new(Errors::ReadingFile, format!("pgp: {}: {}", path, e))
+        })?;
+
+        let (key, _) = SignedPublicKey::from_string(&armored).map_err(|e| {
+            ErrorArrayItem::new(Errors::InvalidKey, format!("pgp: {}: {}", address, e))
+        })?;
+
+        if encryption_subkey(&key).is_none() {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidKey,
+                format!("pgp: {}: key has no encryption subkey", address),
+            ));
+        }
+
+        log!(LogLevel::Info, "Loaded PGP key for {}", address);
+        keys.insert(address.to_lowercase(), key);
+    }
+
+    Ok(PgpKeys { keys })
+}
+
+fn encryption_subkey(key: &SignedPublicKey) -> Option<&SignedPublicSubKey> {
+    key.public_subkeys
+        .iter()
+        .find(|subkey| subkey.is_encryption_key())
+}
+
+impl PgpKeys {
+    pub fn has_key(&self, address: &str) -> bool {
+        self.keys.contains_key(&address.to_lowercase())
+    }
+
+    // Encrypts a complete MIME entity to every recipient, producing PGP/MIME (RFC 3156) output
+    pub fn encrypt(&self, recipients: &[String], entity: &[u8]) -> Result<MultiPart, ErrorArrayItem> {
+        let subkeys: Vec<&SignedPublicSubKey> = recipients
+            .iter()
+            .filter_map(|address| self.keys.get(&address.to_lowercase()))
+            .filter_map(encryption_subkey)
+            .collect();
+
+        let armored = Message::new_literal_bytes("", entity)
+            .encrypt_to_keys_seipdv1(rand::thread_rng(), SymmetricKeyAlgorithm::AES256, &subkeys)
+            .and_then(|message| message.to_armored_string(ArmorOptions::default()))
+            .map_err(|e| ErrorArrayItem::new(Errors::MessageEncode, format!("pgp: {}", e)))?;
+
+        let content_type = |value: &str| {
+            ContentType::parse(value)
+                .map_err(|e| ErrorArrayItem::new(Errors::MessageEncode, format!("pgp: {}", e)))
+        };
+
+        Ok(MultiPart::encrypted(String::from("application/pgp-encrypted"))
+            .singlepart(
+                SinglePart::builder()
+                    .header(content_type("application/pgp-encrypted")?)
+                    .body(String::from("Version: 1")),
+            )
+            .singlepart(
+                SinglePart::builder()
+                    .header(content_type("application/octet-stream; name=\"encrypted.asc\"")?)
+                    .header(ContentDisposition::inline_with_name("encrypted.asc"))
+                    .body(armored),
+            ))
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Instant;
+
+use dusa_collection_utils::{
+    errors::ErrorArrayItem,
+    functions::{create_hash, truncate},
+    log,
+    log::LogLevel,
+    rwarc::LockWithTimeout,
+    stringy::Stringy,
+};
+
+use crate::{
+    config::ErrorDigestConfig,
+    payload::{EmailPayload, Priority},
+};
+
+// A failure waiting to be summarised for operators, repeats are told apart by the hash of their message
+#[derive(Debug, Clone)]
+pub struct ErrorEmail {
+    pub hash: Stringy,
+    pub subject: Option<String>,
+    pub occoured_at: Instant,
+}
+
+impl ErrorEmail {
+    pub fn new(message: String) -> Self {
+        Self {
+            hash: truncate(&*create_hash(message.clone()), 10).to_owned(),
+            subject: Some(message),
+            occoured_at: Instant::now(),
+        }
+    }
+}
+
+// One line per distinct error with how often and how recently it happened
+pub fn compose_error_digest(config: &ErrorDigestConfig, errors: &[ErrorEmail]) -> EmailPayload {
+    let mut seen: Vec<(&ErrorEmail, usize, Instant)> = Vec::new();
+    for error in errors {
+        match seen.iter_mut().find(|(first, _, _)| first.hash == error.hash) {
+            Some((_, count, last)) => {
+                *count += 1;
+                *last = (*last).max(error.occoured_at);
+            }
+            None => seen.push((error, 1, error.occoured_at)),
+        }
+    }
+
+    let mut body = format!("{} failures, {} distinct:\n\n", errors.len(), seen.len());
+    for (error, count, last) in &seen {
+        body.push_str(&format!(
+            "[{}] x{}, last {}s ago\n{}\n\n",
+            error.hash,
+            count,
+            last.elapsed().as_secs(),
+            error.subject.as_deref().unwrap_or("unknown")
+        ));
+    }
+
+    let mut email = EmailPayload::new(config.subject.replace("{count}", &errors.len().to_string()), body);
+    email.priority = Priority::High;
+    email.to = config.to.clone();
+    email
+}
+
+pub async fn record_error(errors: &LockWithTimeout<Vec<ErrorEmail>>, error: &ErrorArrayItem) {
+    match errors.try_write_with_timeout(None).await {
+        Ok(mut errors) => errors.push(ErrorEmail::new(error.to_string())),
+        Err(e) => log!(LogLevel::Error, "Failed to record error: {}", e),
+    }
+}
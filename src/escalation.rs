@@ -0,0 +1,66 @@
+use dusa_collection_utils::{errors::ErrorArrayItem, log, log::LogLevel};
+
+use crate::{
+    config::AppConfig,
+    payload::{EmailPayload, Priority},
+    twilio::send_sms,
+};
+
+// Moves a message that keeps failing over to the escalation channels, returning the operator notice to queue. The
+// email is only given up on when a channel or a text took over, otherwise it keeps retrying
+pub async fn escalate(config: &AppConfig, email: &mut EmailPayload, error: &ErrorArrayItem, attempts: u32) -> Option<EmailPayload> {
+    let escalation = &config.escalation;
+    log!(
+        LogLevel::Warn,
+        "Escalating {} after {} failed attempts",
+        email.summary(config.app.redact_logs),
+        attempts
+    );
+
+    for channel in &escalation.channels {
+        if !email.channels.contains(channel) {
+            email.channels.push(channel.clone());
+        }
+    }
+
+    let mut texted = false;
+    if escalation.sms {
+        match &config.twilio {
+            Some(twilio) => match send_sms(twilio, email).await {
+                Ok(()) => texted = true,
+                Err(e) => log!(LogLevel::Error, "Failed to text the escalation: {}", e),
+            },
+            None => log!(LogLevel::Error, "Escalation wants SMS but no [twilio] section is configured"),
+        }
+    }
+
+    // Stop retrying the email once something else carries the message
+    if !escalation.channels.is_empty() || texted {
+        email.skip_email = true;
+    }
+
+    if escalation.notify.is_empty() {
+        return None;
+    }
+
+    let mut notice = EmailPayload::new(
+        format!("Escalated: {}", email.subject),
+        format!(
+            "Delivery of \"{}\" failed {} times, last error: {}\nIt has been re-routed to: {}\n\n{}",
+            email.subject,
+            attempts,
+            error,
+            match (escalation.channels.is_empty(), texted) {
+                (true, false) => "nothing, the email is still being retried".to_owned(),
+                (true, true) => "SMS".to_owned(),
+                (false, false) => escalation.channels.join(", "),
+                (false, true) => format!("{}, SMS", escalation.channels.join(", ")),
+            },
+            email.body
+        ),
+    );
+    notice.priority = Priority::High;
+    notice.client = email.client.clone();
+    notice.to = escalation.notify.clone();
+    Some(notice)
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    process::{Command, Output, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    functions::create_hash,
+    log,
+    log::LogLevel,
+};
+
+use crate::{
+    config::{DedupFilter, ExecFilter, FilterConfig, HeaderFilter, SizeFilter},
+    payload::EmailPayload,
+};
+
+// sysexits EX_TEMPFAIL, an exec filter that can't decide right now
+const EXIT_TEMPFAIL: i32 = 75;
+
+// What the pipeline decided about a submission
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    // Not queued, with the reason to give the sender
+    Refuse(String),
+}
+
+// Messages filed recently, for the dedup filter
+#[derive(Debug, Default)]
+pub struct DedupCache {
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl DedupCache {
+    // Whether the same message was filed within `window`, the first copy starts the window
+    fn seen_within(&self, key: String, window: Duration) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        seen.retain(|_, first| first.elapsed() < window);
+        if seen.contains_key(&key) {
+            return true;
+        }
+        seen.insert(key, Instant::now());
+        false
+    }
+}
+
+// Runs every configured filter in order, each sees the message as the previous one left it
+pub async fn run_filters(
+    filters: &[FilterConfig],
+    dedup: &DedupCache,
+    email: &mut EmailPayload,
+) -> Result<Verdict, ErrorArrayItem> {
+    for filter in filters {
+        let verdict = match filter {
+            FilterConfig::Size(size) => check_size(size, email),
+            FilterConfig::Dedup(config) => check_dedup(config, dedup, email),
+            FilterConfig::Headers(headers) => {
+                rewrite_headers(headers, email);
+                Verdict::Pass
+            }
+            FilterConfig::Exec(exec) => run_exec(exec, email).await?,
+        };
+
+        if let Verdict::Refuse(reason) = &verdict {
+            log!(LogLevel::Warn, "Filter {} refused submission: {}", filter.name(), reason);
+            return Ok(verdict);
+        }
+    }
+    Ok(Verdict::Pass)
+}
+
+// Body, HTML and attachments together, attachments counted as submitted
+fn check_size(config: &SizeFilter, email: &EmailPayload) -> Verdict {
+    let size = email.body.len()
+        + email.html.as_ref().map_or(0, String::len)
+        + email.attachments.iter().map(|attachment| attachment.content.len()).sum::<usize>();
+    match size as u64 > config.max_kb * 1024 {
+        true => Verdict::Refuse(format!("message is {} KB, the limit is {} KB", size / 1024, config.max_kb)),
+        false => Verdict::Pass,
+    }
+}
+
+// A retrying client or a flapping check shouldn't fill the queue with copies of one alert
+fn check_dedup(config: &DedupFilter, dedup: &DedupCache, email: &EmailPayload) -> Verdict {
+    let key = create_hash(format!(
+        "{}{}{}{}",
+        email.subject,
+        email.body,
+        email.client.as_deref().unwrap_or_default(),
+        email.to.join(",")
+    ))
+    .to_string();
+
+    match dedup.seen_within(key, Duration::from_secs(config.window_seconds)) {
+        true => Verdict::Refuse(format!("duplicate of a message filed in the last {}s", config.window_seconds)),
+        false => Verdict::Pass,
+    }
+}
+
+fn rewrite_headers(config: &HeaderFilter, email: &mut EmailPayload) {
+    email
+        .headers
+        .retain(|name, _| !config.remove.iter().any(|removed| removed.eq_ignore_ascii_case(name)));
+    for (name, value) in &config.set {
+        email.headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+        email.headers.insert(name.clone(), value.clone());
+    }
+}
+
+// The message goes to the command as JSON on stdin. Exit 0 accepts it, replaced by the JSON on stdout if there is
+// any, 75 asks the sender to try later and anything else refuses it with stderr as the reason
+async fn run_exec(config: &ExecFilter, email: &mut EmailPayload) -> Result<Verdict, ErrorArrayItem> {
+    let input = serde_json::to_vec(email).map_err(ErrorArrayItem::from)?;
+    let command = config.command.clone();
+    let program = command.first().cloned().unwrap_or_default();
+
+    // A hung filter is given up on, the process itself is left to finish
+    let output = tokio::time::timeout(
+        Duration::from_secs(config.timeout_seconds),
+        tokio::task::spawn_blocking(move || exec(&command, input)),
+    )
+    .await
+    .map_err(|_| {
+        ErrorArrayItem::new(
+            Errors::TimedOut,
+            format!("filter {}: no answer after {}s", program, config.timeout_seconds),
+        )
+    })?
+    .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("filter {}: {}", program, e)))?
+    .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("filter {}: {}", program, e)))?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    match output.status.code() {
+        Some(0) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if !stdout.trim().is_empty() {
+                *email = EmailPayload::from_json(&stdout).map_err(|e| {
+                    ErrorArrayItem::new(Errors::InvalidType, format!("filter {}: {}", program, e.err_mesg))
+                })?;
+            }
+            Ok(Verdict::Pass)
+        }
+        Some(EXIT_TEMPFAIL) => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("filter {}: try again later: {}", program, stderr),
+        )),
+        _ if stderr.is_empty() => Ok(Verdict::Refuse(format!("rejected by {} ({})", program, output.status))),
+        _ => Ok(Verdict::Refuse(stderr)),
+    }
+}
+
+fn exec(command: &[String], input: Vec<u8>) -> std::io::Result<Output> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty command"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Written from its own thread so a filter that answers before reading everything can't deadlock us
+    let mut stdin = child.stdin.take();
+    let writer = std::thread::spawn(move || {
+        if let Some(stdin) = stdin.as_mut() {
+            let _ = stdin.write_all(&input);
+        }
+    });
+    let output = child.wait_with_output();
+    let _ = writer.join();
+    output
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use chrono_tz::Tz;
+
+use crate::{config::AppConfig, maildir::gethostname, payload::EmailPayload};
+
+// Server-side additions to the outgoing message, applied per send so a retry doesn't add them twice
+pub fn decorate(config: &AppConfig, payload: &EmailPayload) -> EmailPayload {
+    let mut email = payload.clone();
+    let settings = &config.headers;
+
+    if let Some(template) = &settings.subject_template {
+        email.subject = template
+            .replace("{env}", &settings.environment)
+            .replace("{hostname}", &gethostname())
+            .replace("{client}", payload.client.as_deref().unwrap_or("unknown"))
+            .replace("{subject}", &payload.subject)
+            .into();
+    }
+
+    // The client header is the server's to set, a submitted one never goes out
+    if settings.client_header && payload.client.is_none() {
+        email.headers.retain(|name, _| !name.eq_ignore_ascii_case("X-Artisan-Client"));
+    }
+
+    if let Some(client) = &payload.client {
+        let client = client.replace(['\r', '\n'], " ");
+        if settings.client_header {
+            set_header(&mut email.headers, "X-Artisan-Client", &client);
+        }
+        if settings.client_subject_prefix {
+            email.subject = format!("[{}] {}", client, email.subject).into();
+        }
+    }
+
+    if let Some(template) = &settings.list_id {
+        let id = template
+            .replace("{env}", &settings.environment)
+            .replace("{client}", payload.client.as_deref().unwrap_or("unknown"));
+        set_header(&mut email.headers, "List-Id", &format!("<{}>", list_id(&id)));
+    }
+
+    if settings.category_header {
+        let category = payload.category.clone().or_else(|| payload.severity.map(|severity| severity.to_string()));
+        if let Some(category) = category {
+            set_header(&mut email.headers, "X-Category", &category.replace(['\r', '\n'], " "));
+        }
+    }
+
+    if settings.auto_submitted {
+        set_header(&mut email.headers, "Auto-Submitted", "auto-generated");
+    }
+
+    // Operator headers take precedence over anything the client submitted
+    for (name, value) in &settings.custom {
+        set_header(&mut email.headers, name, value);
+    }
+
+    // Always the server's own, written at send time rather than left to the transport
+    email.headers.insert("Date".to_owned(), in_zone(settings.timezone, Utc::now()).to_rfc2822());
+    if let Some(submitted_at) = payload.submitted_at {
+        email.headers.insert("X-Submitted-At".to_owned(), in_zone(settings.timezone, submitted_at).to_rfc3339());
+    }
+
+    email
+}
+
+fn in_zone(timezone: Option<Tz>, time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    match timezone {
+        Some(timezone) => time.with_timezone(&timezone).fixed_offset(),
+        None => time.with_timezone(&Local).fixed_offset(),
+    }
+}
+
+// Replaces a header whatever case the submitter spelled it in, so the value set here is the one that goes out
+pub fn set_header(headers: &mut BTreeMap<String, String>, name: &str, value: &str) {
+    headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+    headers.insert(name.to_owned(), value.to_owned());
+}
+
+// Headers that come from the sender checks, the recipients or the server itself. A submission or the config setting
+// one could go out signed as somebody else, or replace the MIME structure and trace lines the message was built with
+const RESERVED: [&str; 15] = [
+    "From",
+    "Sender",
+    "Reply-To",
+    "To",
+    "Cc",
+    "Bcc",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "DKIM-Signature",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "MIME-Version",
+    "Return-Path",
+    "Received",
+];
+
+pub fn reserved_header(name: &str) -> bool {
+    RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(name.trim()))
+}
+
+// Refuses a submission naming a reserved header among its extra headers
+pub fn check_reserved(headers: &BTreeMap<String, String>) -> Result<(), String> {
+    match headers.keys().find(|name| reserved_header(name)) {
+        Some(name) => Err(format!("header {} can't be set by the submission", name)),
+        None => Ok(()),
+    }
+}
+
+// A List-Id is a dot-atom, so anything a client name adds outside that becomes a hyphen
+fn list_id(id: &str) -> String {
+    id.chars()
+        .map(|c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_reserved_headers_in_any_case() {
+        for name in ["content-type", "MIME-Version", " Received ", "Return-Path", "Content-Transfer-Encoding", "from"] {
+            let headers = BTreeMap::from([(name.to_owned(), String::from("x"))]);
+            assert!(check_reserved(&headers).is_err(), "{}", name);
+        }
+        let headers = BTreeMap::from([(String::from("X-Ticket"), String::from("42"))]);
+        assert!(check_reserved(&headers).is_ok());
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use crate::{config::HeartbeatConfig, maildir::gethostname, payload::EmailPayload};
+
+// Due on the first round after startup, then every `heartbeat.interval_hours`
+pub fn heartbeat_due(config: &HeartbeatConfig, last_sent: Option<Instant>) -> bool {
+    let interval = Duration::from_secs(config.interval_hours * 60 * 60);
+    config.enabled && last_sent.is_none_or(|sent| sent.elapsed() >= interval)
+}
+
+// Goes through the queue and the relay like any other mail, so an external monitor that stops seeing these knows
+// the whole pipeline is down even while the process still looks alive
+pub fn compose_heartbeat(config: &HeartbeatConfig, queued: usize) -> EmailPayload {
+    let hostname = gethostname();
+    let now = Local::now();
+    let subject = config.subject.replace("{hostname}", &hostname);
+    let body = format!(
+        "MailRegulator {} on {} is alive at {}.\n\nQueued: {}\nNext heartbeat within {} hours.\n",
+        env!("CARGO_PKG_VERSION"),
+        hostname,
+        now.to_rfc3339(),
+        queued,
+        config.interval_hours
+    );
+
+    let mut email = EmailPayload::new(subject, body);
+    email.to = config.to.clone();
+    email
+}
@@ -0,0 +1,77 @@
+use std::fmt::Debug;
+
+use async_imap::{Client, Session};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::config::{ImapConfig, SmtpSecurity};
+
+// Plain and TLS connections behind one type so callers don't care which was negotiated
+pub trait ImapStream: AsyncRead + AsyncWrite + Unpin + Debug + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Debug + Send> ImapStream for T {}
+
+pub type ImapSession = Session<Box<dyn ImapStream>>;
+
+pub fn imap_error(e: impl std::fmt::Display) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::ConnectionError, format!("imap: {}", e))
+}
+
+// Connects, secures the connection as configured and logs in
+pub async fn login(config: &ImapConfig) -> Result<ImapSession, ErrorArrayItem> {
+    let tcp = TcpStream::connect((config.server.as_str(), config.port))
+        .await
+        .map_err(imap_error)?;
+    let tls = TlsConnector::from(native_tls::TlsConnector::new().map_err(imap_error)?);
+
+    let stream: Box<dyn ImapStream> = match config.security {
+        SmtpSecurity::Tls => Box::new(tls.connect(&config.server, tcp).await.map_err(imap_error)?),
+        SmtpSecurity::Starttls => {
+            let mut client = greeted(tcp).await?;
+            client.run_command_and_check_ok("STARTTLS", None).await.map_err(imap_error)?;
+            Box::new(tls.connect(&config.server, client.into_inner()).await.map_err(imap_error)?)
+        }
+        SmtpSecurity::None => Box::new(tcp),
+    };
+
+    // STARTTLS already consumed the greeting on the plaintext side
+    let client = match config.security {
+        SmtpSecurity::Starttls => Client::new(stream),
+        _ => greeted(stream).await?,
+    };
+
+    client.login(&config.username, &config.password).await.map_err(|(e, _)| {
+        ErrorArrayItem::new(Errors::AuthenticationError, format!("imap: {}", e))
+    })
+}
+
+// The server greeting has to be consumed before the first command
+async fn greeted<T: ImapStream>(stream: T) -> Result<Client<T>, ErrorArrayItem> {
+    let mut client = Client::new(stream);
+    match client.read_response().await {
+        Some(Ok(_)) => Ok(client),
+        Some(Err(e)) => Err(imap_error(e)),
+        None => Err(imap_error("connection closed before the greeting")),
+    }
+}
+
+// Copies a delivered message into the shared mailbox's Sent folder, marked as read
+pub async fn append_sent(config: &ImapConfig, email: &[u8]) -> Result<(), ErrorArrayItem> {
+    let mailbox = config.mailbox.as_deref().unwrap_or("Sent");
+    let mut session = login(config).await?;
+
+    let appended = session.append(mailbox, Some("(\\Seen)"), None, email).await;
+    let _ = session.logout().await;
+    appended.map_err(imap_error)?;
+
+    log!(LogLevel::Debug, "Appended sent message to {} on {}", mailbox, config.server);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use tokio::fs;
+
+use crate::{
+    config::InboxConfig,
+    payload::EmailPayload,
+    receiver::{file_submission, Filed, Intake},
+    rfc822::from_rfc822,
+};
+
+async fn parse(path: &Path) -> Result<EmailPayload, ErrorArrayItem> {
+    let data = fs::read(path)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("inbox: {}: {}", path.display(), e)))?;
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("eml") => from_rfc822(&data, true),
+        _ => EmailPayload::from_json(&String::from_utf8_lossy(&data)),
+    }
+}
+
+// Moves a processed file into `done` or `failed` next to it
+async fn file_away(path: &Path, outcome: &str) {
+    let Some(directory) = path.parent().map(|parent| parent.join(outcome)) else {
+        return;
+    };
+    let target: PathBuf = directory.join(path.file_name().unwrap_or_default());
+
+    let moved = match fs::create_dir_all(&directory).await {
+        Ok(_) => fs::rename(path, &target).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = moved {
+        log!(LogLevel::Error, "Failed to move {} to {}: {}", path.display(), target.display(), e);
+    }
+}
+
+// Ingests `.json` payloads and `.eml` messages dropped into the inbox, writers should rename a finished file in
+// so half-written ones are never picked up
+pub async fn scan_inbox(config: &InboxConfig, intake: &Intake<'_>) -> usize {
+    let mut entries = match fs::read_dir(&config.path).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log!(LogLevel::Warn, "Failed to read inbox {}: {}", config.path, e);
+            return 0;
+        }
+    };
+
+    let mut accepted = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        let wanted = matches!(path.extension().and_then(|extension| extension.to_str()), Some("json" | "eml"));
+        if !wanted || !entry.file_type().await.map(|kind| kind.is_file()).unwrap_or(false) {
+            continue;
+        }
+
+        let source = format!("inbox:{}", path.display());
+        let filed = match parse(&path).await {
+            Ok(email) => file_submission(intake, email, &source, None).await,
+            Err(e) => Err(e),
+        };
+
+        match filed {
+            Ok(Filed::Accepted) => {
+                accepted += 1;
+                file_away(&path, "done").await;
+            }
+            Ok(Filed::Refused(reason)) => {
+                log!(LogLevel::Warn, "Refused {}: {}", path.display(), reason);
+                file_away(&path, "failed").await;
+            }
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to ingest {}: {}", path.display(), e);
+                file_away(&path, "failed").await;
+            }
+        }
+    }
+
+    if accepted > 0 {
+        log!(LogLevel::Info, "Queued {} messages from the inbox", accepted);
+    }
+    accepted
+}
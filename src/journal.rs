@@ -0,0 +1,65 @@
+use std::process::Command;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+
+use crate::{
+    config::JournalConfig,
+    payload::{Attachment, JournalRequest},
+};
+
+// Unit names are passed to journalctl as an argument, keep them to what systemd allows
+fn valid_unit(unit: &str) -> bool {
+    !unit.is_empty()
+        && !unit.starts_with('-')
+        && unit.chars().all(|c| c.is_ascii_alphanumeric() || ":-_.@\\".contains(c))
+}
+
+// The last lines journald holds for a unit, as a text attachment named after it
+pub async fn journal_excerpt(config: &JournalConfig, request: &JournalRequest) -> Result<Attachment, ErrorArrayItem> {
+    if !valid_unit(&request.unit) {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidType,
+            format!("journal: invalid unit name {}", request.unit),
+        ));
+    }
+
+    // Only listed units can be read, an empty list allows none
+    if !config.units.contains(&request.unit) {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("journal: unit {} is not in journal.units", request.unit),
+        ));
+    }
+
+    let lines = request.lines.clamp(1, config.max_lines.max(1));
+    let unit = request.unit.clone();
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new("journalctl")
+            .arg(format!("--unit={}", unit))
+            .arg(format!("--lines={}", lines))
+            .args(["--no-pager", "--output=short-iso"])
+            .output()
+    })
+    .await
+    .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("journal: {}", e)))?
+    .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("journal: journalctl: {}", e)))?;
+
+    if !output.status.success() {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "journal: journalctl exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(Attachment {
+        filename: format!("{}.log", request.unit.replace(['/', '\\'], "_")),
+        content_type: String::from("text/plain"),
+        content: STANDARD.encode(&output.stdout),
+        content_id: None,
+    })
+}
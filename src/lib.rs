@@ -0,0 +1,102 @@
+// Queueing, routing and delivery for Artisan notification mail. The `MailRegulator` binary is `run` plus a few
+// one-shot admin commands, services that would rather not go over the wire can file and send mail through
+// `file_submission` and a `QueueRunner` directly.
+
+mod admin;
+mod archive;
+mod attachments;
+mod audit;
+mod auth;
+mod bounces;
+mod breaker;
+mod channels;
+mod check;
+pub mod config;
+mod daemon;
+mod deadletter;
+mod diagnostics;
+mod digest;
+mod discord;
+mod dkim;
+mod email;
+mod encryption;
+mod errordigest;
+mod escalation;
+mod filters;
+mod headers;
+mod heartbeat;
+mod imap;
+mod inbox;
+mod journal;
+mod limits;
+mod locale;
+mod mailgun;
+mod maildir;
+mod metrics;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod matrix;
+mod monitor;
+mod mx;
+mod oauth;
+mod pagerduty;
+mod payload;
+mod protocol;
+mod push;
+mod queue;
+mod quiet;
+mod ratelimit;
+mod receiver;
+mod report;
+mod retry;
+mod rfc822;
+mod routing;
+mod runner;
+mod schedule;
+mod script;
+mod selftest;
+mod senders;
+mod sendgrid;
+mod ses;
+mod signals;
+mod slack;
+mod smime;
+mod smtp_sink;
+mod spool;
+mod statsd;
+mod submit;
+mod submission;
+mod suppression;
+mod systemd;
+mod telegram;
+mod telemetry;
+mod transport;
+mod twilio;
+mod vault;
+mod webhook;
+
+pub use admin::{fetch_queue, request_action, request_requeue, Action, QueueListing};
+pub use check::check_config;
+pub use daemon::{run, Options};
+pub use deadletter::{parse_time, Selection};
+pub use payload::{EmailPayload, Priority};
+pub use queue::TimedEmail;
+pub use receiver::{file_submission, Filed, Intake, Shared};
+pub use rfc822::from_rfc822;
+pub use runner::{Dispatch, QueueRunner};
+pub use submit::submit;
+
+// What a `Dispatch` borrows and what a round reports back
+pub use audit::AuditLog;
+pub use breaker::BreakerState;
+pub use email::Keyring;
+pub use metrics::Metrics;
+pub use retry::{Failure, SendError};
+pub use statsd::StatsD;
+pub use suppression::SuppressionList;
+pub use transport::MailTransport;
+
+#[cfg(any(test, feature = "mock"))]
+pub use mock::MockTransport;
+#[cfg(any(test, feature = "mock"))]
+pub use smtp_sink::SmtpSink;
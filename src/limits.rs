@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use artisan_middleware::communication_proto::ProtocolStatus;
+use dusa_collection_utils::{log, log::LogLevel};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc::Sender,
+};
+
+use crate::{
+    config::{BanConfig, ConnectionConfig},
+    protocol::send_status_tcp,
+};
+
+// Connections accepted but not yet finished, overall and per peer address
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    peers: HashMap<IpAddr, usize>,
+}
+
+// Held for as long as a connection is open, dropping it frees the slot
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    counts: Arc<Mutex<Counts>>,
+    peer: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        counts.total = counts.total.saturating_sub(1);
+        if let Some(open) = counts.peers.get_mut(&self.peer) {
+            *open -= 1;
+            if *open == 0 {
+                counts.peers.remove(&self.peer);
+            }
+        }
+    }
+}
+
+// Open connections across every listener, shared so the caps hold for the protocol and SMTP ports together
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl Connections {
+    pub fn acquire(&self, config: &ConnectionConfig, peer: IpAddr) -> Option<ConnectionPermit> {
+        let mut guard = self.counts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let open = guard.peers.get(&peer).copied().unwrap_or(0);
+        if guard.total >= config.max_total || open >= config.max_per_peer {
+            return None;
+        }
+
+        guard.total += 1;
+        guard.peers.insert(peer, open + 1);
+        Some(ConnectionPermit {
+            counts: self.counts.clone(),
+            peer,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Offences {
+    // Failures inside the current window
+    failures: Vec<Instant>,
+    banned_until: Option<Instant>,
+}
+
+// Peers that keep sending malformed or unauthenticated submissions, shared with the accept task
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    peers: Arc<Mutex<HashMap<IpAddr, Offences>>>,
+}
+
+impl BanList {
+    pub fn is_banned(&self, peer: IpAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match peers.get(&peer).and_then(|offences| offences.banned_until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                peers.remove(&peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Counts a rejected submission, returning true when it gets the peer banned
+    pub fn record_failure(&self, config: &BanConfig, peer: IpAddr) -> bool {
+        if !config.enabled {
+            return false;
+        }
+
+        let now = Instant::now();
+        let window = Duration::from_secs(config.window_seconds);
+        let mut peers = self.peers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Peers with nothing recent are forgotten so the map doesn't grow with every address ever seen
+        peers.retain(|_, offences| {
+            offences.banned_until.is_some_and(|until| until > now)
+                || offences.failures.iter().any(|failed| now.duration_since(*failed) < window)
+        });
+
+        let offences = peers.entry(peer).or_default();
+        offences.failures.retain(|failed| now.duration_since(*failed) < window);
+        offences.failures.push(now);
+        if offences.failures.len() < config.max_failures as usize {
+            return false;
+        }
+
+        offences.failures.clear();
+        offences.banned_until = Some(now + Duration::from_secs(config.ban_seconds));
+        log!(
+            LogLevel::Warn,
+            "Banning {} for {}s after {} rejected submissions in {}s",
+            peer,
+            config.ban_seconds,
+            config.max_failures,
+            config.window_seconds
+        );
+        true
+    }
+}
+
+// Accepts connections on their own task so waiting ones are bounded, the main loop still handles them one at a time
+pub fn accept_monitor(
+    listener: TcpListener,
+    config: ConnectionConfig,
+    connections: Connections,
+    bans: BanList,
+    sender: Sender<(TcpStream, SocketAddr, ConnectionPermit)>,
+) {
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log!(LogLevel::Warn, "Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            // Banned peers are dropped without a reply, there's nothing useful to tell them
+            if bans.is_banned(peer.ip()) {
+                log!(LogLevel::Debug, "Dropping connection from banned peer {}", peer);
+                continue;
+            }
+
+            match connections.acquire(&config, peer.ip()) {
+                Some(permit) => {
+                    if sender.send((conn, peer, permit)).await.is_err() {
+                        break;
+                    }
+                }
+                None => {
+                    log!(LogLevel::Warn, "Refusing connection from {}, connection limit reached", peer);
+                    send_status_tcp(&mut conn, ProtocolStatus::REFUSED).await;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(last: u8) -> IpAddr {
+        IpAddr::from(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    fn config() -> BanConfig {
+        BanConfig {
+            enabled: true,
+            max_failures: 3,
+            window_seconds: 60,
+            ban_seconds: 600,
+        }
+    }
+
+    #[test]
+    fn bans_after_repeated_failures() {
+        let bans = BanList::default();
+        assert!(!bans.record_failure(&config(), peer(1)));
+        assert!(!bans.record_failure(&config(), peer(1)));
+        assert!(!bans.is_banned(peer(1)));
+        assert!(bans.record_failure(&config(), peer(1)));
+        assert!(bans.is_banned(peer(1)));
+        assert!(!bans.is_banned(peer(2)));
+    }
+
+    #[test]
+    fn lifts_expired_bans() {
+        let config = BanConfig {
+            max_failures: 1,
+            ban_seconds: 0,
+            ..config()
+        };
+        let bans = BanList::default();
+        assert!(bans.record_failure(&config, peer(1)));
+        assert!(!bans.is_banned(peer(1)));
+        assert!(bans.peers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_failures_outside_the_window() {
+        let config = BanConfig {
+            window_seconds: 0,
+            ..config()
+        };
+        let bans = BanList::default();
+        for _ in 0..5 {
+            assert!(!bans.record_failure(&config, peer(1)));
+        }
+        assert!(!bans.is_banned(peer(1)));
+    }
+
+    #[test]
+    fn does_nothing_when_disabled() {
+        let config = BanConfig {
+            enabled: false,
+            max_failures: 1,
+            ..config()
+        };
+        let bans = BanList::default();
+        assert!(!bans.record_failure(&config, peer(1)));
+        assert!(!bans.is_banned(peer(1)));
+    }
+
+    #[test]
+    fn caps_connections_per_peer_and_overall() {
+        let config = ConnectionConfig {
+            max_total: 3,
+            max_per_peer: 2,
+            ..ConnectionConfig::default()
+        };
+        let connections = Connections::default();
+        let first = connections.acquire(&config, peer(1)).unwrap();
+        let _second = connections.acquire(&config, peer(1)).unwrap();
+        assert!(connections.acquire(&config, peer(1)).is_none());
+
+        let _third = connections.acquire(&config, peer(2)).unwrap();
+        assert!(connections.acquire(&config, peer(3)).is_none());
+
+        // Dropping a permit frees its slot
+        drop(first);
+        assert!(connections.acquire(&config, peer(3)).is_some());
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{config::LocalizationConfig, payload::EmailPayload};
+
+// An address's own entry, then its domain's as `@example.com`, then `localization.default_locale`
+pub fn recipient_locale<'a>(config: &'a LocalizationConfig, address: &str) -> &'a str {
+    let lookup = |key: &str| {
+        config.recipients.iter().find(|(entry, _)| entry.eq_ignore_ascii_case(key)).map(|(_, locale)| locale)
+    };
+    lookup(address)
+        .or_else(|| address.rsplit_once('@').and_then(|(_, domain)| lookup(&format!("@{}", domain))))
+        .unwrap_or(&config.default_locale)
+}
+
+// The copy of a message that goes to `locale`'s recipients, with that locale's template variant and boilerplate
+pub fn localize(config: &LocalizationConfig, locale: &str, payload: &EmailPayload) -> EmailPayload {
+    let mut email = payload.clone();
+    if let Some(variant) = payload.localized.get(locale) {
+        if let Some(body) = &variant.body {
+            email.body = body.clone().into();
+        }
+        if variant.html.is_some() {
+            email.html = variant.html.clone();
+        }
+    }
+
+    let Some(boilerplate) = config.boilerplate.get(locale) else {
+        return email;
+    };
+
+    let wrap = |header: &Option<String>, body: &str, footer: &Option<String>| {
+        let mut wrapped = String::new();
+        if let Some(header) = header {
+            wrapped.push_str(&payload.render(header));
+            wrapped.push_str("\n\n");
+        }
+        wrapped.push_str(body);
+        if let Some(footer) = footer {
+            wrapped.push_str("\n\n");
+            wrapped.push_str(&payload.render(footer));
+        }
+        wrapped
+    };
+
+    email.body = wrap(&boilerplate.header, &email.body, &boilerplate.footer).into();
+    email.html = email
+        .html
+        .as_deref()
+        .map(|html| wrap(&boilerplate.html_header, html, &boilerplate.html_footer));
+    email
+}
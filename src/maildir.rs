@@ -0,0 +1,61 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use tokio::fs;
+
+static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+// Unique file name following the maildir `time.pid_count.host` convention
+fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let host = gethostname();
+
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        host.replace('/', "\\057").replace(':', "\\072")
+    )
+}
+
+pub fn gethostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| String::from("localhost"))
+}
+
+// Delivers a message into `new/` by way of `tmp/` so readers never see partial files
+pub async fn write_maildir(root: &str, email: &[u8]) -> Result<(), ErrorArrayItem> {
+    let root = Path::new(root);
+    for dir in ["tmp", "new", "cur"] {
+        fs::create_dir_all(root.join(dir)).await.map_err(|e| {
+            ErrorArrayItem::new(Errors::CreatingDirectory, format!("maildir: {}: {}", dir, e))
+        })?;
+    }
+
+    let name = unique_name();
+    let tmp = root.join("tmp").join(&name);
+    let new = root.join("new").join(&name);
+
+    fs::write(&tmp, email)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("maildir: {}", e)))?;
+    fs::rename(&tmp, &new)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("maildir: {}", e)))?;
+
+    log!(LogLevel::Debug, "Stored message in maildir as {}", new.display());
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::address::Envelope;
+use reqwest::multipart::{Form, Part};
+
+use crate::{
+    config::{MailgunConfig, MailgunRegion},
+    retry::SendError,
+};
+
+// Posts the rendered message to Mailgun's MIME endpoint so signatures and encryption survive
+pub async fn send_mailgun(config: &MailgunConfig, envelope: &Envelope, email: &[u8]) -> Result<(), SendError> {
+    let host = match config.region {
+        MailgunRegion::Us => "api.mailgun.net",
+        MailgunRegion::Eu => "api.eu.mailgun.net",
+    };
+
+    let recipients: Vec<String> = envelope.to().iter().map(|address| address.to_string()).collect();
+    let message = Part::bytes(email.to_vec())
+        .file_name("message.mime")
+        .mime_str("message/rfc822")
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("mailgun: {}", e)))?;
+
+    let form = Form::new()
+        .text("to", recipients.join(","))
+        .part("message", message);
+
+    let response = reqwest::Client::new()
+        .post(format!("https://{}/v3/{}/messages.mime", host, config.domain))
+        .basic_auth("api", Some(&config.api_key))
+        .multipart(form)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("mailgun: {}", e)))?;
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(SendError::http("mailgun", status, &body));
+    }
+
+    log!(LogLevel::Debug, "Mailgun accepted message: {}", body);
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use reqwest::Url;
+use serde_json::json;
+
+use crate::{config::MatrixConfig, payload::EmailPayload, retry::SendError};
+
+static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+
+// Transaction ids only need to be unique per access token
+fn transaction_id() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default();
+    format!("mailregulator-{}-{}", millis, TRANSACTIONS.fetch_add(1, Ordering::Relaxed))
+}
+
+// Sends the rendered template to the room as an m.notice so bots don't reply to it
+pub async fn post_matrix(config: &MatrixConfig, email: &EmailPayload) -> Result<(), SendError> {
+    let mut url = Url::parse(&config.homeserver)
+        .map_err(|e| ErrorArrayItem::new(Errors::ConfigParsing, format!("matrix: {}: {}", config.homeserver, e)))?;
+    url.path_segments_mut()
+        .map_err(|_| ErrorArrayItem::new(Errors::ConfigParsing, format!("matrix: {}: not a base url", config.homeserver)))?
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3", "rooms", &config.room_id, "send", "m.room.message", &transaction_id()]);
+
+    let request = json!({
+        "msgtype": "m.notice",
+        "body": email.render(&config.template),
+    });
+
+    let response = reqwest::Client::new()
+        .put(url)
+        .bearer_auth(&config.access_token)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("matrix: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("matrix", status, &body));
+    }
+
+    log!(LogLevel::Debug, "Matrix accepted message ({})", status);
+    Ok(())
+}
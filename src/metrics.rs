@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use serde::{Deserialize, Serialize};
+
+use crate::breaker::BreakerState;
+
+// How the last send to the relay went
+#[derive(Debug, Serialize, Clone)]
+pub struct RelayStatus {
+    pub ok: bool,
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// What became of each submission and send. Carried over restarts, so these count since the state file was created
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct Counters {
+    pub accepted: u64,
+    pub rejected_format: u64,
+    pub rejected_auth: u64,
+    pub sent: u64,
+    pub failed: u64,
+    pub expired: u64,
+}
+
+impl Counters {
+    // Failures that weren't the sender's fault, like a dropped connection, aren't rejections
+    pub fn reject(&mut self, error: &ErrorArrayItem) {
+        match error.err_type {
+            Errors::AuthenticationError => self.rejected_auth += 1,
+            Errors::InvalidType => self.rejected_format += 1,
+            _ => (),
+        }
+    }
+}
+
+// The part of a previous run's metrics worth keeping
+#[derive(Deserialize)]
+struct Saved {
+    #[serde(default)]
+    events: Counters,
+    #[serde(default)]
+    dead_lettered: u64,
+}
+
+// Published as JSON in `AppState.data` so the artisan manager shows the queue's health
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct Metrics {
+    pub queued: usize,
+    pub held: usize,
+    pub digest: usize,
+    pub oldest_seconds: Option<u64>,
+    pub events: Counters,
+    pub dead_lettered: u64,
+    pub relay: Option<RelayStatus>,
+    pub circuit: &'static str,
+    // The monitor's verdict, when it is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<String>,
+}
+
+impl Metrics {
+    // Picks the counters back up from the data a previous run saved, anything else starts fresh
+    pub fn restore(data: &str) -> Self {
+        match serde_json::from_str::<Saved>(data) {
+            Ok(saved) => Self {
+                events: saved.events,
+                dead_lettered: saved.dead_lettered,
+                ..Self::default()
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    // Only a send that reached the relay counts, a channel failing says nothing about it
+    pub fn record_relay(&mut self, error: Option<&ErrorArrayItem>) {
+        self.relay = Some(RelayStatus {
+            ok: error.is_none(),
+            at: Utc::now(),
+            error: error.map(|e| e.err_mesg.to_string()),
+        });
+    }
+
+    pub fn set_queue(&mut self, queued: usize, held: usize, digest: usize, oldest: Option<Duration>) {
+        self.queued = queued;
+        self.held = held;
+        self.digest = digest;
+        self.oldest_seconds = oldest.map(|oldest| oldest.as_secs());
+    }
+
+    pub fn set_circuit(&mut self, state: BreakerState) {
+        self.circuit = match state {
+            BreakerState::Closed => "closed",
+            BreakerState::Open(_) => "open",
+            BreakerState::HalfOpen => "half-open",
+        };
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
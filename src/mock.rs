@@ -0,0 +1,66 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    config::TransportKind,
+    retry::SendError,
+    transport::{MailTransport, Outgoing},
+};
+
+// One message handed to the mock
+#[derive(Debug, Clone)]
+pub struct MockSent {
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub formatted: Vec<u8>,
+}
+
+// Records sends in memory instead of delivering them, clones share the same record so a test keeps a handle
+// while `Dispatch::transport` carries another into the send path
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    sent: Arc<Mutex<Vec<MockSent>>>,
+    failures: Arc<Mutex<VecDeque<SendError>>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fails upcoming sends with these errors in order, later sends succeed again
+    pub fn fail_next(&self, error: SendError) {
+        self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push_back(error);
+    }
+
+    pub fn sent(&self) -> Vec<MockSent> {
+        self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+#[async_trait]
+impl MailTransport for MockTransport {
+    // Stands in for the relay
+    fn kind(&self) -> TransportKind {
+        TransportKind::Smtp
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        if let Some(error) = self.failures.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).pop_front() {
+            return Err(error);
+        }
+
+        self.sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(MockSent {
+            from: message.envelope.from().map(|address| address.to_string()),
+            to: message.envelope.to().iter().map(|address| address.to_string()).collect(),
+            subject: message.payload.subject.to_string(),
+            formatted: message.formatted.to_vec(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use dusa_collection_utils::{errors::ErrorArrayItem, log, log::LogLevel};
+
+use crate::{
+    channels::notify_channels,
+    config::{AppConfig, MonitorConfig},
+    payload::{EmailPayload, Priority},
+};
+
+// Describes what is wrong with the queue, if anything
+pub fn check_health(config: &MonitorConfig, depth: usize, oldest: Option<Duration>) -> Option<String> {
+    let mut problems: Vec<String> = Vec::new();
+
+    if depth > config.max_queue_depth {
+        problems.push(format!("queue depth {} over {}", depth, config.max_queue_depth));
+    }
+
+    if let Some(oldest) = oldest {
+        if oldest.as_secs() > config.max_age_seconds {
+            problems.push(format!("oldest message {}s over {}s", oldest.as_secs(), config.max_age_seconds));
+        }
+    }
+
+    match problems.is_empty() {
+        true => None,
+        false => Some(problems.join(", ")),
+    }
+}
+
+// Goes straight to the monitor channels since the queue itself is what's unhealthy
+pub async fn alert_health(config: &AppConfig, problem: &str) -> Result<(), ErrorArrayItem> {
+    log!(LogLevel::Warn, "Mail queue unhealthy: {}", problem);
+
+    let mut alert = EmailPayload::new(
+        "MailRegulator queue unhealthy".to_owned(),
+        format!("The mail queue needs attention: {}", problem),
+    );
+    alert.priority = Priority::High;
+    alert.channels = config.monitor.channels.clone();
+    alert.skip_email = true;
+
+    notify_channels(config, &mut alert).await.map_err(ErrorArrayItem::from)
+}
@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use hickory_resolver::{error::ResolveErrorKind, proto::op::ResponseCode, TokioAsyncResolver};
+use lettre::{
+    address::Envelope,
+    transport::smtp::client::{Tls, TlsParameters},
+    Address, AsyncSmtpTransport, AsyncTransport, Tokio1Executor,
+};
+
+use crate::{
+    config::SmtpConfig,
+    email::{send_via_relay, smtp_error},
+    retry::SendError,
+};
+
+const MX_PORT: u16 = 25;
+
+// Looks up the mail exchangers for a domain, most preferred first
+async fn resolve_mx(resolver: &TokioAsyncResolver, domain: &str) -> Result<Vec<String>, ErrorArrayItem> {
+    let lookup = match resolver.mx_lookup(domain).await {
+        Ok(lookup) => lookup,
+        // RFC 5321: a domain that exists without MX records is its own exchanger, reached through its A record
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { response_code: ResponseCode::NoError, .. }) => {
+            return Ok(vec![domain.to_owned()]);
+        }
+        Err(e) => return Err(ErrorArrayItem::new(Errors::Network, format!("mx: {}: {}", domain, e))),
+    };
+
+    let mut records: Vec<(u16, String)> = lookup
+        .iter()
+        .map(|mx| (mx.preference(), mx.exchange().to_utf8().trim_end_matches('.').to_owned()))
+        .collect();
+    records.sort();
+    Ok(records.into_iter().map(|(_, host)| host).collect())
+}
+
+async fn send_to_exchanger(config: &SmtpConfig, host: &str, envelope: &Envelope, email: &[u8]) -> Result<(), SendError> {
+    let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        .port(MX_PORT)
+        .timeout(Some(Duration::from_secs(30)));
+
+    // Exchangers rarely present certificates for their MX name, so TLS is opportunistic
+    if let Ok(tls) = TlsParameters::builder(host.to_owned())
+        .dangerous_accept_invalid_certs(true)
+        .build()
+    {
+        builder = builder.tls(Tls::Opportunistic(tls));
+    }
+
+    if let Some(ehlo_name) = &config.ehlo_name {
+        builder = builder.hello_name(lettre::transport::smtp::extension::ClientId::Domain(
+            ehlo_name.clone(),
+        ));
+    }
+
+    builder
+        .build()
+        .send_raw(envelope, email)
+        .await
+        .map(|_| ())
+        .map_err(smtp_error)
+}
+
+// Delivers straight to each recipient domain's MX hosts, using the relay for domains that fail. `send_email` hands
+// over one domain at a time, so the domains that accepted are never sent the message again when another one fails
+pub async fn deliver_mx(
+    config: &SmtpConfig,
+    access_token: Option<&str>,
+    envelope: &Envelope,
+    email: &[u8],
+) -> Result<(), SendError> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| ErrorArrayItem::new(Errors::Network, format!("mx: {}", e)))?;
+
+    let mut domains: BTreeMap<String, Vec<Address>> = BTreeMap::new();
+    for recipient in envelope.to() {
+        domains
+            .entry(recipient.domain().to_lowercase())
+            .or_default()
+            .push(recipient.clone());
+    }
+
+    let mut failed: Vec<Address> = Vec::new();
+    let mut last_error: Option<SendError> = None;
+
+    for (domain, recipients) in domains {
+        let domain_envelope = Envelope::new(envelope.from().cloned(), recipients.clone())
+            .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("mx: {}", e)))?;
+
+        let mut delivered = false;
+        match resolve_mx(&resolver, &domain).await {
+            Ok(hosts) => {
+                for host in hosts {
+                    match send_to_exchanger(config, &host, &domain_envelope, email).await {
+                        Ok(_) => {
+                            log!(LogLevel::Debug, "Delivered to {} via {}", domain, host);
+                            delivered = true;
+                            break;
+                        }
+                        Err(e) => {
+                            log!(LogLevel::Warn, "MX {} for {} failed: {}", host, domain, e);
+                            last_error = Some(e);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                log!(LogLevel::Warn, "{}", e);
+                last_error = Some(e.into());
+            }
+        }
+
+        if !delivered {
+            failed.extend(recipients);
+        }
+    }
+
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    if !config.mx_fallback_to_relay {
+        return Err(last_error.unwrap_or_else(|| {
+            ErrorArrayItem::new(Errors::GeneralError, "mx: delivery failed".to_owned()).into()
+        }));
+    }
+
+    log!(LogLevel::Info, "Falling back to the relay for {} recipients", failed.len());
+    let fallback = Envelope::new(envelope.from().cloned(), failed)
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("mx: {}", e)))?;
+    send_via_relay(config, access_token, &fallback, email).await
+}
@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde::Deserialize;
+
+use crate::config::OAuth2Settings;
+
+// Tokens are refreshed this long before the provider says they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+// Caches the client-credentials access token used for XOAUTH2
+#[derive(Debug, Default)]
+pub struct TokenCache {
+    token: Option<(String, Instant)>,
+}
+
+impl TokenCache {
+    pub fn clear(&mut self) {
+        self.token = None;
+    }
+
+    pub async fn access_token(&mut self, settings: &OAuth2Settings) -> Result<String, ErrorArrayItem> {
+        if let Some((token, expires_at)) = &self.token {
+            if Instant::now() + REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        log!(LogLevel::Debug, "Requesting new OAuth2 access token");
+        let token_url = settings.token_url.clone().unwrap_or_else(|| {
+            format!(
+                "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                settings.tenant_id
+            )
+        });
+
+        let response = reqwest::Client::new()
+            .post(&token_url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &settings.client_id),
+                ("client_secret", &settings.client_secret),
+                ("scope", &settings.scope),
+            ])
+            .send()
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::Network, format!("oauth2: {}", e)))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::Network, format!("oauth2: {}", e)))?;
+
+        if !status.is_success() {
+            return Err(ErrorArrayItem::new(
+                Errors::AuthenticationError,
+                format!("oauth2: token request failed with {}: {}", status, body),
+            ));
+        }
+
+        let token: TokenResponse = serde_json::from_str(&body).map_err(ErrorArrayItem::from)?;
+        self.token = Some((
+            token.access_token.clone(),
+            Instant::now() + Duration::from_secs(token.expires_in),
+        ));
+
+        Ok(token.access_token)
+    }
+}
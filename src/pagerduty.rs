@@ -0,0 +1,63 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    functions::create_hash,
+    log,
+    log::LogLevel,
+};
+use serde_json::json;
+
+use crate::{
+    config::PagerDutyConfig,
+    maildir::gethostname,
+    payload::{EmailPayload, Severity},
+    retry::SendError,
+};
+
+// The Events API truncates longer summaries
+const SUMMARY_LIMIT: usize = 1024;
+
+// Triggers an event for critical mail, repeats of the same message collapse into one incident
+pub async fn post_pagerduty(config: &PagerDutyConfig, email: &EmailPayload) -> Result<(), SendError> {
+    if email.severity != Some(Severity::Critical) && !email.is_critical() {
+        log!(LogLevel::Debug, "Skipping pagerduty for non-critical mail");
+        return Ok(());
+    }
+
+    let summary = email.render(&config.summary);
+    let summary = match summary.char_indices().nth(SUMMARY_LIMIT) {
+        Some((index, _)) => &summary[..index],
+        None => &summary,
+    };
+
+    let request = json!({
+        "routing_key": config.routing_key,
+        "event_action": "trigger",
+        "dedup_key": create_hash(format!("{}{}{}", email.subject, email.body, email.client.as_deref().unwrap_or_default())).to_string(),
+        "payload": {
+            "summary": summary,
+            "source": email.client.clone().unwrap_or_else(gethostname),
+            "severity": "critical",
+            "custom_details": {
+                "body": email.body.to_string(),
+                "priority": email.priority.to_string(),
+                "tags": email.tags,
+            },
+        },
+    });
+
+    let response = reqwest::Client::new()
+        .post(&config.events_url)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("pagerduty: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("pagerduty", status, &body));
+    }
+
+    log!(LogLevel::Debug, "PagerDuty accepted event ({})", status);
+    Ok(())
+}
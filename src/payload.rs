@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, fmt};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    functions::{create_hash, truncate},
+    stringy::Stringy,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::TransportKind;
+
+// Urgency requested by the submitting client, messages without one are treated as normal
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Critical,
+}
+
+// Severity of the event being reported, used to pick recipients
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+// A file attached to the message, content is base64 encoded in the payload
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    pub content: String,
+    // Shown inline where the HTML body references cid:<content_id>
+    #[serde(default)]
+    pub content_id: Option<String>,
+}
+
+// Asks the server to attach the unit's most recent journal lines
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JournalRequest {
+    pub unit: String,
+    #[serde(default = "default_journal_lines")]
+    pub lines: u32,
+}
+
+fn default_journal_lines() -> u32 {
+    50
+}
+
+fn default_content_type() -> String {
+    String::from("application/octet-stream")
+}
+
+impl Attachment {
+    pub fn decode(&self) -> Result<Vec<u8>, ErrorArrayItem> {
+        STANDARD.decode(&self.content).map_err(|e| {
+            ErrorArrayItem::new(Errors::InvalidType, format!("attachment {}: {}", self.filename, e))
+        })
+    }
+}
+
+// Inbound email data, a superset of the middleware `Email` so older clients keep working
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EmailPayload {
+    pub subject: Stringy,
+    pub body: Stringy,
+    // HTML alternative to the plain text body
+    #[serde(default)]
+    pub html: Option<String>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub severity: Option<Severity>,
+    #[serde(default)]
+    pub client: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // Kind of alert, like "backup" or "disk", that picks its body template
+    #[serde(default)]
+    pub category: Option<String>,
+    // Explicit recipients, normally filled in by routing rules
+    #[serde(default)]
+    pub to: Vec<String>,
+    // Backend override, normally filled in by routing rules
+    #[serde(default)]
+    pub transport: Option<TransportKind>,
+    // Envelope sender override, normally filled in by routing rules
+    #[serde(default)]
+    pub return_path: Option<String>,
+    // From address the client asks for, only honoured as far as `senders` allows it
+    #[serde(default)]
+    pub from: Option<String>,
+    // Where replies should go instead of the From address
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    // Addresses that already accepted the email, left out when a send that partly failed is retried
+    #[serde(default)]
+    pub delivered: Vec<String>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    // Collected into an attachment when the message is accepted
+    #[serde(default)]
+    pub attach_journal: Option<JournalRequest>,
+    // Extra headers added to the outgoing message
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    // Named channels that get a copy, normally filled in by routing rules
+    #[serde(default)]
+    pub channels: Vec<String>,
+    // Set when only the channels should be notified, or the email already went out
+    #[serde(default)]
+    pub skip_email: bool,
+    // Id from the submitting service, carried on the trace so an alert can be followed across services
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    // When the client submitted it, stamped on acceptance when the client didn't say
+    #[serde(default)]
+    pub submitted_at: Option<DateTime<Utc>>,
+    // Bodies rendered from a template's locale variants, picked per recipient when the message is sent
+    #[serde(default)]
+    pub localized: BTreeMap<String, Localized>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Localized {
+    pub body: Option<String>,
+    pub html: Option<String>,
+}
+
+impl EmailPayload {
+    // Builds a plain message generated by the server itself
+    pub fn new(subject: String, body: String) -> Self {
+        Self {
+            subject: Stringy::from(subject),
+            body: Stringy::from(body),
+            html: None,
+            priority: Priority::default(),
+            severity: None,
+            client: None,
+            tags: Vec::new(),
+            category: None,
+            to: Vec::new(),
+            transport: None,
+            return_path: None,
+            from: None,
+            reply_to: None,
+            delivered: Vec::new(),
+            attachments: Vec::new(),
+            attach_journal: None,
+            headers: BTreeMap::new(),
+            channels: Vec::new(),
+            skip_email: false,
+            correlation_id: None,
+            submitted_at: None,
+            localized: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_json(json_data: &str) -> Result<Self, ErrorArrayItem> {
+        serde_json::from_str(json_data).map_err(ErrorArrayItem::from)
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.priority == Priority::Critical
+    }
+
+    // Subject and body for log lines, reduced to hashes and lengths when `redact` is set
+    pub fn summary(&self, redact: bool) -> String {
+        match redact {
+            true => format!(
+                "subject {} ({} bytes), body {} ({} bytes)",
+                short_hash(&self.subject),
+                self.subject.len(),
+                short_hash(&self.body),
+                self.body.len()
+            ),
+            false => format!("\"{}\": {}", self.subject, self.body),
+        }
+    }
+
+    // Fills the {subject} {body} {client} {tags} {priority} {category} placeholders in a template
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{subject}", &self.subject)
+            .replace("{body}", &self.body)
+            .replace("{client}", self.client.as_deref().unwrap_or("unknown"))
+            .replace("{tags}", &self.tags.join(", "))
+            .replace("{priority}", &self.priority.to_string())
+            .replace("{category}", self.category.as_deref().unwrap_or("general"))
+    }
+}
+
+pub fn short_hash(text: &str) -> String {
+    truncate(&*create_hash(text.to_owned()), 10).to_string()
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::Critical => "critical",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+            Severity::Critical => "critical",
+        };
+        write!(f, "{}", name)
+    }
+}
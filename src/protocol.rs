@@ -0,0 +1,348 @@
+use std::{io::Read, time::Duration};
+
+use artisan_middleware::communication_proto::{
+    read_until, send_empty_ok, Flags, Proto, ProtocolHeader, ProtocolMessage, ProtocolStatus, EOL,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+    stringy::Stringy,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+
+use crate::{config::ProtocolConfig, payload::EmailPayload};
+
+// Payload flags carried in the header's reserved byte, the middleware leaves it alone on requests
+// The payload is base64 of a zstd frame, for large log bodies sent over slow links
+pub const PAYLOAD_COMPRESSED: u8 = 0x01;
+// The payload is base64 of the message as CBOR instead of JSON, cheaper to parse for busy submitters
+pub const PAYLOAD_CBOR: u8 = 0x02;
+// The payload is one `Chunk` of a submission too big for a single frame, the rest follow on the same connection
+pub const PAYLOAD_CHUNKED: u8 = 0x04;
+
+// A health check, answered with a `Health` payload and never queued
+pub const PAYLOAD_PING: u8 = 0x08;
+
+// Reply to a ping, enough for a client library to decide whether to rely on the mailer. A count is null and the
+// status "degraded" when its queue couldn't be read in time
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub queued: Option<usize>,
+    pub held: Option<usize>,
+    pub digest: Option<usize>,
+}
+
+pub async fn send_pong(conn: &mut TcpStream, health: &Health) -> Result<(), ErrorArrayItem> {
+    let payload = serde_json::to_string(health).map_err(ErrorArrayItem::from)?;
+    let mut response: ProtocolMessage<Stringy> =
+        ProtocolMessage::new(Flags::NONE, Stringy::from(payload)).map_err(ErrorArrayItem::from)?;
+    response.header.status = ProtocolStatus::OK.bits();
+    response.header.reserved = PAYLOAD_PING;
+
+    let response_bytes: Vec<u8> = response.to_bytes().await.map_err(ErrorArrayItem::from)?;
+    conn.write_all(&response_bytes).await.map_err(ErrorArrayItem::from)?;
+    conn.flush().await.map_err(ErrorArrayItem::from)
+}
+
+// One piece of a chunked submission, `data` pieces joined in `seq` order make up the full payload
+#[derive(Debug, Deserialize)]
+pub struct Chunk {
+    pub seq: u32,
+    pub total: u32,
+    pub data: String,
+}
+
+// Pieces received so far, chunks may arrive in any order
+#[derive(Debug, Default)]
+struct Reassembly {
+    parts: Vec<Option<String>>,
+    size: usize,
+}
+
+impl Reassembly {
+    // The full payload once the last missing piece arrives
+    fn add(&mut self, config: &ProtocolConfig, chunk: Chunk) -> Result<Option<String>, ErrorArrayItem> {
+        if chunk.total == 0 || chunk.total > config.max_chunks {
+            return Err(invalid("chunked", format!("{} chunks, at most {} allowed", chunk.total, config.max_chunks)));
+        }
+        if self.parts.is_empty() {
+            self.parts = vec![None; chunk.total as usize];
+        }
+        if chunk.total as usize != self.parts.len() || chunk.seq >= chunk.total {
+            return Err(invalid("chunked", format!("chunk {} of {} doesn't fit the stream", chunk.seq, chunk.total)));
+        }
+
+        self.size += chunk.data.len();
+        if self.size as u64 > config.max_message_kb * 1024 {
+            return Err(invalid("chunked", format!("over the {} KB limit", config.max_message_kb)));
+        }
+        self.parts[chunk.seq as usize] = Some(chunk.data);
+
+        match self.parts.iter().all(Option::is_some) {
+            true => Ok(Some(self.parts.iter_mut().filter_map(Option::take).collect())),
+            false => Ok(None),
+        }
+    }
+}
+
+// Replies with a bare status and no payload
+pub async fn send_status_tcp(conn: &mut TcpStream, status: ProtocolStatus) {
+    let mut response: ProtocolMessage<()> = match ProtocolMessage::new(Flags::NONE, ()) {
+        Ok(response) => response,
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to build error response: {}", e);
+            return;
+        }
+    };
+
+    response.header.status = status.bits();
+
+    match response.to_bytes().await {
+        Ok(response_bytes) => {
+            let _ = conn.write_all(&response_bytes).await;
+            let _ = conn.flush().await;
+        }
+        Err(e) => log!(LogLevel::Error, "Failed to encode error response: {}", e),
+    }
+}
+
+// Reads one EOL terminated frame off the connection
+pub async fn read_frame(conn: &mut TcpStream) -> Result<ProtocolMessage<Stringy>, ErrorArrayItem> {
+    let mut buffer: Vec<u8> = read_until(conn, EOL.as_bytes().to_vec())
+        .await
+        .map_err(ErrorArrayItem::from)?;
+
+    // Truncate the EOL from the buffer
+    if let Some(pos) = buffer
+        .windows(EOL.len())
+        .rposition(|window| window == EOL.as_bytes())
+    {
+        buffer.truncate(pos);
+    }
+
+    ProtocolMessage::<Stringy>::from_bytes(&buffer)
+        .await
+        .map_err(ErrorArrayItem::from)
+}
+
+// Collects the rest of a chunked submission, acknowledging each piece so the sender knows to send the next
+pub async fn reassemble(conn: &mut TcpStream, config: &ProtocolConfig, first: &str) -> Result<String, ErrorArrayItem> {
+    let mut reassembly = Reassembly::default();
+    let mut frame = first.to_owned();
+    let wait = Duration::from_secs(config.chunk_timeout_seconds);
+
+    loop {
+        let chunk: Chunk = serde_json::from_str(&frame).map_err(|e| invalid("chunked", e))?;
+        if let Some(payload) = reassembly.add(config, chunk)? {
+            log!(LogLevel::Debug, "Reassembled {} byte payload from {} chunks", payload.len(), reassembly.parts.len());
+            return Ok(payload);
+        }
+
+        send_empty_ok::<TcpStream>(conn, Proto::TCP).await.map_err(ErrorArrayItem::from)?;
+        let message = timeout(wait, read_frame(conn)).await.map_err(|_| {
+            ErrorArrayItem::new(Errors::ConnectionError, format!("chunked payload: no chunk within {}s", config.chunk_timeout_seconds))
+        })??;
+        frame = message.payload.to_string();
+    }
+}
+
+// Header flag sets sent by middleware releases from before OPTIMIZED was required
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LegacyFormat {
+    Plain,
+    Compressed,
+    Encoded,
+    CompressedEncoded,
+}
+
+impl LegacyFormat {
+    pub const ALL: [LegacyFormat; 4] = [
+        LegacyFormat::Plain,
+        LegacyFormat::Compressed,
+        LegacyFormat::Encoded,
+        LegacyFormat::CompressedEncoded,
+    ];
+
+    fn flags(self) -> u8 {
+        match self {
+            LegacyFormat::Plain => Flags::NONE.bits(),
+            LegacyFormat::Compressed => Flags::COMPRESSED.bits(),
+            LegacyFormat::Encoded => Flags::ENCODED.bits(),
+            LegacyFormat::CompressedEncoded => Flags::COMPRESSED.bits() | Flags::ENCODED.bits(),
+        }
+    }
+}
+
+// What the header says about how to read the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Negotiated {
+    Current,
+    Legacy(LegacyFormat),
+    // Unknown or disabled format, the sender is asked to resend as OPTIMIZED
+    Upgrade,
+}
+
+pub fn negotiate(accepted: &[LegacyFormat], header: &ProtocolHeader) -> Negotiated {
+    if header.flags == Flags::OPTIMIZED.bits() {
+        return Negotiated::Current;
+    }
+
+    match accepted.iter().find(|format| format.flags() == header.flags) {
+        Some(format) => {
+            log!(
+                LogLevel::Info,
+                "Accepting {:?} message from protocol version {}, sender should upgrade to OPTIMIZED",
+                format,
+                header.version
+            );
+            Negotiated::Legacy(*format)
+        }
+        None => Negotiated::Upgrade,
+    }
+}
+
+fn invalid(stage: &str, error: impl std::fmt::Display) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::InvalidType, format!("{} payload: {}", stage, error))
+}
+
+fn decompress(config: &ProtocolConfig, compressed: &[u8]) -> Result<Vec<u8>, ErrorArrayItem> {
+    let decoder = zstd::stream::read::Decoder::new(compressed).map_err(|e| invalid("compressed", e))?;
+
+    // Read one byte past the limit so an oversized payload is caught without inflating all of it
+    let limit = config.max_decompressed_kb * 1024;
+    let mut data = Vec::new();
+    decoder
+        .take(limit + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| invalid("compressed", e))?;
+    if data.len() as u64 > limit {
+        return Err(invalid(
+            "compressed",
+            format!("over the {} KB limit once decompressed", config.max_decompressed_kb),
+        ));
+    }
+
+    log!(LogLevel::Debug, "Decompressed payload from {} to {} bytes", compressed.len(), data.len());
+    Ok(data)
+}
+
+// Undoes the payload flags and parses the submission, JSON unless the sender asked for CBOR
+pub fn read_payload(
+    config: &ProtocolConfig,
+    header: &ProtocolHeader,
+    negotiated: Negotiated,
+    payload: &str,
+) -> Result<EmailPayload, ErrorArrayItem> {
+    let flags = header.reserved;
+    if flags & (PAYLOAD_COMPRESSED | PAYLOAD_CBOR) == 0 {
+        return match negotiated {
+            Negotiated::Legacy(_) => upconvert(payload),
+            _ => EmailPayload::from_json(payload),
+        };
+    }
+
+    let mut data = STANDARD.decode(payload.trim()).map_err(|e| invalid("binary", e))?;
+    if flags & PAYLOAD_COMPRESSED != 0 {
+        data = decompress(config, &data)?;
+    }
+
+    if flags & PAYLOAD_CBOR != 0 {
+        return ciborium::from_reader(data.as_slice()).map_err(|e| invalid("cbor", e));
+    }
+
+    let text = String::from_utf8(data).map_err(|e| invalid("compressed", e))?;
+    match negotiated {
+        Negotiated::Legacy(_) => upconvert(&text),
+        _ => EmailPayload::from_json(&text),
+    }
+}
+
+// Older clients sent either the middleware `Email` JSON, which the payload already covers, or bare text
+// with the subject on the first line
+pub fn upconvert(payload: &str) -> Result<EmailPayload, ErrorArrayItem> {
+    if let Ok(email) = EmailPayload::from_json(payload) {
+        return Ok(email);
+    }
+
+    let text = payload.trim();
+    if text.starts_with('{') {
+        return EmailPayload::from_json(text);
+    }
+
+    let (subject, body) = text.split_once('\n').unwrap_or((text, ""));
+    Ok(EmailPayload::new(subject.trim().to_owned(), body.trim().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn chunk(seq: u32, total: u32, data: &str) -> Chunk {
+        Chunk {
+            seq,
+            total,
+            data: data.to_owned(),
+        }
+    }
+
+    fn compressed(data: &[u8]) -> Vec<u8> {
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 0).unwrap();
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn reassembles_chunks_in_any_order() {
+        let config = ProtocolConfig::default();
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.add(&config, chunk(2, 3, "c")).unwrap().is_none());
+        assert!(reassembly.add(&config, chunk(0, 3, "a")).unwrap().is_none());
+        assert_eq!(reassembly.add(&config, chunk(1, 3, "b")).unwrap().as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn refuses_chunks_past_the_caps() {
+        let config = ProtocolConfig {
+            max_chunks: 4,
+            max_message_kb: 1,
+            ..ProtocolConfig::default()
+        };
+        assert!(Reassembly::default().add(&config, chunk(0, 5, "a")).is_err());
+        assert!(Reassembly::default().add(&config, chunk(0, 0, "a")).is_err());
+
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.add(&config, chunk(0, 2, &"x".repeat(600))).is_ok());
+        assert!(reassembly.add(&config, chunk(1, 2, &"x".repeat(600))).unwrap_err().err_mesg.contains("1 KB"));
+    }
+
+    #[test]
+    fn refuses_chunks_that_dont_fit_the_stream() {
+        let config = ProtocolConfig::default();
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.add(&config, chunk(0, 3, "a")).is_ok());
+        assert!(reassembly.add(&config, chunk(1, 4, "b")).is_err());
+        assert!(reassembly.add(&config, chunk(3, 3, "b")).is_err());
+    }
+
+    #[test]
+    fn decompresses_up_to_the_limit() {
+        let config = ProtocolConfig {
+            max_decompressed_kb: 1,
+            ..ProtocolConfig::default()
+        };
+        assert_eq!(decompress(&config, &compressed(&[b'a'; 1024])).unwrap().len(), 1024);
+
+        // A small frame that inflates past the limit is caught
+        let bomb = compressed(&[b'a'; 1025]);
+        assert!(decompress(&config, &bomb).unwrap_err().err_mesg.contains("1 KB limit"));
+        assert!(decompress(&config, b"not zstd").is_err());
+    }
+}
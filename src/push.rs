@@ -0,0 +1,79 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use reqwest::Response;
+use serde_json::json;
+
+use crate::{
+    config::{GotifyConfig, NtfyConfig},
+    payload::{EmailPayload, Priority},
+    retry::SendError,
+};
+
+async fn check(service: &str, response: Response) -> Result<(), SendError> {
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http(service, status, &body));
+    }
+
+    log!(LogLevel::Debug, "{} accepted message ({})", service, status);
+    Ok(())
+}
+
+// Publishes to a topic through ntfy's JSON endpoint, which unlike headers allows UTF-8 titles
+pub async fn post_ntfy(config: &NtfyConfig, email: &EmailPayload) -> Result<(), SendError> {
+    // ntfy priorities run 1 (min) to 5 (max)
+    let priority = match email.priority {
+        Priority::Low => 2,
+        Priority::Normal => 3,
+        Priority::High => 4,
+        Priority::Critical => 5,
+    };
+
+    let request = json!({
+        "topic": config.topic,
+        "title": email.subject.to_string(),
+        "message": email.render(&config.template),
+        "priority": priority,
+        "tags": email.tags,
+    });
+
+    let mut builder = reqwest::Client::new().post(&config.server).json(&request);
+    if let Some(token) = &config.token {
+        builder = builder.bearer_auth(token);
+    }
+
+    let response = builder
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("ntfy: {}", e)))?;
+    check("ntfy", response).await
+}
+
+pub async fn post_gotify(config: &GotifyConfig, email: &EmailPayload) -> Result<(), SendError> {
+    // Gotify priorities run 0 to 10, clients usually only alert from 4 up
+    let priority = match email.priority {
+        Priority::Low => 2,
+        Priority::Normal => 5,
+        Priority::High => 7,
+        Priority::Critical => 10,
+    };
+
+    let request = json!({
+        "title": email.subject.to_string(),
+        "message": email.render(&config.template),
+        "priority": priority,
+    });
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/message", config.server.trim_end_matches('/')))
+        .header("X-Gotify-Key", &config.app_token)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("gotify: {}", e)))?;
+    check("gotify", response).await
+}
@@ -0,0 +1,439 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use dusa_collection_utils::{errors::ErrorArrayItem, log, log::LogLevel, rwarc::LockWithTimeout};
+use lettre::message::Mailbox;
+use tokio::{fs, time::sleep_until};
+use tracing::{field, info_span, Instrument, Span};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditLog, Outcome},
+    channels::notify_channels,
+    config::{AppConfig, RetryConfig},
+    deadletter::{dead_letter, load_dead_letters, DeadLetter, Selection},
+    email::{send_email, Keyring},
+    maildir::gethostname,
+    oauth::TokenCache,
+    payload::EmailPayload,
+    retry::{backoff, exhausted, Failure, SendError},
+    routing::resolve_recipients,
+    suppression::SuppressionList,
+    telemetry::message_span,
+    transport::MailTransport,
+    twilio::send_sms,
+};
+
+// A message waiting in the queue along with its delivery history
+#[derive(Debug, Clone)]
+pub struct TimedEmail {
+    pub email: EmailPayload,
+    pub received_at: Instant,
+    // Failed email delivery attempts
+    pub attempts: u32,
+    // Held back until then after a transient failure
+    pub next_attempt: Instant,
+    // Set on escalated messages and operator notices so they don't escalate again
+    pub escalated: bool,
+    // Lifecycle trace span, ends when the message leaves the queue
+    pub span: Span,
+    // Identifies the message in the audit log
+    pub id: String,
+}
+
+impl TimedEmail {
+    pub fn new(email: EmailPayload) -> Self {
+        let span = message_span(&email);
+        Self {
+            email,
+            received_at: Instant::now(),
+            attempts: 0,
+            next_attempt: Instant::now(),
+            escalated: false,
+            span,
+            id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    // Operator notices and error digests, which must never escalate themselves
+    pub fn notice(email: EmailPayload) -> Self {
+        Self {
+            escalated: true,
+            ..Self::new(email)
+        }
+    }
+
+    // Outlived the retry policy, it is discarded rather than sent
+    pub fn expired(&self, config: &RetryConfig, now: Instant) -> bool {
+        now.duration_since(self.received_at) > Duration::from_secs(config.max_lifetime_seconds)
+    }
+}
+
+pub fn record_audit(audit: &AuditLog, app_config: &AppConfig, timed: &TimedEmail, outcome: Outcome, error: Option<&str>) {
+    let recipients = resolve_recipients(app_config, &timed.email);
+    audit.record(&timed.id, &timed.email, &recipients, outcome, error);
+}
+
+// Takes a permanently refused message out of circulation, keeping it on disk for inspection
+pub async fn dead_letter_queued(app_config: &AppConfig, audit: &AuditLog, timed: TimedEmail, error: &ErrorArrayItem) {
+    record_audit(audit, app_config, &timed, Outcome::DeadLettered, Some(&error.err_mesg));
+    let letter = DeadLetter {
+        id: timed.id,
+        failed_at: Utc::now(),
+        attempts: timed.attempts,
+        error: error.err_mesg.to_string(),
+        email: timed.email,
+    };
+
+    if let Err(e) = dead_letter(&app_config.app.dead_letter_path, &letter).await {
+        log!(LogLevel::Error, "Failed to dead-letter message {}: {}", letter.id, e);
+    }
+}
+
+// Puts the selected dead letters back in the queue under their old ids, once the relay problem behind them is fixed.
+// Each file is removed only after its message is queued
+pub async fn requeue_dead_letters(
+    app_config: &AppConfig,
+    audit: &AuditLog,
+    emails: &LockWithTimeout<Vec<TimedEmail>>,
+    selection: &Selection,
+) -> Result<Vec<String>, ErrorArrayItem> {
+    let letters = load_dead_letters(&app_config.app.dead_letter_path).await?;
+    let mut queue = emails.try_write_with_timeout(None).await?;
+    let mut requeued = Vec::new();
+
+    for (path, letter) in letters.into_iter().filter(|(_, letter)| selection.matches(letter)) {
+        let mut timed = TimedEmail::new(letter.email);
+        timed.id = letter.id.clone();
+        record_audit(audit, app_config, &timed, Outcome::Requeued, None);
+        queue.push(timed);
+
+        if let Err(e) = fs::remove_file(&path).await {
+            log!(LogLevel::Error, "Requeued {} but couldn't remove {}: {}", letter.id, path.display(), e);
+        }
+        requeued.push(letter.id);
+    }
+
+    log!(LogLevel::Info, "Requeued {} dead-lettered messages", requeued.len());
+    Ok(requeued)
+}
+
+// Clears the backoff on a queued message, or moves a held one into the queue ahead of quiet hours ending
+pub async fn retry_message(
+    emails: &LockWithTimeout<Vec<TimedEmail>>,
+    held: &LockWithTimeout<Vec<TimedEmail>>,
+    id: &str,
+) -> Result<bool, ErrorArrayItem> {
+    let mut queue = emails.try_write().await?;
+    if let Some(timed) = queue.iter_mut().find(|timed| timed.id == id) {
+        timed.next_attempt = Instant::now();
+        return Ok(true);
+    }
+
+    let mut held = held.try_write().await?;
+    match held.iter().position(|timed| timed.id == id) {
+        Some(index) => {
+            let mut timed = held.remove(index);
+            timed.next_attempt = Instant::now();
+            queue.push(timed);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Takes a message out of the queue or the held list for good
+pub async fn purge_message(
+    emails: &LockWithTimeout<Vec<TimedEmail>>,
+    held: &LockWithTimeout<Vec<TimedEmail>>,
+    id: &str,
+) -> Result<Option<TimedEmail>, ErrorArrayItem> {
+    for list in [emails, held] {
+        let mut list = list.try_write().await?;
+        if let Some(index) = list.iter().position(|timed| timed.id == id) {
+            return Ok(Some(list.remove(index)));
+        }
+    }
+    Ok(None)
+}
+
+// How each leg of a send went, kept apart so a channel's failure is never taken for the email's
+#[derive(Debug)]
+pub struct Delivery {
+    pub email: Result<(), SendError>,
+    pub channels: Result<(), SendError>,
+    // Every recipient was suppressed by the time it was sent, so no email went out
+    pub suppressed: bool,
+}
+
+impl Delivery {
+    // How a send that went through is audited
+    pub fn outcome(&self) -> Outcome {
+        match self.suppressed {
+            true => Outcome::Suppressed,
+            false => Outcome::Delivered,
+        }
+    }
+
+    // The failure to report, the email's when it has one
+    pub fn error(&self) -> Option<&SendError> {
+        self.email.as_ref().err().or(self.channels.as_ref().err())
+    }
+
+    // The longest wait either leg was told to leave before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        [&self.email, &self.channels].into_iter().filter_map(|leg| leg.as_ref().err()?.retry_after).max()
+    }
+
+    // A delivered or channel-only message whose remaining channels refused for good, there's nothing to retry
+    // and nothing to dead-letter
+    pub fn channels_refused(&self) -> bool {
+        self.email.is_ok() && self.channels.as_ref().is_err_and(|e| e.failure == Failure::Permanent)
+    }
+}
+
+// Whether an earlier attempt already got the email to `recipient`
+fn already_delivered(email: &EmailPayload, recipient: &str) -> bool {
+    match recipient.parse::<Mailbox>() {
+        Ok(mailbox) => email.delivered.contains(&mailbox.email.to_string().to_lowercase()),
+        Err(_) => false,
+    }
+}
+
+// Notifies the payload's channels and sends the email, marking the email done so a retry only repeats what failed
+pub async fn deliver_queued(
+    app_config: &AppConfig,
+    keyring: &Keyring,
+    suppressions: &SuppressionList,
+    access_token: Option<&str>,
+    transport: Option<&dyn MailTransport>,
+    timed: &mut TimedEmail,
+) -> Delivery {
+    let span = info_span!(
+        parent: &timed.span,
+        "send",
+        attempt = timed.attempts + 1,
+        queued_ms = timed.received_at.elapsed().as_millis() as u64,
+        otel.status_code = field::Empty,
+        error = field::Empty,
+    );
+    let email = &mut timed.email;
+
+    // Bounces quote the Message-ID back, so it carries our id for correlation
+    email
+        .headers
+        .entry("Message-ID".to_owned())
+        .or_insert_with(|| format!("<{}@{}>", timed.id, gethostname()));
+
+    let delivery = async {
+        let channels = notify_channels(app_config, email).await;
+
+        // Addresses suppressed since the message was accepted can leave nobody to send to
+        let mut suppressed = false;
+        let sent = match email.skip_email {
+            true => Ok(()),
+            false => {
+                let recipients = resolve_recipients(app_config, email);
+                let mut allowed = suppressions.filter(recipients.clone());
+                suppressed = !recipients.is_empty() && allowed.is_empty();
+                let pending = allowed.len();
+                allowed.retain(|recipient| !already_delivered(email, recipient));
+
+                let mut delivered = Vec::new();
+                let sent = match suppressed || (pending > 0 && allowed.is_empty()) {
+                    true => Ok(()),
+                    false => send_email(app_config, keyring, access_token, transport, email, &allowed, &mut delivered).await,
+                };
+                email.delivered.extend(delivered);
+                sent
+            }
+        };
+
+        if sent.is_ok() {
+            email.skip_email = true;
+        }
+
+        Delivery { email: sent, channels, suppressed }
+    }
+    .instrument(span.clone())
+    .await;
+
+    if let Some(e) = delivery.error() {
+        span.record("otel.status_code", "ERROR");
+        span.record("error", e.error.err_mesg.to_string());
+    }
+    delivery
+}
+
+// Sends what is due before `until`, ignoring the rate limit. Failures back off as they would in a round and expired
+// mail is discarded, whatever is left is the caller's to spool
+pub async fn drain_queue(
+    app_config: &AppConfig,
+    keyring: &Keyring,
+    audit: &AuditLog,
+    suppressions: &SuppressionList,
+    oauth_tokens: &mut TokenCache,
+    queue: &mut Vec<TimedEmail>,
+    until: Instant,
+) {
+    let access_token = match &app_config.smtp.oauth2 {
+        Some(settings) => match oauth_tokens.access_token(settings).await {
+            Ok(token) => Some(token),
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to obtain OAuth2 token, spooling the queue: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    loop {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < queue.len() {
+            if queue[i].expired(&app_config.retry, now) {
+                let expired = queue.remove(i);
+                log!(LogLevel::Info, "Expired email discarding: {}", expired.email.summary(app_config.app.redact_logs));
+                record_audit(audit, app_config, &expired, Outcome::Expired, None);
+                if let Some(twilio) = &app_config.twilio {
+                    if expired.email.is_critical() && !expired.email.skip_email {
+                        if let Err(e) = send_sms(twilio, &expired.email).await {
+                            log!(LogLevel::Error, "Failed to text an expired critical email: {}", e);
+                        }
+                    }
+                }
+                continue;
+            }
+            if queue[i].next_attempt > now {
+                i += 1;
+                continue;
+            }
+
+            let delivery = deliver_queued(app_config, keyring, suppressions, access_token.as_deref(), None, &mut queue[i]).await;
+            match (&delivery.email, delivery.error()) {
+                (_, None) => {
+                    record_audit(audit, app_config, &queue.remove(i), delivery.outcome(), None);
+                }
+                (Err(e), _) if e.failure == Failure::Permanent => {
+                    log!(LogLevel::Warn, "Refused while draining: {}", e);
+                    dead_letter_queued(app_config, audit, queue.remove(i), &e.error).await;
+                }
+                (_, Some(e)) if delivery.channels_refused() => {
+                    log!(LogLevel::Warn, "Dropping channels that refused while draining: {}", e);
+                    record_audit(audit, app_config, &queue.remove(i), Outcome::Failed, Some(&e.error.err_mesg));
+                }
+                (_, Some(e)) => {
+                    log!(LogLevel::Warn, "Failed to send while draining: {}", e);
+                    let timed = &mut queue[i];
+                    timed.attempts += 1;
+                    match &delivery.email {
+                        Err(e) if exhausted(&app_config.retry, timed.attempts) => {
+                            log!(LogLevel::Warn, "Giving up after {} attempts", timed.attempts);
+                            dead_letter_queued(app_config, audit, queue.remove(i), &e.error).await;
+                        }
+                        _ => {
+                            let wait = delivery.retry_after().unwrap_or_else(|| backoff(&app_config.retry, timed.attempts));
+                            timed.next_attempt = Instant::now() + wait;
+                            i += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Wait for the next message due before the deadline, anything due later is spooled as it is
+        match queue.iter().map(|timed| timed.next_attempt).filter(|due| *due < until).min() {
+            Some(due) => sleep_until(due.into()).await,
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::smtp_sink::SmtpSink;
+
+    // Sends to a sink on localhost, `extra` is appended for the section under test
+    async fn relay(extra: &str) -> (AppConfig, SmtpSink) {
+        let sink = SmtpSink::new();
+        let bound = sink.start(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.unwrap();
+        let toml = format!(
+            "[smtp]\nserver = \"127.0.0.1\"\nport = {}\nsecurity = \"none\"\nto = \"ops@example.com\"\n\
+             from = \"regulator@example.com\"\n\n[app]\nloop_interval_seconds = 1\nredact_logs = false\n\n{}",
+            bound.port(),
+            extra
+        );
+        (toml::from_str(&toml).unwrap(), sink)
+    }
+
+    async fn drain(config: &AppConfig, queue: &mut Vec<TimedEmail>, until: Instant) {
+        let audit = AuditLog::new(&config.audit);
+        let suppressions = SuppressionList::default();
+        let mut tokens = TokenCache::default();
+        drain_queue(config, &Keyring::default(), &audit, &suppressions, &mut tokens, queue, until).await;
+    }
+
+    fn message(subject: &str) -> TimedEmail {
+        let mut email = EmailPayload::new(subject.to_owned(), String::from("body"));
+        email.to = vec![String::from("a@example.com")];
+        TimedEmail::new(email)
+    }
+
+    #[tokio::test]
+    async fn drains_what_is_due() {
+        let (config, sink) = relay("").await;
+        let mut queue = vec![message("one"), message("two")];
+
+        drain(&config, &mut queue, Instant::now() + Duration::from_secs(5)).await;
+
+        assert!(queue.is_empty());
+        assert_eq!(sink.captured().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn leaves_mail_due_after_the_deadline_for_the_spool() {
+        let (config, sink) = relay("").await;
+        let mut backing_off = message("later");
+        backing_off.next_attempt = Instant::now() + Duration::from_secs(60);
+        let mut queue = vec![backing_off, message("now")];
+
+        let started = Instant::now();
+        drain(&config, &mut queue, Instant::now() + Duration::from_secs(5)).await;
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].email.subject.to_string(), "later");
+        assert_eq!(sink.captured().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backs_off_failures_while_draining() {
+        let (config, sink) = relay("[retry]\ninitial_delay_seconds = 30").await;
+        sink.reply_to_next_data("451 4.3.0 try again later");
+        let mut queue = vec![message("deferred")];
+
+        // The retry falls after the deadline, so the one attempt is all it gets
+        drain(&config, &mut queue, Instant::now() + Duration::from_secs(5)).await;
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].attempts, 1);
+        assert!(queue[0].next_attempt > Instant::now() + Duration::from_secs(20));
+        assert!(sink.captured().is_empty());
+    }
+
+    #[tokio::test]
+    async fn discards_expired_mail_while_draining() {
+        let (config, sink) = relay("[retry]\nmax_lifetime_seconds = 60").await;
+        let mut stale = message("stale");
+        stale.received_at = Instant::now() - Duration::from_secs(120);
+        let mut queue = vec![stale];
+
+        drain(&config, &mut queue, Instant::now() + Duration::from_secs(5)).await;
+
+        assert!(queue.is_empty());
+        assert!(sink.captured().is_empty());
+    }
+}
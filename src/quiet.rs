@@ -0,0 +1,23 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime};
+
+use crate::config::{QuietHoursConfig, QuietWindow};
+
+// Whether non-critical mail should currently be held back
+pub fn is_quiet(config: &QuietHoursConfig) -> bool {
+    config.enabled && config.windows.iter().any(|window| window_contains(window, Local::now()))
+}
+
+fn window_contains(window: &QuietWindow, now: DateTime<Local>) -> bool {
+    let time: NaiveTime = now.time();
+
+    // Windows that cross midnight belong to the day they started on
+    let (inside, day) = if window.start <= window.end {
+        (time >= window.start && time < window.end, now.weekday())
+    } else if time >= window.start {
+        (true, now.weekday())
+    } else {
+        (time < window.end, now.weekday().pred())
+    };
+
+    inside && (window.days.is_empty() || window.days.contains(&day))
+}
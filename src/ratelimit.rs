@@ -0,0 +1,70 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::config::RecipientRateLimit;
+
+// Refills continuously at `per_minute` up to `burst`, so the send rate doesn't depend on the loop interval
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    // Starts full so mail queued before startup goes out straight away
+    pub fn new(burst: u32) -> Self {
+        Self {
+            tokens: burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, per_minute: u32, burst: u32) {
+        let rate = per_minute as f64 / 60.0;
+        self.tokens = (self.tokens + self.refilled.elapsed().as_secs_f64() * rate).min(burst as f64);
+        self.refilled = Instant::now();
+    }
+
+    // Whole sends that can be made right now
+    pub fn available(&mut self, per_minute: u32, burst: u32) -> usize {
+        self.refill(per_minute, burst);
+        self.tokens.floor() as usize
+    }
+
+    pub fn take(&mut self, count: usize) {
+        self.tokens = (self.tokens - count as f64).max(0.0);
+    }
+}
+
+// A bucket per destination address, so one busy recipient can't use up the shared allowance
+#[derive(Debug, Default)]
+pub struct RecipientThrottle {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RecipientThrottle {
+    // Whether every recipient has a token left, without taking any
+    pub fn ready(&mut self, config: &RecipientRateLimit, recipients: &[String]) -> bool {
+        recipients.iter().all(|recipient| {
+            self.buckets
+                .entry(recipient.to_lowercase())
+                .or_insert_with(|| TokenBucket::new(config.burst))
+                .available(config.per_minute, config.burst)
+                >= 1
+        })
+    }
+
+    // Takes a token for every recipient, once the message is certain to be attempted
+    pub fn take(&mut self, recipients: &[String]) {
+        for recipient in recipients {
+            if let Some(bucket) = self.buckets.get_mut(&recipient.to_lowercase()) {
+                bucket.take(1);
+            }
+        }
+    }
+
+    // Forgets recipients whose bucket has filled back up, they'd start full anyway
+    pub fn prune(&mut self, config: &RecipientRateLimit) {
+        self.buckets
+            .retain(|_, bucket| bucket.available(config.per_minute, config.burst) < config.burst as usize);
+    }
+}
@@ -0,0 +1,358 @@
+use std::{net::SocketAddr, sync::Mutex, time::Duration};
+
+use artisan_middleware::communication_proto::{
+    send_empty_ok, Flags, Proto, ProtocolHeader, ProtocolMessage, ProtocolStatus,
+};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+    rwarc::LockWithTimeout,
+};
+use chrono::Utc;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    attachments::enforce_limits,
+    audit::{AuditLog, Outcome},
+    auth::{verify, NonceCache},
+    config::{AppConfig, ProtocolConfig},
+    digest::Digest,
+    filters::{run_filters, DedupCache, Verdict},
+    headers::check_reserved,
+    journal::journal_excerpt,
+    limits::ConnectionPermit,
+    payload::{short_hash, EmailPayload},
+    protocol::{
+        negotiate, read_frame, read_payload, reassemble, send_pong, send_status_tcp, Health, Negotiated,
+        PAYLOAD_CHUNKED, PAYLOAD_PING,
+    },
+    queue::{record_audit, TimedEmail},
+    quiet::is_quiet,
+    routing::{apply_rules, resolve_recipients},
+    script::Script,
+    senders::{check_addresses, enforce_sender},
+    suppression::SuppressionList,
+};
+
+// Everything a submission may be checked against or filed into, borrowed from the main loop
+pub struct Intake<'a> {
+    pub app_config: &'a AppConfig,
+    pub audit: &'a AuditLog,
+    pub suppressions: &'a SuppressionList,
+    pub emails: &'a LockWithTimeout<Vec<TimedEmail>>,
+    pub held: &'a LockWithTimeout<Vec<TimedEmail>>,
+    pub digest: &'a LockWithTimeout<Digest>,
+    pub dedup: &'a DedupCache,
+    pub script: Option<&'a Script>,
+    // Replay protection for signed submissions, shared by every connection
+    pub nonces: &'a Mutex<NonceCache>,
+}
+
+impl<'a> Intake<'a> {
+    // The settings are the ones in force right now, a reload swaps them between submissions
+    pub fn new(
+        app_config: &'a AppConfig,
+        audit: &'a AuditLog,
+        suppressions: &'a SuppressionList,
+        script: Option<&'a Script>,
+        shared: &'a Shared,
+    ) -> Self {
+        Self {
+            app_config,
+            audit,
+            suppressions,
+            emails: &shared.emails,
+            held: &shared.held,
+            digest: &shared.digest,
+            dedup: &shared.dedup,
+            script,
+            nonces: &shared.nonces,
+        }
+    }
+}
+
+// The queues and caches submissions are filed into, kept for the life of the process across reloads
+pub struct Shared {
+    pub emails: LockWithTimeout<Vec<TimedEmail>>,
+    // Mail waiting out quiet hours
+    pub held: LockWithTimeout<Vec<TimedEmail>>,
+    pub digest: LockWithTimeout<Digest>,
+    pub dedup: DedupCache,
+    pub nonces: Mutex<NonceCache>,
+}
+
+impl Shared {
+    // Starts with whatever a previous run spooled
+    pub fn new(queued: Vec<TimedEmail>) -> Self {
+        Self {
+            emails: LockWithTimeout::new(queued),
+            held: LockWithTimeout::new(Vec::new()),
+            digest: LockWithTimeout::new(Digest::new()),
+            dedup: DedupCache::default(),
+            nonces: Mutex::new(NonceCache::default()),
+        }
+    }
+}
+
+// A frame read off a connection by its own task, handed to the main loop to be answered
+pub enum Received {
+    // Answered with the queue sizes
+    Ping(oneshot::Sender<Health>),
+    // Still to be verified and parsed, answered with what became of it
+    Payload {
+        header: ProtocolHeader,
+        negotiated: Negotiated,
+        payload: String,
+        peer: SocketAddr,
+        reply: oneshot::Sender<Result<Filed, ErrorArrayItem>>,
+    },
+    // The read failed or timed out, the client has already been sent an error
+    Failed(SocketAddr, ErrorArrayItem),
+}
+
+// Reads the connection on a task of its own so an idle or slow client only ever holds up itself. The whole
+// submission has to arrive within `read_timeout`, the permit is held until the client has its answer
+pub fn spawn_connection(
+    mut conn: TcpStream,
+    peer: SocketAddr,
+    permit: ConnectionPermit,
+    protocol: ProtocolConfig,
+    read_timeout: Duration,
+    redact: bool,
+    sender: mpsc::Sender<Received>,
+) {
+    tokio::spawn(async move {
+        let _permit = permit;
+        let read = match timeout(read_timeout, read_connection(&mut conn, &protocol, redact)).await {
+            Ok(read) => read,
+            Err(_) => Err(ErrorArrayItem::new(
+                Errors::TimedOut,
+                format!("receiver: no complete submission within {}s", read_timeout.as_secs()),
+            )),
+        };
+
+        let (header, negotiated, payload) = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(e) => {
+                send_err_tcp(&mut conn).await;
+                let _ = sender.send(Received::Failed(peer, e)).await;
+                return;
+            }
+        };
+
+        // Pings are answered whatever format they arrive in and never reach the queue
+        if header.reserved & PAYLOAD_PING != 0 {
+            let (reply, health) = oneshot::channel();
+            if sender.send(Received::Ping(reply)).await.is_ok() {
+                if let Ok(health) = health.await {
+                    log!(LogLevel::Debug, "Answering ping: {}", health.status);
+                    if let Err(e) = send_pong(&mut conn, &health).await {
+                        log!(LogLevel::Warn, "Failed to answer ping from {}: {}", peer, e);
+                    }
+                }
+            }
+            return;
+        }
+
+        let (reply, filed) = oneshot::channel();
+        if sender.send(Received::Payload { header, negotiated, payload, peer, reply }).await.is_err() {
+            return;
+        }
+        match filed.await {
+            Ok(Ok(Filed::Accepted)) => {
+                if let Err(e) = send_empty_ok::<TcpStream>(&mut conn, Proto::TCP).await {
+                    log!(LogLevel::Warn, "Failed to acknowledge submission from {}: {}", peer, e);
+                }
+            }
+            Ok(Ok(Filed::Refused(_))) => send_status_tcp(&mut conn, ProtocolStatus::REFUSED).await,
+            Ok(Err(_)) | Err(_) => send_err_tcp(&mut conn).await,
+        }
+    });
+}
+
+// Reads one submission's frames. None when the sender was told to upgrade, which it has been by the time this returns
+async fn read_connection(
+    conn: &mut TcpStream,
+    protocol: &ProtocolConfig,
+    redact: bool,
+) -> Result<Option<(ProtocolHeader, Negotiated, String)>, ErrorArrayItem> {
+    let message = read_frame(conn).await?;
+    match redact {
+        true => log!(
+            LogLevel::Debug,
+            "Message recieved: {} byte payload {}\n{}",
+            message.payload.len(),
+            short_hash(&message.payload),
+            message.header
+        ),
+        false => log!(LogLevel::Debug, "Message recieved: {:#?}", message),
+    }
+
+    let mut header: ProtocolHeader = message.header;
+    if header.reserved & PAYLOAD_PING != 0 {
+        return Ok(Some((header, Negotiated::Current, String::new())));
+    }
+
+    // ! Processing the header, OPTIMIZED is current and the configured legacy formats are up-converted
+    let negotiated = negotiate(&protocol.legacy, &header);
+    if negotiated == Negotiated::Upgrade {
+        // Preparing a response requesting a resend with a upgrade
+        let mut response: ProtocolMessage<()> =
+            ProtocolMessage::new(Flags::NONE, ()).map_err(ErrorArrayItem::from)?;
+        response.header.status = ProtocolStatus::SIDEGRADE.bits();
+        response.header.reserved = Flags::OPTIMIZED.bits();
+        log!(LogLevel::Error, "Recieved message in a illegal format asking them to try again");
+        log!(LogLevel::Debug, "Sent the following header to sender: {}", response.header);
+
+        let response_bytes: Vec<u8> = response.to_bytes().await.map_err(ErrorArrayItem::from)?;
+        let _ = conn.write_all(&response_bytes).await;
+        let _ = conn.flush().await;
+        return Ok(None);
+    }
+
+    // ! Now were processing the email data
+    let mut payload = message.payload.to_string();
+    if header.reserved & PAYLOAD_CHUNKED != 0 {
+        payload = reassemble(conn, protocol, &payload).await?;
+        header.reserved &= !PAYLOAD_CHUNKED;
+    }
+    Ok(Some((header, negotiated, payload)))
+}
+
+// The queue sizes a ping is answered with, a busy lock leaves its count unknown rather than the client unanswered
+pub async fn health(intake: &Intake<'_>) -> Health {
+    let queued = count(intake.emails.try_read().await.map(|emails| emails.len()));
+    let held = count(intake.held.try_read().await.map(|held| held.len()));
+    let digest = count(intake.digest.try_read().await.map(|digest| digest.len()));
+    let status = match (queued, held, digest) {
+        (Some(_), Some(_), Some(_)) => "ok",
+        _ => "degraded",
+    };
+    Health { status, version: env!("CARGO_PKG_VERSION"), queued, held, digest }
+}
+
+fn count(read: Result<usize, ErrorArrayItem>) -> Option<usize> {
+    read.map_err(|e| log!(LogLevel::Warn, "Failed to read the queue for a ping: {}", e)).ok()
+}
+
+// Verifies and parses a payload a connection task read, then files it
+pub async fn receive(
+    intake: &Intake<'_>,
+    header: &ProtocolHeader,
+    negotiated: Negotiated,
+    payload: &str,
+    peer: SocketAddr,
+) -> Result<Filed, ErrorArrayItem> {
+    let Intake { app_config, nonces, .. } = *intake;
+    let verified = verify(&app_config.auth, &mut nonces.lock().unwrap_or_else(|poisoned| poisoned.into_inner()), payload);
+    let (payload, key_id) = verified?;
+    let email: EmailPayload = read_payload(&app_config.protocol, header, negotiated, &payload)?;
+
+    // Without a key of its own a client is only known by where it connects from
+    let identity = key_id.unwrap_or_else(|| peer.ip().to_string());
+    file_submission(intake, email, &peer.to_string(), Some(&identity)).await
+}
+
+// What became of a submission handed to `file_submission`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filed {
+    Accepted,
+    // Not queued, with the reason to give the sender
+    Refused(String),
+}
+
+// Applies rules and acceptance checks to a parsed submission, then files it into the digest, the held list or the
+// queue. Shared by every way mail gets in, `source` only labels the trace and `identity` is who the client
+// authenticated as, if anyone
+pub async fn file_submission(
+    intake: &Intake<'_>,
+    mut email: EmailPayload,
+    source: &str,
+    identity: Option<&str>,
+) -> Result<Filed, ErrorArrayItem> {
+    let Intake { app_config, audit, suppressions, emails, held, digest, dedup, script, .. } = *intake;
+    email.submitted_at.get_or_insert_with(Utc::now);
+
+    if let Some(rule) = apply_rules(app_config, &mut email) {
+        log!(LogLevel::Debug, "Email matched rule: {}", rule);
+    }
+    if let Some(Err(reason)) = script.map(|script| script.route(&mut email)) {
+        log!(LogLevel::Warn, "Routing script refused submission: {}", reason);
+        return Ok(Filed::Refused(reason));
+    }
+
+    // A missing excerpt shouldn't hold up the alert it was meant to explain
+    if let Some(request) = email.attach_journal.take() {
+        match app_config.journal.enabled {
+            true => match journal_excerpt(&app_config.journal, &request).await {
+                Ok(attachment) => email.attachments.push(attachment),
+                Err(e) => log!(LogLevel::Warn, "Skipping journal excerpt: {}", e.err_mesg),
+            },
+            false => log!(LogLevel::Warn, "Journal excerpt for {} requested but journal is disabled", request.unit),
+        }
+    }
+
+    if let Err(reason) = enforce_limits(&app_config.attachments, &mut email) {
+        log!(LogLevel::Warn, "Refusing submission: {}", reason);
+        return Ok(Filed::Refused(reason));
+    }
+
+    if let Verdict::Refuse(reason) = run_filters(&app_config.filters, dedup, &mut email).await? {
+        return Ok(Filed::Refused(reason));
+    }
+
+    // Checked last so the headers and addresses are the ones the message will go out with, whatever the rules,
+    // script and filters changed
+    if let Err(reason) = check_reserved(&email.headers)
+        .and_then(|_| enforce_sender(&app_config.senders, &app_config.smtp.from, identity, &mut email))
+        .and_then(|_| check_addresses(app_config, &email))
+    {
+        log!(LogLevel::Warn, "Refusing submission: {}", reason);
+        return Ok(Filed::Refused(reason));
+    }
+
+    // Mail that could only ever go to suppressed addresses is refused rather than queued
+    let recipients = resolve_recipients(app_config, &email);
+    if !email.skip_email && !recipients.is_empty() && recipients.iter().all(|recipient| suppressions.contains(recipient)) {
+        log!(LogLevel::Warn, "Refusing submission for suppressed {}", recipients.join(", "));
+        audit.record(&Uuid::new_v4().to_string(), &email, &recipients, Outcome::Suppressed, None);
+        return Ok(Filed::Refused(format!("suppressed recipients {}", recipients.join(", "))));
+    }
+
+    // Non-critical mail is held for the next digest when enabled
+    if app_config.digest.enabled && !email.is_critical() {
+        audit.record(&Uuid::new_v4().to_string(), &email, &recipients, Outcome::Digested, None);
+        digest.try_write_with_timeout(None).await?.push(email);
+        return Ok(Filed::Accepted);
+    }
+
+    // preping email for queue
+    let email_tagged = TimedEmail::new(email);
+    info!(parent: &email_tagged.span, peer = %source, "accepted");
+    record_audit(audit, app_config, &email_tagged, Outcome::Accepted, None);
+
+    // Hold non-critical mail until the quiet window closes
+    if !email_tagged.email.is_critical() && is_quiet(&app_config.quiet_hours) {
+        info!(parent: &email_tagged.span, "held for quiet hours");
+        record_audit(audit, app_config, &email_tagged, Outcome::Held, None);
+        held.try_write_with_timeout(None).await?.push(email_tagged);
+    } else {
+        emails.try_write_with_timeout(None).await?.push(email_tagged);
+    }
+
+    Ok(Filed::Accepted)
+}
+
+// Sending error over tcp, best effort since the client may already be gone
+pub async fn send_err_tcp(conn: &mut TcpStream) {
+    send_status_tcp(conn, ProtocolStatus::ERROR).await
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Days, Local, NaiveDate};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    admin::redacted,
+    audit::Outcome,
+    config::ReportConfig,
+    payload::EmailPayload,
+};
+
+// What the audit log saw since the last report, only ever kept in memory
+#[derive(Debug)]
+pub struct Summary {
+    since: DateTime<Local>,
+    accepted: u64,
+    sent: u64,
+    failed: u64,
+    expired: u64,
+    clients: HashMap<String, ClientVolume>,
+    // Accepted mail by subject
+    subjects: HashMap<String, u64>,
+    // Failed sends by the relay's error
+    errors: HashMap<String, u64>,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self {
+            since: Local::now(),
+            accepted: 0,
+            sent: 0,
+            failed: 0,
+            expired: 0,
+            clients: HashMap::new(),
+            subjects: HashMap::new(),
+            errors: HashMap::new(),
+        }
+    }
+}
+
+// One client's traffic over a day or a week
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct ClientVolume {
+    pub accepted: u64,
+    pub sent: u64,
+    pub failed: u64,
+}
+
+impl ClientVolume {
+    fn add(&mut self, other: &ClientVolume) {
+        self.accepted += other.accepted;
+        self.sent += other.sent;
+        self.failed += other.failed;
+    }
+
+    // Share of send attempts that failed, as a percentage
+    fn failure_rate(&self) -> f64 {
+        match self.sent + self.failed {
+            0 => 0.0,
+            attempts => self.failed as f64 * 100.0 / attempts as f64,
+        }
+    }
+}
+
+impl Summary {
+    pub fn count(&mut self, email: &EmailPayload, outcome: Outcome, error: Option<&str>) {
+        let client = email.client.as_deref().unwrap_or("(none)");
+        match outcome {
+            Outcome::Accepted | Outcome::Digested => {
+                self.accepted += 1;
+                self.clients.entry(client.to_owned()).or_default().accepted += 1;
+                *self.subjects.entry(email.subject.to_string()).or_default() += 1;
+            }
+            Outcome::Delivered => {
+                self.sent += 1;
+                self.clients.entry(client.to_owned()).or_default().sent += 1;
+            }
+            Outcome::Failed => {
+                self.failed += 1;
+                self.clients.entry(client.to_owned()).or_default().failed += 1;
+                *self.errors.entry(error.unwrap_or("unknown").to_owned()).or_default() += 1;
+            }
+            Outcome::Expired => self.expired += 1,
+            _ => (),
+        }
+    }
+}
+
+// Once a day, the first round at or after `report.at` local time
+pub fn report_due(config: &ReportConfig, last_sent: Option<NaiveDate>) -> bool {
+    let now = Local::now();
+    now.time() >= config.at && last_sent != Some(now.date_naive())
+}
+
+// The largest `top` counts, ties broken by name so the report reads the same each time
+fn top(counts: &HashMap<String, u64>, top: usize) -> Vec<(&String, u64)> {
+    let mut counts: Vec<(&String, u64)> = counts.iter().map(|(name, count)| (name, *count)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    counts.truncate(top);
+    counts
+}
+
+pub fn compose_report(config: &ReportConfig, summary: &Summary, redact: bool) -> EmailPayload {
+    let now = Local::now();
+    let mut body = format!(
+        "From {} to {}\n\nAccepted: {}\nSent: {}\nFailed: {}\nExpired: {}\n",
+        summary.since.format("%Y-%m-%d %H:%M"),
+        now.format("%Y-%m-%d %H:%M"),
+        summary.accepted,
+        summary.sent,
+        summary.failed,
+        summary.expired
+    );
+
+    let clients: HashMap<String, u64> =
+        summary.clients.iter().map(|(client, volume)| (client.clone(), volume.accepted)).collect();
+    let sections = [
+        ("Top clients", &clients, false),
+        ("Top subjects", &summary.subjects, redact),
+        ("Relay errors", &summary.errors, false),
+    ];
+    for (title, counts, hashed) in sections {
+        body.push_str(&format!("\n{}:\n", title));
+        if counts.is_empty() {
+            body.push_str("  none\n");
+        }
+        for (name, count) in top(counts, config.top) {
+            body.push_str(&format!("  {:>6}  {}\n", count, redacted(name, hashed)));
+        }
+    }
+
+    let subject = config.subject.replace("{date}", &now.format("%Y-%m-%d").to_string());
+    let mut email = EmailPayload::new(subject, body);
+    email.to = config.to.clone();
+    email
+}
+
+// A day's per-client traffic as kept in `report.history_path` for the weekly comparison
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Day {
+    pub date: NaiveDate,
+    pub clients: HashMap<String, ClientVolume>,
+}
+
+pub async fn load_history(path: &str) -> Vec<Day> {
+    match fs::read_to_string(path).await {
+        Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+            log!(LogLevel::Error, "Ignoring unreadable report history {}: {}", path, e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Adds the day just summarised, keeping the two weeks the comparison needs
+pub async fn record_day(path: &str, date: NaiveDate, summary: &Summary) -> Result<Vec<Day>, ErrorArrayItem> {
+    let mut history = load_history(path).await;
+    let cutoff = date - Days::new(14);
+    history.retain(|day| day.date > cutoff && day.date != date);
+    history.push(Day { date, clients: summary.clients.clone() });
+
+    let data = serde_json::to_vec_pretty(&history).map_err(ErrorArrayItem::from)?;
+    fs::write(path, data)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("report: {}: {}", path, e)))?;
+    Ok(history)
+}
+
+// Due on the daily run that falls on `report.weekly_day`
+pub fn weekly_due(config: &ReportConfig, date: NaiveDate) -> bool {
+    config.weekly && date.weekday() == config.weekly_day
+}
+
+// Totals per client over the seven days ending `end`, exclusive
+fn week(history: &[Day], end: NaiveDate) -> HashMap<String, ClientVolume> {
+    let start = end - Days::new(7);
+    let mut totals: HashMap<String, ClientVolume> = HashMap::new();
+    for day in history.iter().filter(|day| day.date > start && day.date <= end) {
+        for (client, volume) in &day.clients {
+            totals.entry(client.clone()).or_default().add(volume);
+        }
+    }
+    totals
+}
+
+// Whether a client's volume grew past `surge_factor`, ignoring clients too quiet for the jump to matter
+fn surged(config: &ReportConfig, current: u64, previous: u64) -> bool {
+    current >= config.surge_minimum && current as f64 >= previous as f64 * config.surge_factor
+}
+
+pub fn compose_weekly(config: &ReportConfig, history: &[Day], date: NaiveDate) -> EmailPayload {
+    let current = week(history, date);
+    let previous = week(history, date - Days::new(7));
+
+    let mut clients: Vec<&String> = current.keys().chain(previous.keys()).collect();
+    clients.sort();
+    clients.dedup();
+
+    let mut surges = Vec::new();
+    let mut body = format!("Week ending {} compared with the week before\n\n", date);
+    body.push_str(&format!("{:<24} {:>10} {:>10} {:>8} {:>8}\n", "Client", "Accepted", "Previous", "Failed", "Previous"));
+    for client in clients {
+        let now = current.get(client).copied().unwrap_or_default();
+        let before = previous.get(client).copied().unwrap_or_default();
+        let flag = match surged(config, now.accepted, before.accepted) {
+            true => {
+                surges.push(client.as_str());
+                "  !"
+            }
+            false => "",
+        };
+        body.push_str(&format!(
+            "{:<24} {:>10} {:>10} {:>7.1}% {:>7.1}%{}\n",
+            client,
+            now.accepted,
+            before.accepted,
+            now.failure_rate(),
+            before.failure_rate(),
+            flag
+        ));
+    }
+
+    if !surges.is_empty() {
+        body.push_str(&format!(
+            "\nVolume grew {}x or more for: {}\n",
+            config.surge_factor,
+            surges.join(", ")
+        ));
+    }
+
+    let subject = config
+        .weekly_subject
+        .replace("{date}", &date.to_string())
+        .replace("{surges}", &surges.len().to_string());
+    let mut email = EmailPayload::new(subject, body);
+    email.to = config.to.clone();
+    email
+}
@@ -0,0 +1,142 @@
+use std::{fmt, time::Duration};
+
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use reqwest::StatusCode;
+
+use crate::config::RetryConfig;
+
+// How a failed send should be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    // 4xx replies and server errors, worth trying again later
+    Transient,
+    // 5xx replies and refused API requests, retrying only repeats the rejection
+    Permanent,
+    // The backend couldn't be reached or wouldn't accept our credentials
+    Connection,
+}
+
+// A failed send and how to handle it, decided where the backend's reply is still at hand instead of from the
+// wording of the message afterwards
+#[derive(Debug, Clone)]
+pub struct SendError {
+    pub error: ErrorArrayItem,
+    pub failure: Failure,
+    // The wait a rate-limited backend asked for, which replaces the usual backoff
+    pub retry_after: Option<Duration>,
+}
+
+impl SendError {
+    pub fn new(error: ErrorArrayItem, failure: Failure) -> Self {
+        Self { error, failure, retry_after: None }
+    }
+
+    // An HTTP backend's refusal, timeouts and throttling aside a 4xx won't change on retry
+    pub fn http(service: &str, status: StatusCode, body: &str) -> Self {
+        let failure = match status {
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => Failure::Transient,
+            status if status.is_client_error() => Failure::Permanent,
+            _ => Failure::Transient,
+        };
+        Self::new(ErrorArrayItem::new(Errors::GeneralError, format!("{}: {}: {}", service, status, body)), failure)
+    }
+
+    pub fn with_retry_after(self, wait: Duration) -> Self {
+        Self {
+            retry_after: Some(wait),
+            ..self
+        }
+    }
+}
+
+// Errors raised before a backend answered, an unreachable one is a connection failure and the rest are retried
+impl From<ErrorArrayItem> for SendError {
+    fn from(error: ErrorArrayItem) -> Self {
+        let failure = match error.err_type {
+            Errors::ConnectionError | Errors::Network => Failure::Connection,
+            _ => Failure::Transient,
+        };
+        Self::new(error, failure)
+    }
+}
+
+impl From<SendError> for ErrorArrayItem {
+    fn from(error: SendError) -> Self {
+        error.error
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+// Wait before the next attempt, growing by `multiplier` from `initial_delay_seconds` up to `max_delay_seconds`
+pub fn backoff(config: &RetryConfig, attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+    let delay = config.initial_delay_seconds as f64 * config.multiplier.powi(exponent);
+    Duration::from_secs_f64(delay.min(config.max_delay_seconds as f64).max(0.0))
+}
+
+// Whether a message has used up its attempts, 0 leaves only the queue lifetime as a limit
+pub fn exhausted(config: &RetryConfig, attempts: u32) -> bool {
+    config.max_attempts > 0 && attempts >= config.max_attempts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_http_replies_by_status() {
+        let failure = |status: u16| SendError::http("api", StatusCode::from_u16(status).unwrap(), "").failure;
+        assert_eq!(failure(400), Failure::Permanent);
+        assert_eq!(failure(401), Failure::Permanent);
+        assert_eq!(failure(404), Failure::Permanent);
+        assert_eq!(failure(408), Failure::Transient);
+        assert_eq!(failure(429), Failure::Transient);
+        assert_eq!(failure(500), Failure::Transient);
+        assert_eq!(failure(503), Failure::Transient);
+    }
+
+    #[test]
+    fn keeps_the_reply_in_the_message() {
+        let error =
+            SendError::http("sendgrid", StatusCode::BAD_REQUEST, "invalid from").with_retry_after(Duration::from_secs(5));
+        assert_eq!(error.error.err_mesg.to_string(), "sendgrid: 400 Bad Request: invalid from");
+        assert_eq!(error.retry_after, Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn classifies_errors_raised_before_a_reply() {
+        let failure = |err_type| SendError::from(ErrorArrayItem::new(err_type, String::from("x"))).failure;
+        assert_eq!(failure(Errors::ConnectionError), Failure::Connection);
+        assert_eq!(failure(Errors::Network), Failure::Connection);
+        assert_eq!(failure(Errors::GeneralError), Failure::Transient);
+    }
+
+    #[test]
+    fn backs_off_up_to_the_cap() {
+        let config = RetryConfig {
+            initial_delay_seconds: 2,
+            multiplier: 2.0,
+            max_delay_seconds: 10,
+            ..RetryConfig::default()
+        };
+        let delays: Vec<u64> = (1..=5).map(|attempts| backoff(&config, attempts).as_secs()).collect();
+        assert_eq!(delays, vec![2, 4, 8, 10, 10]);
+        assert_eq!(backoff(&config, u32::MAX).as_secs(), 10);
+    }
+
+    #[test]
+    fn exhausts_only_with_a_limit() {
+        let limited = RetryConfig {
+            max_attempts: 3,
+            ..RetryConfig::default()
+        };
+        assert!(!exhausted(&limited, 2));
+        assert!(exhausted(&limited, 3));
+        assert!(!exhausted(&RetryConfig::default(), 1000));
+    }
+}
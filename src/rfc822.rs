@@ -0,0 +1,102 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+};
+use chrono::DateTime;
+use mailparse::{addrparse_header, dateparse, parse_mail, MailAddr, MailHeaderMap, ParsedMail};
+
+use crate::payload::{Attachment, EmailPayload};
+
+fn invalid(error: impl std::fmt::Display) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::InvalidType, format!("rfc822: {}", error))
+}
+
+// Addresses from the To, Cc and Bcc headers, groups flattened
+fn header_recipients(mail: &ParsedMail) -> Vec<String> {
+    let mut recipients = Vec::new();
+    for name in ["To", "Cc", "Bcc"] {
+        for header in mail.headers.get_all_headers(name) {
+            let Ok(list) = addrparse_header(header) else {
+                continue;
+            };
+            for address in list.iter() {
+                match address {
+                    MailAddr::Single(single) => recipients.push(single.addr.clone()),
+                    MailAddr::Group(group) => recipients.extend(group.addrs.iter().map(|single| single.addr.clone())),
+                }
+            }
+        }
+    }
+    recipients
+}
+
+// Walks the MIME tree, keeping the first text and HTML bodies and anything with a filename as an attachment
+fn collect_parts(part: &ParsedMail, email: &mut EmailPayload, text: &mut Option<String>) -> Result<(), ErrorArrayItem> {
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            collect_parts(subpart, email, text)?;
+        }
+        return Ok(());
+    }
+
+    let disposition = part.get_content_disposition();
+    let mimetype = part.ctype.mimetype.to_lowercase();
+    match disposition.params.get("filename").or_else(|| part.ctype.params.get("name")) {
+        Some(filename) => email.attachments.push(Attachment {
+            filename: filename.clone(),
+            content_type: mimetype,
+            content: STANDARD.encode(part.get_body_raw().map_err(invalid)?),
+            content_id: part
+                .headers
+                .get_first_value("Content-ID")
+                .map(|id| id.trim_start_matches('<').trim_end_matches('>').to_owned()),
+        }),
+        None if mimetype == "text/html" && email.html.is_none() => email.html = Some(part.get_body().map_err(invalid)?),
+        None if mimetype.starts_with("text/") && text.is_none() => *text = Some(part.get_body().map_err(invalid)?),
+        None => {}
+    }
+    Ok(())
+}
+
+// Turns a complete message into a payload, recipients are only taken from the headers when `read_recipients`
+// is set, as with `sendmail -t`
+pub fn from_rfc822(raw: &[u8], read_recipients: bool) -> Result<EmailPayload, ErrorArrayItem> {
+    let mail = parse_mail(raw).map_err(invalid)?;
+
+    let subject = mail.headers.get_first_value("Subject").unwrap_or_default();
+    let mut email = EmailPayload::new(subject, String::new());
+    let mut text = None;
+    collect_parts(&mail, &mut email, &mut text)?;
+    email.body = Stringy::from(text.unwrap_or_default());
+
+    if read_recipients {
+        email.to = header_recipients(&mail);
+    }
+
+    // Checked against the client's allowed sender when the submission is filed
+    if let Some(header) = mail.headers.get_first_header("From") {
+        if let Ok(list) = addrparse_header(header) {
+            email.from = list.extract_single_info().map(|single| single.addr);
+        }
+    }
+
+    // The message's own Date is the closest thing to when the client submitted it
+    if let Some(date) = mail.headers.get_first_value("Date") {
+        email.submitted_at = dateparse(&date).ok().and_then(|timestamp| DateTime::from_timestamp(timestamp, 0));
+    }
+
+    if let Some(header) = mail.headers.get_first_header("Reply-To") {
+        if let Ok(list) = addrparse_header(header) {
+            email.reply_to = list.extract_single_info().map(|single| single.addr);
+        }
+    }
+
+    // Threading headers survive so replies still line up, the Message-ID is replaced with ours for bounces
+    for name in ["In-Reply-To", "References"] {
+        if let Some(value) = mail.headers.get_first_value(name) {
+            email.headers.insert(name.to_owned(), value);
+        }
+    }
+    Ok(email)
+}
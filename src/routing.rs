@@ -0,0 +1,142 @@
+use crate::{
+    config::{AppConfig, Pattern, RuleConfig, TemplatesConfig},
+    headers::set_header,
+    payload::{EmailPayload, Localized},
+};
+
+// Works out who should receive a message, falling back to `smtp.to`
+pub fn resolve_recipients(config: &AppConfig, email: &EmailPayload) -> Vec<String> {
+    if !email.to.is_empty() {
+        return expand_groups(config, &email.to);
+    }
+
+    if let Some(severity) = email.severity {
+        if let Some(to) = config.routing.severity.get(&severity) {
+            if !to.is_empty() {
+                return expand_groups(config, to);
+            }
+        }
+    }
+
+    expand_groups(config, &config.smtp.to)
+}
+
+// Replaces group names with their members, dropping duplicate addresses
+pub fn expand_groups(config: &AppConfig, recipients: &[String]) -> Vec<String> {
+    let mut expanded: Vec<String> = Vec::new();
+
+    for recipient in recipients {
+        let members = match config.groups.get(recipient) {
+            Some(members) => members.clone(),
+            None => vec![recipient.clone()],
+        };
+
+        for member in members {
+            if !expanded.contains(&member) {
+                expanded.push(member);
+            }
+        }
+    }
+
+    expanded
+}
+
+// Applies the first matching rule to the payload, returning the rule name when one matched. Mail no rule template
+// applies to gets the layout for its category or severity instead
+pub fn apply_rules<'a>(config: &'a AppConfig, email: &mut EmailPayload) -> Option<&'a str> {
+    let Some(rule) = config.rules.iter().find(|rule| rule_matches(rule, email)) else {
+        apply_template(&config.templates, email);
+        return None;
+    };
+
+    if email.to.is_empty() && !rule.to.is_empty() {
+        email.to = rule.to.clone();
+    }
+
+    if let Some(priority) = rule.priority {
+        email.priority = priority;
+    }
+
+    if rule.transport.is_some() {
+        email.transport = rule.transport;
+    }
+
+    if rule.return_path.is_some() {
+        email.return_path = rule.return_path.clone();
+    }
+
+    for channel in &rule.channels {
+        if !email.channels.contains(channel) {
+            email.channels.push(channel.clone());
+        }
+    }
+
+    if rule.replace_email {
+        email.skip_email = true;
+    }
+
+    // The rule's headers win over any the submitter set itself
+    for (name, value) in &rule.headers {
+        set_header(&mut email.headers, name, value);
+    }
+
+    if rule.template.is_none() && rule.html_template.is_none() {
+        apply_template(&config.templates, email);
+        return Some(&rule.name);
+    }
+
+    // Rendered before the text template so {body} is still the submitted text
+    if let Some(template) = &rule.html_template {
+        email.html = Some(email.render(template));
+    }
+
+    if let Some(template) = &rule.template {
+        email.body = email.render(template).into();
+    }
+
+    Some(&rule.name)
+}
+
+// The category's layout, else the severity's, else the generic wrapper
+fn apply_template(config: &TemplatesConfig, email: &mut EmailPayload) {
+    let template = email
+        .category
+        .as_ref()
+        .and_then(|category| config.categories.get(category))
+        .or_else(|| email.severity.and_then(|severity| config.severity.get(&severity)))
+        .or(config.generic.as_ref());
+    let Some(template) = template else {
+        return;
+    };
+
+    // Variants render from the submitted text too, so they come before the body is replaced
+    for (locale, variant) in &template.locales {
+        let localized = Localized {
+            body: variant.text.as_ref().map(|text| email.render(text)),
+            html: variant.html.as_ref().map(|html| email.render(html)),
+        };
+        email.localized.insert(locale.clone(), localized);
+    }
+
+    if let Some(html) = &template.html {
+        email.html = Some(email.render(html));
+    }
+    if let Some(text) = &template.text {
+        email.body = email.render(text).into();
+    }
+}
+
+fn rule_matches(rule: &RuleConfig, email: &EmailPayload) -> bool {
+    let matches = |pattern: &Option<Pattern>, value: Option<&str>| match pattern {
+        Some(Pattern(regex)) => value.is_some_and(|value| regex.is_match(value)),
+        None => true,
+    };
+
+    matches(&rule.subject, Some(&email.subject))
+        && matches(&rule.client, email.client.as_deref())
+        && match &rule.tag {
+            Some(Pattern(regex)) => email.tags.iter().any(|tag| regex.is_match(tag)),
+            None => true,
+        }
+        && (rule.severity.is_empty() || email.severity.is_some_and(|severity| rule.severity.contains(&severity)))
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use dusa_collection_utils::{errors::ErrorArrayItem, log, log::LogLevel};
+use futures::{stream, StreamExt};
+
+use crate::{
+    admin::redacted,
+    audit::{AuditLog, Outcome},
+    breaker::CircuitBreaker,
+    config::{AppConfig, TransportKind},
+    email::Keyring,
+    escalation::escalate,
+    metrics::Metrics,
+    queue::{dead_letter_queued, deliver_queued, record_audit, Delivery, TimedEmail},
+    ratelimit::{RecipientThrottle, TokenBucket},
+    retry::{backoff, exhausted, Failure},
+    routing::resolve_recipients,
+    schedule::fair_order,
+    statsd::StatsD,
+    suppression::SuppressionList,
+    transport::MailTransport,
+    twilio::send_sms,
+};
+
+// What a round of sending needs besides the queue, borrowed from whoever runs it
+pub struct Dispatch<'a> {
+    pub app_config: &'a AppConfig,
+    pub keyring: &'a Keyring,
+    pub audit: &'a AuditLog,
+    pub suppressions: &'a SuppressionList,
+    pub statsd: &'a StatsD,
+    // XOAUTH2 token for the relay, when it wants one
+    pub access_token: Option<&'a str>,
+    // Sends everything through this instead of the configured backend, e.g. a `MockTransport` under test
+    pub transport: Option<&'a dyn MailTransport>,
+}
+
+// Expires, picks, sends and settles queued mail one round at a time, keeping the rate limits and relay circuit
+// between rounds. The daemon runs a round every loop, an embedding service can drive its own queue the same way
+pub struct QueueRunner {
+    pub bucket: TokenBucket,
+    pub throttle: RecipientThrottle,
+    pub breaker: CircuitBreaker,
+}
+
+impl QueueRunner {
+    pub fn new(config: &AppConfig) -> Self {
+        Self {
+            bucket: TokenBucket::new(config.rate_limit.burst),
+            throttle: RecipientThrottle::default(),
+            breaker: CircuitBreaker::default(),
+        }
+    }
+
+    // Runs one round over `queue`, leaving in it what is still to be sent. Returns each failure with the subject it
+    // belongs to, redacted under `app.redact_logs`
+    pub async fn run_round(
+        &mut self,
+        dispatch: &Dispatch<'_>,
+        queue: &mut Vec<TimedEmail>,
+        metrics: &mut Metrics,
+    ) -> Vec<(String, ErrorArrayItem)> {
+        let Dispatch { app_config, keyring, audit, suppressions, statsd, access_token, transport } = *dispatch;
+        let current_time = Instant::now();
+        let mut errors: Vec<(String, ErrorArrayItem)> = Vec::new();
+        let allowance = self.bucket.available(app_config.rate_limit.per_minute, app_config.rate_limit.burst);
+        let mut i = 0;
+
+        // Expire what has outlived the retry policy
+        while i < queue.len() {
+            if queue[i].expired(&app_config.retry, current_time) {
+                log!(
+                    LogLevel::Info,
+                    "Expired email discarding: {}",
+                    queue[i].email.summary(app_config.app.redact_logs)
+                );
+                let expired = queue.remove(i);
+                record_audit(audit, app_config, &expired, Outcome::Expired, None);
+                let expired = expired.email;
+                statsd.incr("messages.expired");
+                metrics.events.expired += 1;
+
+                // Don't let a relay outage swallow a critical alert
+                if let Some(twilio) = &app_config.twilio {
+                    if expired.is_critical() && !expired.skip_email {
+                        if let Err(e) = send_sms(twilio, &expired).await {
+                            errors.push((redacted(&expired.subject, app_config.app.redact_logs), e));
+                        }
+                    }
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        // Pick the messages due this round, taking turns between clients unless strict FIFO is configured
+        let order: Vec<usize> = match app_config.app.fair_scheduling {
+            true => fair_order(queue, |timed| timed.email.client.as_deref()),
+            false => (0..queue.len()).collect(),
+        };
+        let mut selected: Vec<(usize, bool)> = Vec::new();
+        for index in order {
+            if selected.len() >= allowance {
+                break;
+            }
+
+            // Still backing off, doesn't count against the rate limit
+            let timed = &queue[index];
+            if timed.next_attempt > current_time {
+                continue;
+            }
+
+            // A recipient over its own limit waits without holding up anyone else's mail
+            let throttled = match &app_config.rate_limit.per_recipient {
+                Some(limit) if !timed.email.skip_email => Some((limit, resolve_recipients(app_config, &timed.email))),
+                _ => None,
+            };
+            if let Some((limit, recipients)) = &throttled {
+                if !self.throttle.ready(limit, recipients) {
+                    continue;
+                }
+            }
+
+            // Relay mail waits while the circuit is open, channels and other backends carry on. Asked after
+            // the throttle so a half-open probe is always sent, and before tokens are taken for a send
+            // that won't happen
+            let relayed = !timed.email.skip_email
+                && timed.email.transport.unwrap_or(app_config.app.transport) == TransportKind::Smtp;
+            if relayed && !self.breaker.allow(&app_config.circuit_breaker) {
+                continue;
+            }
+            if let Some((_, recipients)) = &throttled {
+                self.throttle.take(recipients);
+            }
+            selected.push((index, relayed));
+        }
+
+        // Take the selected messages out highest index first so the remaining indices stay valid
+        let mut removal: Vec<usize> = selected.iter().map(|(index, _)| *index).collect();
+        removal.sort_unstable_by(|a, b| b.cmp(a));
+        let mut taken: HashMap<usize, TimedEmail> = removal.into_iter().map(|index| (index, queue.remove(index))).collect();
+        let batch: Vec<(TimedEmail, bool)> = selected
+            .into_iter()
+            .filter_map(|(index, relayed)| taken.remove(&index).map(|timed| (timed, relayed)))
+            .collect();
+
+        self.bucket.take(batch.len());
+        if let Some(limit) = &app_config.rate_limit.per_recipient {
+            self.throttle.prune(limit);
+        }
+
+        // Up to `app.workers` sends in flight at once, results come back in the order they were picked
+        let results: Vec<(TimedEmail, bool, Delivery, Duration)> = stream::iter(batch)
+            .map(|(mut timed, relayed)| async move {
+                let started = Instant::now();
+                let delivery = deliver_queued(app_config, keyring, suppressions, access_token, transport, &mut timed).await;
+                (timed, relayed, delivery, started.elapsed())
+            })
+            .buffered(app_config.app.workers.max(1))
+            .collect()
+            .await;
+
+        let mut retry: Vec<TimedEmail> = Vec::new();
+        for (count, (mut timed, relayed, delivery, elapsed)) in results.into_iter().enumerate() {
+            statsd.timing("send.duration", elapsed);
+
+            // Only the email's own failure says anything about the relay, a failed channel doesn't
+            if relayed {
+                let relay_error = delivery.email.as_ref().err();
+                metrics.record_relay(relay_error.map(|e| &e.error));
+                let failure = relay_error.map(|e| e.failure);
+                if self.breaker.record(&app_config.circuit_breaker, failure) {
+                    statsd.incr("breaker.opened");
+                }
+            }
+
+            // Attempts only count the email's own failures, not a channel's
+            if let Err(e) = &delivery.email {
+                timed.attempts += 1;
+                if app_config.escalation.enabled
+                    && !timed.escalated
+                    && timed.attempts >= app_config.escalation.after_attempts
+                {
+                    timed.escalated = true;
+                    statsd.incr("messages.escalated");
+                    if let Some(notice) = escalate(app_config, &mut timed.email, &e.error, timed.attempts).await {
+                        queue.push(TimedEmail::notice(notice));
+                    }
+                }
+            }
+
+            match delivery.error() {
+                None if delivery.suppressed => {
+                    log!(LogLevel::Info, "Not sending email {} of {}, every recipient is suppressed", count + 1, allowance);
+                    record_audit(audit, app_config, &timed, delivery.outcome(), None);
+                    statsd.incr("messages.suppressed");
+                }
+                None => {
+                    log!(
+                        LogLevel::Info,
+                        "Sending Email: {} of {}",
+                        count + 1,
+                        allowance
+                    );
+                    record_audit(audit, app_config, &timed, delivery.outcome(), None);
+                    statsd.incr("messages.sent");
+                    metrics.events.sent += 1;
+                    statsd.timing("messages.latency", timed.received_at.elapsed());
+                }
+                Some(e) => {
+                    record_audit(audit, app_config, &timed, Outcome::Failed, Some(&e.error.err_mesg));
+                    statsd.incr("messages.failed");
+                    metrics.events.failed += 1;
+                    log!(
+                        LogLevel::Error,
+                        "An error occurred while sending email: {}",
+                        e
+                    );
+                    errors.push((redacted(&timed.email.subject, app_config.app.redact_logs), e.error.clone()));
+
+                    // Only a failed email is dead-lettered, on its own error. A channel refusing for good
+                    // can't undo an email that already went out, so just that channel is given up on
+                    match &delivery.email {
+                        Err(e) if e.failure == Failure::Permanent => {
+                            statsd.incr("messages.dead_lettered");
+                            metrics.dead_lettered += 1;
+                            dead_letter_queued(app_config, audit, timed, &e.error).await;
+                        }
+                        Err(e) if exhausted(&app_config.retry, timed.attempts) => {
+                            log!(LogLevel::Warn, "Giving up after {} attempts", timed.attempts);
+                            statsd.incr("messages.dead_lettered");
+                            metrics.dead_lettered += 1;
+                            dead_letter_queued(app_config, audit, timed, &e.error).await;
+                        }
+                        _ if delivery.channels_refused() => {
+                            log!(LogLevel::Warn, "Dropping channels {} that refused", timed.email.channels.join(", "));
+                        }
+                        _ => {
+                            let wait = delivery.retry_after().unwrap_or_else(|| backoff(&app_config.retry, timed.attempts));
+                            timed.next_attempt = Instant::now() + wait;
+                            retry.push(timed);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Failures go back to the front so the queue stays oldest first
+        queue.splice(0..0, retry);
+        errors
+    }
+}
@@ -0,0 +1,23 @@
+use std::collections::VecDeque;
+
+// Indices of `items` taking one per client in turn, so a burst from one client can't starve the rest
+pub fn fair_order<T>(items: &[T], client: impl Fn(&T) -> Option<&str>) -> Vec<usize> {
+    let mut clients: Vec<(Option<&str>, VecDeque<usize>)> = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let name = client(item);
+        match clients.iter_mut().find(|(existing, _)| *existing == name) {
+            Some((_, indices)) => indices.push_back(index),
+            None => clients.push((name, VecDeque::from([index]))),
+        }
+    }
+
+    let mut order = Vec::with_capacity(items.len());
+    while order.len() < items.len() {
+        for (_, indices) in clients.iter_mut() {
+            if let Some(index) = indices.pop_front() {
+                order.push(index);
+            }
+        }
+    }
+    order
+}
@@ -0,0 +1,80 @@
+use std::fs;
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use regex::Regex;
+use rhai::{
+    serde::{from_dynamic, to_dynamic},
+    Dynamic, Engine, EvalAltResult, Scope, AST,
+};
+
+use crate::{config::ScriptingConfig, payload::EmailPayload};
+
+// Operator routing logic from `scripting.path`, compiled once and re-read on SIGHUP
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    // None when scripting is disabled
+    pub fn load(config: &ScriptingConfig) -> Result<Option<Self>, ErrorArrayItem> {
+        if !config.enabled {
+            return Ok(None);
+        }
+
+        let source = fs::read_to_string(&config.path)
+            .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("scripting: {}: {}", config.path, e)))?;
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.register_fn("matches", |text: &str, pattern: &str| {
+            Regex::new(pattern).is_ok_and(|regex| regex.is_match(text))
+        });
+
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| ErrorArrayItem::new(Errors::ConfigParsing, format!("scripting: {}: {}", config.path, e)))?;
+        if !ast.iter_functions().any(|function| function.name == "route" && function.params.len() == 1) {
+            return Err(ErrorArrayItem::new(
+                Errors::ConfigParsing,
+                format!("scripting: {} does not define fn route(msg)", config.path),
+            ));
+        }
+
+        Ok(Some(Self { engine, ast }))
+    }
+
+    // Calls the script's `route(msg)` with the message as a map. Returning the map replaces the message, returning
+    // nothing keeps it and `throw "reason"` refuses it. Any other failure is logged and the message passes as it was
+    pub fn route(&self, email: &mut EmailPayload) -> Result<(), String> {
+        let message = match to_dynamic(&*email) {
+            Ok(message) => message,
+            Err(e) => {
+                log!(LogLevel::Error, "Routing script skipped, message not convertible: {}", e);
+                return Ok(());
+            }
+        };
+
+        match self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "route", (message,)) {
+            Ok(routed) if routed.is_unit() => Ok(()),
+            Ok(routed) => {
+                match from_dynamic::<EmailPayload>(&routed) {
+                    Ok(routed) => *email = routed,
+                    Err(e) => log!(LogLevel::Error, "Ignoring routing script result: {}", e),
+                }
+                Ok(())
+            }
+            Err(e) => match *e {
+                EvalAltResult::ErrorRuntime(reason, _) => Err(reason.to_string()),
+                e => {
+                    log!(LogLevel::Error, "Routing script failed: {}", e);
+                    Ok(())
+                }
+            },
+        }
+    }
+}
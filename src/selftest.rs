@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    time::timeout,
+};
+
+use crate::{
+    config::{AppConfig, DeliveryMode, TransportKind},
+    email::{send_email, Keyring},
+    maildir::gethostname,
+    payload::{EmailPayload, Priority},
+    routing::expand_groups,
+    transport::transport_for,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Checks the relay one layer at a time so the error says which layer is broken
+async fn check_relay(config: &AppConfig, access_token: Option<&str>) -> Result<(), ErrorArrayItem> {
+    let smtp = &config.smtp;
+
+    let addresses: Vec<_> = lookup_host((smtp.server.as_str(), smtp.port))
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("self-test: DNS lookup of {} failed: {}", smtp.server, e)))?
+        .collect();
+    log!(LogLevel::Debug, "Self-test: {} resolves to {:?}", smtp.server, addresses);
+
+    match timeout(CONNECT_TIMEOUT, TcpStream::connect(addresses.as_slice())).await {
+        Ok(Ok(_)) => log!(LogLevel::Debug, "Self-test: TCP connection to {}:{} succeeded", smtp.server, smtp.port),
+        Ok(Err(e)) => {
+            return Err(ErrorArrayItem::new(
+                Errors::ConnectionError,
+                format!("self-test: TCP connection to {}:{} failed: {}", smtp.server, smtp.port, e),
+            ))
+        }
+        Err(_) => {
+            return Err(ErrorArrayItem::new(
+                Errors::ConnectionError,
+                format!("self-test: TCP connection to {}:{} timed out", smtp.server, smtp.port),
+            ))
+        }
+    }
+
+    // TLS and authentication happen together in the SMTP handshake
+    transport_for(config, TransportKind::Smtp, access_token)?
+        .verify()
+        .await
+        .map_err(|e| ErrorArrayItem::new(e.err_type, format!("self-test: TLS or authentication with {} failed: {}", smtp.server, e.err_mesg)))
+}
+
+// Verifies the default transport and optionally mails a canary through it
+pub async fn self_test(config: &AppConfig, keyring: &Keyring, access_token: Option<&str>) -> Result<(), ErrorArrayItem> {
+    match (config.app.transport, config.smtp.delivery) {
+        (TransportKind::Smtp, DeliveryMode::Relay) => check_relay(config, access_token).await?,
+        (kind, _) => transport_for(config, kind, access_token)?.verify().await?,
+    }
+
+    if config.self_test.canary {
+        let mut canary = EmailPayload::new(
+            "MailRegulator started".to_owned(),
+            format!("MailRegulator {} started on {} and passed its self-test.", env!("CARGO_PKG_VERSION"), gethostname()),
+        );
+        canary.priority = Priority::Low;
+
+        let to = match config.self_test.canary_to.is_empty() {
+            true => expand_groups(config, &config.smtp.to),
+            false => expand_groups(config, &config.self_test.canary_to),
+        };
+        send_email(config, keyring, access_token, None, &canary, &to, &mut Vec::new()).await?;
+    }
+
+    log!(LogLevel::Info, "Startup self-test passed");
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use lettre::message::Mailbox;
+
+use crate::{
+    config::{AppConfig, SendersConfig},
+    payload::EmailPayload,
+    routing::expand_groups,
+};
+
+// The From address the authenticated `client` may send as: its own entry, then `senders.subaddress` with its
+// name filled in
+pub fn allowed_sender(config: &SendersConfig, client: Option<&str>) -> Option<String> {
+    let client = client?;
+    if let Some(address) = config.clients.get(client) {
+        return Some(address.clone());
+    }
+
+    let name: String = client
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+            true => c.to_ascii_lowercase(),
+            false => '-',
+        })
+        .collect();
+    config.subaddress.as_ref().map(|template| template.replace("{client}", &name))
+}
+
+// Settles the From address of an accepted submission from `identity`, who the client proved to be rather than the
+// name it gives. The client may ask for the address it's allowed or leave it out, anything else is refused with the
+// reason. `smtp.from` is used for clients with no mapping
+pub fn enforce_sender(
+    config: &SendersConfig,
+    default_from: &str,
+    identity: Option<&str>,
+    email: &mut EmailPayload,
+) -> Result<(), String> {
+    let requested = email.from.take();
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let allowed = allowed_sender(config, identity);
+    if let Some(requested) = requested {
+        let permitted = allowed.as_deref().unwrap_or(default_from);
+        if !bare_address(&requested).eq_ignore_ascii_case(bare_address(permitted)) {
+            return Err(format!(
+                "{} may not send as {}",
+                identity.unwrap_or("an unauthenticated client"),
+                requested
+            ));
+        }
+    }
+
+    email.from = allowed;
+    Ok(())
+}
+
+// "Alerts <alerts@example.com>" gives "alerts@example.com"
+fn bare_address(address: &str) -> &str {
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address.trim(),
+    }
+}
+
+// Checks the From and any recipients the submission named against `allowlist` while the sender can still be told
+// why, rather than the send failing later. Addresses that won't parse are refused whether or not lists are set
+pub fn check_addresses(config: &AppConfig, email: &EmailPayload) -> Result<(), String> {
+    let from = email.from.as_deref().unwrap_or(&config.smtp.from);
+    check_address(&config.allowlist.from_domains, "sender", from)?;
+    if let Some(reply_to) = &email.reply_to {
+        check_address(&[], "reply-to", reply_to)?;
+    }
+    for recipient in expand_groups(config, &email.to) {
+        check_address(&config.allowlist.to_domains, "recipient", &recipient)?;
+    }
+    Ok(())
+}
+
+fn check_address(domains: &[String], role: &str, address: &str) -> Result<(), String> {
+    let mailbox: Mailbox = address.parse().map_err(|e| format!("{} {:?} is not a valid address: {}", role, address, e))?;
+    let domain = mailbox.email.domain();
+    if !domains.is_empty() && !domains.iter().any(|allowed| allowed.eq_ignore_ascii_case(domain)) {
+        return Err(format!("{} {} is not in an allowed domain ({})", role, mailbox.email, domains.join(", ")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn config() -> SendersConfig {
+        SendersConfig {
+            enabled: true,
+            clients: HashMap::from([(String::from("billing"), String::from("billing@example.com"))]),
+            subaddress: Some(String::from("alerts+{client}@example.com")),
+        }
+    }
+
+    fn email(from: Option<&str>) -> EmailPayload {
+        let mut email = EmailPayload::new(String::from("subject"), String::from("body"));
+        email.from = from.map(str::to_owned);
+        email
+    }
+
+    #[test]
+    fn sets_the_clients_own_address() {
+        let mut mail = email(None);
+        enforce_sender(&config(), "ops@example.com", Some("billing"), &mut mail).unwrap();
+        assert_eq!(mail.from.as_deref(), Some("billing@example.com"));
+
+        let mut mail = email(Some("Billing <BILLING@example.com>"));
+        enforce_sender(&config(), "ops@example.com", Some("billing"), &mut mail).unwrap();
+        assert_eq!(mail.from.as_deref(), Some("billing@example.com"));
+    }
+
+    #[test]
+    fn falls_back_to_the_subaddress() {
+        let mut mail = email(None);
+        enforce_sender(&config(), "ops@example.com", Some("Backup Job"), &mut mail).unwrap();
+        assert_eq!(mail.from.as_deref(), Some("alerts+backup-job@example.com"));
+    }
+
+    #[test]
+    fn refuses_other_addresses() {
+        let mut mail = email(Some("ceo@example.com"));
+        let refused = enforce_sender(&config(), "ops@example.com", Some("billing"), &mut mail).unwrap_err();
+        assert_eq!(refused, "billing may not send as ceo@example.com");
+
+        // Unauthenticated clients may only ask for the default
+        let mut mail = email(Some("billing@example.com"));
+        assert!(enforce_sender(&config(), "ops@example.com", None, &mut mail).is_err());
+        let mut mail = email(Some("ops@example.com"));
+        enforce_sender(&config(), "ops@example.com", None, &mut mail).unwrap();
+        assert_eq!(mail.from, None);
+    }
+
+    #[test]
+    fn drops_the_requested_address_when_disabled() {
+        let config = SendersConfig {
+            enabled: false,
+            ..config()
+        };
+        let mut mail = email(Some("ceo@example.com"));
+        enforce_sender(&config, "ops@example.com", Some("billing"), &mut mail).unwrap();
+        assert_eq!(mail.from, None);
+    }
+}
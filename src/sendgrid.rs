@@ -0,0 +1,85 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::message::Mailbox;
+use serde_json::{json, Value};
+
+use crate::{config::SendGridConfig, payload::EmailPayload, retry::SendError};
+
+fn address(mailbox: &Mailbox) -> Value {
+    match &mailbox.name {
+        Some(name) => json!({ "email": mailbox.email.to_string(), "name": name }),
+        None => json!({ "email": mailbox.email.to_string() }),
+    }
+}
+
+// SendGrid builds the MIME itself, so the payload is mapped onto its JSON schema
+pub async fn send_sendgrid(
+    config: &SendGridConfig,
+    from: &Mailbox,
+    to: &[Mailbox],
+    payload: &EmailPayload,
+) -> Result<(), SendError> {
+    let mut request = json!({
+        "personalizations": [{ "to": to.iter().map(address).collect::<Vec<Value>>() }],
+        "from": address(from),
+        "subject": payload.subject.to_string(),
+        "content": [{ "type": "text/plain", "value": payload.body.to_string() }],
+    });
+
+    if let (Some(html), Some(content)) = (&payload.html, request["content"].as_array_mut()) {
+        content.push(json!({ "type": "text/html", "value": html }));
+    }
+
+    if !payload.attachments.is_empty() {
+        // Decode once to reject bad base64 here rather than at SendGrid
+        for attachment in &payload.attachments {
+            attachment.decode()?;
+        }
+
+        request["attachments"] = payload
+            .attachments
+            .iter()
+            .map(|attachment| {
+                match &attachment.content_id {
+                    Some(content_id) if payload.html.is_some() => json!({
+                        "content": attachment.content,
+                        "filename": attachment.filename,
+                        "type": attachment.content_type,
+                        "disposition": "inline",
+                        "content_id": content_id,
+                    }),
+                    _ => json!({
+                        "content": attachment.content,
+                        "filename": attachment.filename,
+                        "type": attachment.content_type,
+                        "disposition": "attachment",
+                    }),
+                }
+            })
+            .collect();
+    }
+
+    if !payload.headers.is_empty() {
+        request["headers"] = json!(payload.headers);
+    }
+
+    let response = reqwest::Client::new()
+        .post(&config.endpoint)
+        .bearer_auth(&config.api_key)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("sendgrid: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("sendgrid", status, &body));
+    }
+
+    log!(LogLevel::Debug, "SendGrid accepted message ({})", status);
+    Ok(())
+}
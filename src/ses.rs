@@ -0,0 +1,96 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use hmac::{Hmac, Mac};
+use lettre::address::Envelope;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{config::SesConfig, retry::SendError};
+
+const SERVICE: &str = "ses";
+const PATH: &str = "/v2/email/outbound-emails";
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    // HMAC accepts keys of any length, so this can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// AWS Signature Version 4 for a JSON POST, returns the Authorization header value
+fn sign_v4(config: &SesConfig, host: &str, amz_date: &str, payload: &[u8]) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, config.region, SERVICE);
+
+    let canonical_request = format!(
+        "POST\n{}\n\ncontent-type:application/json\nhost:{}\nx-amz-date:{}\n\ncontent-type;host;x-amz-date\n{}",
+        PATH,
+        host,
+        amz_date,
+        hex::encode(Sha256::digest(payload))
+    );
+
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = hmac_sha256(format!("AWS4{}", config.secret_access_key).as_bytes(), date);
+    let key = hmac_sha256(&key, &config.region);
+    let key = hmac_sha256(&key, SERVICE);
+    let key = hmac_sha256(&key, "aws4_request");
+    let signature = hex::encode(hmac_sha256(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=content-type;host;x-amz-date, Signature={}",
+        config.access_key_id, scope, signature
+    )
+}
+
+// Sends the already rendered message through the SES v2 API so signatures and encryption survive
+pub async fn send_ses(config: &SesConfig, envelope: &Envelope, email: &[u8]) -> Result<(), SendError> {
+    let host = format!("email.{}.amazonaws.com", config.region);
+
+    let mut request = json!({
+        "Content": { "Raw": { "Data": STANDARD.encode(email) } },
+        "Destination": {
+            "ToAddresses": envelope.to().iter().map(|address| address.to_string()).collect::<Vec<String>>()
+        },
+    });
+    if let Some(from) = envelope.from() {
+        request["FromEmailAddress"] = json!(from.to_string());
+    }
+    if let Some(configuration_set) = &config.configuration_set {
+        request["ConfigurationSetName"] = json!(configuration_set);
+    }
+
+    let payload = serde_json::to_vec(&request).map_err(ErrorArrayItem::from)?;
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let authorization = sign_v4(config, &host, &amz_date, &payload);
+
+    let response = reqwest::Client::new()
+        .post(format!("https://{}{}", host, PATH))
+        .header("content-type", "application/json")
+        .header("x-amz-date", &amz_date)
+        .header("authorization", authorization)
+        .body(payload)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("ses: {}", e)))?;
+
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return Err(SendError::http("ses", status, &body));
+    }
+
+    log!(LogLevel::Debug, "SES accepted message: {}", body);
+    Ok(())
+}
@@ -1,12 +1,12 @@
 use std::{sync::Arc, thread};
 
 use dusa_collection_utils::{log, log::LogLevel};
-use signal_hook::{consts::{SIGHUP, SIGUSR1}, iterator::Signals};
+use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2}, iterator::Signals};
 use tokio::sync::Notify;
 
 pub fn reload_monitor(notify: Arc<Notify>) {
     thread::spawn(move || {
-        let mut signals = Signals::new(&[SIGHUP]).expect("Failed to register signals");
+        let mut signals = Signals::new([SIGHUP]).expect("Failed to register signals");
         for _ in signals.forever() {
             log!(LogLevel::Info, "Received SIGHUP, reloading...");
             notify.notify_one();
@@ -16,10 +16,26 @@ pub fn reload_monitor(notify: Arc<Notify>) {
 
 pub fn shutdown_monitor(notify: Arc<Notify>) {
     thread::spawn(move || {
-        let mut signals = Signals::new(&[SIGUSR1]).expect("Failed to register signals");
-        for _ in signals.forever() {
-            log!(LogLevel::Info, "Received SIGHUP, exiting...");
+        // SIGTERM is what systemd sends on stop, SIGINT covers Ctrl-C in a terminal
+        let mut signals = Signals::new([SIGUSR1, SIGTERM, SIGINT]).expect("Failed to register signals");
+        for signal in signals.forever() {
+            let name = match signal {
+                SIGTERM => "SIGTERM",
+                SIGINT => "SIGINT",
+                _ => "SIGUSR1",
+            };
+            log!(LogLevel::Info, "Received {}, exiting...", name);
             notify.notify_one();
         }
     });    
-}
\ No newline at end of file
+}
+
+pub fn diagnostics_monitor(notify: Arc<Notify>) {
+    thread::spawn(move || {
+        let mut signals = Signals::new([SIGUSR2]).expect("Failed to register signals");
+        for _ in signals.forever() {
+            log!(LogLevel::Info, "Received SIGUSR2, dumping diagnostics...");
+            notify.notify_one();
+        }
+    });
+}
@@ -0,0 +1,44 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde_json::json;
+
+use crate::{config::SlackConfig, payload::EmailPayload, retry::SendError};
+
+// Posts a header, the rendered template and a context line to an incoming webhook
+pub async fn post_slack(config: &SlackConfig, email: &EmailPayload) -> Result<(), SendError> {
+    let mut context = format!("*Priority:* {}", email.priority);
+    if let Some(client) = &email.client {
+        context.push_str(&format!("  *Client:* {}", client));
+    }
+    if !email.tags.is_empty() {
+        context.push_str(&format!("  *Tags:* {}", email.tags.join(", ")));
+    }
+
+    let request = json!({
+        "text": email.subject.to_string(),
+        "blocks": [
+            { "type": "header", "text": { "type": "plain_text", "text": email.subject.to_string() } },
+            { "type": "section", "text": { "type": "mrkdwn", "text": email.render(&config.template) } },
+            { "type": "context", "elements": [{ "type": "mrkdwn", "text": context }] },
+        ],
+    });
+
+    let response = reqwest::Client::new()
+        .post(&config.webhook_url)
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("slack: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("slack", status, &body));
+    }
+
+    log!(LogLevel::Debug, "Slack accepted message ({})", status);
+    Ok(())
+}
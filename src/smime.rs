@@ -0,0 +1,108 @@
+use std::fs;
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::message::{
+    header::{ContentDisposition, ContentTransferEncoding, ContentType},
+    Body, MultiPart, SinglePart,
+};
+use openssl::{
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
+
+use crate::{config::SmimeSettings, email::Entity};
+
+// A certificate and key used to sign mail sent from one address
+pub struct SmimeSigner {
+    pub from: String,
+    cert: X509,
+    key: PKey<Private>,
+    chain: Stack<X509>,
+}
+
+impl std::fmt::Debug for SmimeSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SmimeSigner").field("from", &self.from).finish()
+    }
+}
+
+pub fn load_smime(settings: &[SmimeSettings]) -> Result<Vec<SmimeSigner>, ErrorArrayItem> {
+    let mut signers = Vec::new();
+
+    for entry in settings {
+        let read = |path: &str| {
+            fs::read(path).map_err(|e| {
+                ErrorArrayItem::new(Errors::ReadingFile, format!("smime: {}: {}", path, e))
+            })
+        };
+        let invalid = |e: openssl::error::ErrorStack| {
+            ErrorArrayItem::new(Errors::InvalidKey, format!("smime: {}: {}", entry.from, e))
+        };
+
+        let cert = X509::from_pem(&read(&entry.cert_path)?).map_err(invalid)?;
+        let key = PKey::private_key_from_pem(&read(&entry.key_path)?).map_err(invalid)?;
+
+        let mut chain = Stack::new().map_err(invalid)?;
+        if let Some(chain_path) = &entry.chain_path {
+            for cert in X509::stack_from_pem(&read(chain_path)?).map_err(invalid)? {
+                chain.push(cert).map_err(invalid)?;
+            }
+        }
+
+        log!(LogLevel::Info, "Loaded S/MIME certificate for {}", entry.from);
+        signers.push(SmimeSigner {
+            from: entry.from.to_lowercase(),
+            cert,
+            key,
+            chain,
+        });
+    }
+
+    Ok(signers)
+}
+
+impl SmimeSigner {
+    // Wraps the entity in a multipart/signed structure with a detached PKCS#7 signature,
+    // lettre never emits 8bit bodies so the signed bytes survive relays unchanged
+    pub fn sign(&self, entity: Entity) -> Result<MultiPart, ErrorArrayItem> {
+        // The signature covers the entity up to, but excluding, the CRLF before the boundary
+        let mut signed_bytes = entity.formatted();
+        signed_bytes.truncate(signed_bytes.len().saturating_sub(2));
+
+        let signature = Pkcs7::sign(
+            &self.cert,
+            &self.key,
+            &self.chain,
+            &signed_bytes,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )
+        .and_then(|pkcs7| pkcs7.to_der())
+        .map_err(|e| ErrorArrayItem::new(Errors::InvalidSignature, format!("smime: {}", e)))?;
+
+        let signature_part = SinglePart::builder()
+            .header(ContentType::parse("application/pkcs7-signature; name=\"smime.p7s\"").map_err(
+                |e| ErrorArrayItem::new(Errors::MessageEncode, format!("smime: {}", e)),
+            )?)
+            .header(ContentDisposition::attachment("smime.p7s"))
+            .body(Body::new_with_encoding(signature, ContentTransferEncoding::Base64).map_err(
+                |_| ErrorArrayItem::new(Errors::MessageEncode, "smime: signature encoding".to_owned()),
+            )?);
+
+        let signed = MultiPart::signed(
+            String::from("application/pkcs7-signature"),
+            String::from("sha-256"),
+        );
+        let signed = match entity {
+            Entity::Single(part) => signed.singlepart(part),
+            Entity::Multi(part) => signed.multipart(part),
+        };
+
+        Ok(signed.singlepart(signature_part))
+    }
+}
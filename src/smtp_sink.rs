@@ -0,0 +1,194 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use colored::Colorize;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use mailparse::{parse_headers, MailHeaderMap};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+// One message received by the sink
+#[derive(Debug, Clone)]
+pub struct CapturedMail {
+    pub from: String,
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+// An in-process SMTP server that accepts everything, for exercising the relay path without a real relay
+#[derive(Debug, Clone, Default)]
+pub struct SmtpSink {
+    captured: Arc<Mutex<Vec<CapturedMail>>>,
+    // Replies given to DATA instead of 250, in order, e.g. "451 4.3.0 try again later"
+    data_replies: Arc<Mutex<Vec<String>>>,
+    // Print a line per captured message, for `--debug-smtp`
+    print: bool,
+}
+
+impl SmtpSink {
+    #[cfg(any(test, feature = "mock"))]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn printing() -> Self {
+        Self {
+            print: true,
+            ..Self::default()
+        }
+    }
+
+    #[cfg(any(test, feature = "mock"))]
+    pub fn reply_to_next_data(&self, reply: &str) {
+        self.data_replies.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(reply.to_owned());
+    }
+
+    #[cfg(any(test, feature = "mock"))]
+    pub fn captured(&self) -> Vec<CapturedMail> {
+        self.captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    // Binds `address` and serves connections on a background task, port 0 picks a free one
+    pub async fn start(&self, address: SocketAddr) -> Result<SocketAddr, ErrorArrayItem> {
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::Network, format!("smtp sink: {}: {}", address, e)))?;
+        let bound = listener
+            .local_addr()
+            .map_err(|e| ErrorArrayItem::new(Errors::Network, format!("smtp sink: {}", e)))?;
+
+        let sink = self.clone();
+        tokio::spawn(async move {
+            while let Ok((conn, peer)) = listener.accept().await {
+                let sink = sink.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = sink.session(conn).await {
+                        log!(LogLevel::Debug, "SMTP sink session with {} ended: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        log!(LogLevel::Info, "SMTP sink listening on {}", bound);
+        Ok(bound)
+    }
+
+    fn next_data_reply(&self) -> Option<String> {
+        let mut replies = self.data_replies.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match replies.is_empty() {
+            true => None,
+            false => Some(replies.remove(0)),
+        }
+    }
+
+    // Just enough SMTP for lettre: no AUTH, no TLS, one transaction at a time
+    async fn session(&self, conn: TcpStream) -> std::io::Result<()> {
+        let (reader, mut writer) = conn.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut from = String::new();
+        let mut to = Vec::new();
+
+        writer.write_all(b"220 localhost ESMTP sink\r\n").await?;
+        while let Some(line) = lines.next_line().await? {
+            let verb = line.split_whitespace().next().unwrap_or_default().to_uppercase();
+            // "MAIL FROM:<a@example.com> BODY=8BITMIME" gives "a@example.com"
+            let argument = || {
+                let (_, rest) = line.split_once(':').unwrap_or_default();
+                let address = rest.split_whitespace().next().unwrap_or_default();
+                address.trim_start_matches('<').trim_end_matches('>').to_owned()
+            };
+
+            let reply = match verb.as_str() {
+                "EHLO" => String::from("250-localhost\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN XOAUTH2"),
+                // Any credentials will do, the sink only cares that the client got through
+                "AUTH" => {
+                    let words = line.split_whitespace().count();
+                    if words == 2 && line.to_uppercase().ends_with("LOGIN") {
+                        writer.write_all(b"334 VXNlcm5hbWU6\r\n").await?;
+                        lines.next_line().await?;
+                        writer.write_all(b"334 UGFzc3dvcmQ6\r\n").await?;
+                        lines.next_line().await?;
+                    } else if words == 2 {
+                        writer.write_all(b"334 \r\n").await?;
+                        lines.next_line().await?;
+                    }
+                    String::from("235 2.7.0 Authentication successful")
+                }
+                "HELO" => String::from("250 localhost"),
+                "MAIL" => {
+                    from = argument();
+                    String::from("250 OK")
+                }
+                "RCPT" => {
+                    to.push(argument());
+                    String::from("250 OK")
+                }
+                "DATA" => {
+                    writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+                    let mut data = String::new();
+                    while let Some(line) = lines.next_line().await? {
+                        if line == "." {
+                            break;
+                        }
+                        // Undo dot-stuffing
+                        data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                        data.push_str("\r\n");
+                    }
+
+                    let mail = CapturedMail {
+                        from: std::mem::take(&mut from),
+                        to: std::mem::take(&mut to),
+                        data,
+                    };
+                    match self.next_data_reply() {
+                        Some(reply) => reply,
+                        None => {
+                            // A printing sink runs for as long as the server, so it keeps nothing
+                            match self.print {
+                                true => print_summary(&mail),
+                                false => self.captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(mail),
+                            }
+                            String::from("250 OK queued")
+                        }
+                    }
+                }
+                "RSET" => {
+                    from.clear();
+                    to.clear();
+                    String::from("250 OK")
+                }
+                "NOOP" => String::from("250 OK"),
+                "QUIT" => {
+                    writer.write_all(b"221 Bye\r\n").await?;
+                    return Ok(());
+                }
+                _ => String::from("502 Command not implemented"),
+            };
+            writer.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn print_summary(mail: &CapturedMail) {
+    let subject = parse_headers(mail.data.as_bytes())
+        .ok()
+        .and_then(|(headers, _)| headers.get_first_value("Subject"))
+        .unwrap_or_default();
+    println!(
+        "{} {} -> {}: {} ({} bytes)",
+        "captured".green().bold(),
+        mail.from,
+        mail.to.join(", "),
+        subject,
+        mail.data.len()
+    );
+}
@@ -0,0 +1,51 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use std::io::ErrorKind;
+
+use tokio::fs;
+
+use crate::payload::EmailPayload;
+
+// Writes messages that couldn't be sent before shutdown so the next start picks them up. Also checkpoints a spool
+// being worked through, removing it once nothing is left
+pub async fn save_spool(path: &str, emails: &[EmailPayload]) -> Result<(), ErrorArrayItem> {
+    if emails.is_empty() {
+        return match fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(ErrorArrayItem::new(Errors::GeneralError, format!("spool: {}: {}", path, e)))
+            }
+            _ => Ok(()),
+        };
+    }
+
+    let data = serde_json::to_vec_pretty(emails).map_err(ErrorArrayItem::from)?;
+    fs::write(path, data)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("spool: {}: {}", path, e)))?;
+
+    log!(LogLevel::Info, "Spooled {} unsent messages to {}", emails.len(), path);
+    Ok(())
+}
+
+// Reads the spool, a missing file just means nothing was left over. The file stays until `save_spool` records the
+// messages as sent, so a crash before then requeues them again
+pub async fn load_spool(path: &str) -> Vec<EmailPayload> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(_) => return Vec::new(),
+    };
+
+    let emails: Vec<EmailPayload> = match serde_json::from_slice(&data) {
+        Ok(emails) => emails,
+        Err(e) => {
+            log!(LogLevel::Error, "Ignoring unreadable spool {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+
+    log!(LogLevel::Info, "Requeued {} spooled messages from {}", emails.len(), path);
+    emails
+}
@@ -0,0 +1,68 @@
+use std::{net::UdpSocket, time::Duration};
+
+use dusa_collection_utils::{log, log::LogLevel};
+
+use crate::config::StatsdConfig;
+
+// Fire-and-forget UDP metrics, a dropped packet is preferable to blocking the queue
+#[derive(Debug, Default)]
+pub struct StatsD {
+    socket: Option<UdpSocket>,
+    prefix: String,
+    // DogStatsD tag suffix, empty for plain StatsD
+    tags: String,
+}
+
+impl StatsD {
+    pub fn new(config: &StatsdConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).and_then(|socket| {
+            socket.connect((config.host.as_str(), config.port))?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        });
+
+        match socket {
+            Ok(socket) => Self {
+                socket: Some(socket),
+                prefix: config.prefix.trim_end_matches('.').to_owned(),
+                tags: match config.dogstatsd && !config.tags.is_empty() {
+                    true => format!("|#{}", config.tags.join(",")),
+                    false => String::new(),
+                },
+            },
+            Err(e) => {
+                log!(LogLevel::Error, "Failed to set up StatsD sink for {}:{}: {}", config.host, config.port, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn incr(&self, name: &str) {
+        self.count(name, 1);
+    }
+
+    pub fn count(&self, name: &str, value: usize) {
+        self.send(name, &format!("{}|c", value));
+    }
+
+    pub fn timing(&self, name: &str, elapsed: Duration) {
+        self.send(name, &format!("{}|ms", elapsed.as_millis()));
+    }
+
+    pub fn gauge(&self, name: &str, value: usize) {
+        self.send(name, &format!("{}|g", value));
+    }
+
+    fn send(&self, name: &str, value: &str) {
+        if let Some(socket) = &self.socket {
+            let line = format!("{}.{}:{}{}", self.prefix, name, value, self.tags);
+            if let Err(e) = socket.send(line.as_bytes()) {
+                log!(LogLevel::Trace, "Dropped StatsD metric {}: {}", name, e);
+            }
+        }
+    }
+}
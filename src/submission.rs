@@ -0,0 +1,548 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use openssl::memcmp;
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use crate::{
+    config::{BanConfig, ConnectionConfig, SubmissionConfig},
+    limits::{BanList, ConnectionPermit, Connections},
+    maildir::gethostname,
+    payload::EmailPayload,
+    receiver::Filed,
+    rfc822::from_rfc822,
+};
+
+// RFC 5321 4.5.3.1.6, the longest command or text line counting its CRLF
+const MAX_LINE: usize = 1000;
+
+// A message received over SMTP, filed by the main loop which answers on `reply`
+pub struct Submitted {
+    pub email: EmailPayload,
+    pub source: String,
+    // The SMTP user it authenticated as
+    pub identity: Option<String>,
+    pub reply: oneshot::Sender<Result<Filed, ErrorArrayItem>>,
+}
+
+fn tls_acceptor(config: &SubmissionConfig) -> Result<Option<TlsAcceptor>, ErrorArrayItem> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) else {
+        // AUTH would otherwise go out in the clear with nothing to upgrade to
+        if config.require_tls {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                String::from("submission: require_tls needs tls_cert_path and tls_key_path"),
+            ));
+        }
+        return Ok(None);
+    };
+
+    let read = |path: &String| {
+        std::fs::read(path)
+            .map_err(|e| ErrorArrayItem::new(Errors::ReadingFile, format!("submission: {}: {}", path, e)))
+    };
+    let identity = native_tls::Identity::from_pkcs8(&read(cert_path)?, &read(key_path)?)
+        .map_err(|e| ErrorArrayItem::new(Errors::InvalidType, format!("submission: {}", e)))?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("submission: {}", e)))?;
+    Ok(Some(TlsAcceptor::from(acceptor)))
+}
+
+// Listens for SMTP submissions from mail clients on the LAN, handing each message to the main loop. Connections count
+// against the same caps and bans as the protocol listener
+pub async fn submission_monitor(
+    config: SubmissionConfig,
+    limits: ConnectionConfig,
+    ban_config: BanConfig,
+    connections: Connections,
+    bans: BanList,
+    sender: Sender<Submitted>,
+) -> Result<(), ErrorArrayItem> {
+    let acceptor = tls_acceptor(&config)?;
+    let listener = TcpListener::bind((config.bind, config.port)).await.map_err(|e| {
+        ErrorArrayItem::new(Errors::Network, format!("submission: {}:{}: {}", config.bind, config.port, e))
+    })?;
+    log!(LogLevel::Info, "SMTP submission listening on {}:{}", config.bind, config.port);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log!(LogLevel::Warn, "Failed to accept SMTP connection: {}", e);
+                    continue;
+                }
+            };
+
+            if bans.is_banned(peer.ip()) {
+                log!(LogLevel::Debug, "Dropping SMTP connection from banned peer {}", peer);
+                continue;
+            }
+            let Some(permit) = connections.acquire(&limits, peer.ip()) else {
+                log!(LogLevel::Warn, "Refusing SMTP connection from {}, connection limit reached", peer);
+                let _ = conn.write_all(b"421 4.7.0 Too many connections, try again later\r\n").await;
+                continue;
+            };
+
+            let session = Session {
+                config: config.clone(),
+                acceptor: acceptor.clone(),
+                sender: sender.clone(),
+                bans: bans.clone(),
+                ban_config: ban_config.clone(),
+                peer,
+                _permit: permit,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = session.run(conn).await {
+                    log!(LogLevel::Debug, "SMTP session with {} ended: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// Checked against `submission.users`, AUTH PLAIN carries "\0user\0password" and AUTH LOGIN asks for each. Digests are
+// compared in constant time so neither the password's length nor how much of it matched shows in the timing
+fn check_login(users: &HashMap<String, String>, username: &str, password: &str) -> bool {
+    let expected = users.get(username);
+    let matched = memcmp::eq(
+        &Sha256::digest(expected.map(String::as_str).unwrap_or_default()),
+        &Sha256::digest(password),
+    );
+    expected.is_some() && matched
+}
+
+fn decode(text: &str) -> String {
+    STANDARD
+        .decode(text.trim())
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_default()
+}
+
+struct Session {
+    config: SubmissionConfig,
+    acceptor: Option<TlsAcceptor>,
+    sender: Sender<Submitted>,
+    bans: BanList,
+    ban_config: BanConfig,
+    peer: SocketAddr,
+    // Frees the connection slot when the session ends
+    _permit: ConnectionPermit,
+}
+
+// What the command loop ended with
+enum Ended {
+    Quit,
+    StartTls,
+}
+
+// Per-connection state, kept across STARTTLS apart from the transaction
+#[derive(Default)]
+struct State {
+    secure: bool,
+    user: Option<String>,
+    from: Option<String>,
+    to: Vec<String>,
+}
+
+impl Session {
+    async fn run(self, mut conn: TcpStream) -> std::io::Result<()> {
+        conn.write_all(format!("220 {} ESMTP MailRegulator\r\n", gethostname()).as_bytes()).await?;
+
+        let mut state = State::default();
+        let mut reader = BufReader::new(conn);
+        if let Ended::StartTls = self.converse(&mut reader, &mut state).await? {
+            let Some(acceptor) = &self.acceptor else {
+                return Ok(());
+            };
+            let stream = acceptor.accept(reader.into_inner()).await.map_err(std::io::Error::other)?;
+
+            // RFC 3207: everything learned before the handshake is forgotten
+            let mut state = State {
+                secure: true,
+                ..State::default()
+            };
+            self.converse(&mut BufReader::new(stream), &mut state).await?;
+        }
+        Ok(())
+    }
+
+    async fn converse<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        reader: &mut BufReader<S>,
+        state: &mut State,
+    ) -> std::io::Result<Ended> {
+        let tls_offered = self.acceptor.is_some() && !state.secure;
+        let mut line = Vec::new();
+
+        loop {
+            if self.read_line(reader, &mut line).await? == 0 {
+                return Ok(Ended::Quit);
+            }
+            let command = String::from_utf8_lossy(&line).trim_end().to_owned();
+            let mut words = command.split_whitespace();
+            let verb = words.next().unwrap_or_default().to_uppercase();
+
+            let reply = match verb.as_str() {
+                "EHLO" => {
+                    let mut reply = format!(
+                        "250-{}\r\n250-8BITMIME\r\n250-SIZE {}\r\n",
+                        gethostname(),
+                        self.config.max_size_kb * 1024
+                    );
+                    if tls_offered {
+                        reply.push_str("250-STARTTLS\r\n");
+                    }
+                    reply.push_str("250 AUTH PLAIN LOGIN");
+                    reply
+                }
+                "HELO" => format!("250 {}", gethostname()),
+                "STARTTLS" if tls_offered => {
+                    write_reply(reader, "220 2.0.0 Ready to start TLS").await?;
+                    return Ok(Ended::StartTls);
+                }
+                "AUTH" if self.config.require_tls && !state.secure => {
+                    String::from("538 5.7.11 Encryption required for requested authentication mechanism")
+                }
+                "AUTH" => {
+                    let mechanism = words.next().unwrap_or_default().to_uppercase();
+                    let initial = words.next().map(str::to_owned);
+                    let credentials = match mechanism.as_str() {
+                        "PLAIN" => {
+                            let response = match initial {
+                                Some(response) => response,
+                                None => self.prompt(reader, "334 ").await?,
+                            };
+                            let decoded = decode(&response);
+                            let mut parts = decoded.split('\0').skip(1);
+                            Some((
+                                parts.next().unwrap_or_default().to_owned(),
+                                parts.next().unwrap_or_default().to_owned(),
+                            ))
+                        }
+                        "LOGIN" => {
+                            let username = decode(&self.prompt(reader, "334 VXNlcm5hbWU6").await?);
+                            let password = decode(&self.prompt(reader, "334 UGFzc3dvcmQ6").await?);
+                            Some((username, password))
+                        }
+                        _ => None,
+                    };
+
+                    match credentials {
+                        Some((username, password)) if check_login(&self.config.users, &username, &password) => {
+                            log!(LogLevel::Debug, "SMTP client {} authenticated as {}", self.peer, username);
+                            state.user = Some(username);
+                            String::from("235 2.7.0 Authentication successful")
+                        }
+                        Some((username, _)) => {
+                            log!(LogLevel::Warn, "SMTP authentication failed for {} from {}", username, self.peer);
+                            if self.bans.record_failure(&self.ban_config, self.peer.ip()) {
+                                write_reply(reader, "421 4.7.0 Too many failed logins, closing connection").await?;
+                                return Ok(Ended::Quit);
+                            }
+                            String::from("535 5.7.8 Authentication credentials invalid")
+                        }
+                        None => String::from("504 5.5.4 Unrecognized authentication type"),
+                    }
+                }
+                "MAIL" | "RCPT" | "DATA" if state.user.is_none() => String::from("530 5.7.0 Authentication required"),
+                "MAIL" => {
+                    state.from = Some(address(&command));
+                    state.to.clear();
+                    String::from("250 2.1.0 OK")
+                }
+                "RCPT" if state.from.is_none() => String::from("503 5.5.1 MAIL first"),
+                "RCPT" if state.to.len() >= self.config.max_recipients => String::from("452 4.5.3 Too many recipients"),
+                "RCPT" => {
+                    state.to.push(address(&command));
+                    String::from("250 2.1.5 OK")
+                }
+                "DATA" if state.to.is_empty() => String::from("503 5.5.1 RCPT first"),
+                "DATA" => {
+                    write_reply(reader, "354 End data with <CR><LF>.<CR><LF>").await?;
+                    let reply = self.receive_data(reader, state).await?;
+                    state.from = None;
+                    state.to.clear();
+                    reply
+                }
+                "RSET" => {
+                    state.from = None;
+                    state.to.clear();
+                    String::from("250 2.0.0 OK")
+                }
+                "NOOP" => String::from("250 2.0.0 OK"),
+                "QUIT" => {
+                    write_reply(reader, "221 2.0.0 Bye").await?;
+                    return Ok(Ended::Quit);
+                }
+                _ => String::from("502 5.5.2 Command not implemented"),
+            };
+            write_reply(reader, &reply).await?;
+        }
+    }
+
+    // Reads the message up to the lone dot and files it, the reply tells the client whether it was queued
+    async fn receive_data<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        reader: &mut BufReader<S>,
+        state: &State,
+    ) -> std::io::Result<String> {
+        let limit = self.config.max_size_kb as usize * 1024;
+        let mut data = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            if self.read_line(reader, &mut line).await? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            if line == b".\r\n" || line == b".\n" {
+                break;
+            }
+            if data.len() <= limit {
+                // Undo dot-stuffing
+                data.extend_from_slice(line.strip_prefix(b".").unwrap_or(&line));
+            }
+        }
+
+        if data.len() > limit {
+            return Ok(format!("552 5.3.4 Message exceeds the {} KB limit", self.config.max_size_kb));
+        }
+
+        let mut email = match from_rfc822(&data, false) {
+            Ok(email) => email,
+            Err(e) => return Ok(format!("554 5.6.0 {}", e.err_mesg)),
+        };
+        email.to = state.to.clone();
+        email.client = state.user.clone();
+        if let Some(from) = state.from.as_ref().filter(|from| !from.is_empty()) {
+            email.return_path = Some(from.clone());
+        }
+
+        let (reply, filed) = oneshot::channel();
+        let submitted = Submitted {
+            email,
+            source: format!("smtp:{}", self.peer),
+            identity: state.user.clone(),
+            reply,
+        };
+        if self.sender.send(submitted).await.is_err() {
+            return Ok(String::from("421 4.3.0 Server shutting down"));
+        }
+
+        Ok(match filed.await {
+            Ok(Ok(Filed::Accepted)) => String::from("250 2.0.0 Queued"),
+            Ok(Ok(Filed::Refused(reason))) => format!("550 5.7.1 {}", reason),
+            Ok(Err(e)) => format!("451 4.3.0 {}", e.err_mesg),
+            Err(_) => String::from("451 4.3.0 Server busy, try again later"),
+        })
+    }
+
+    // Reads one line into `line`, 0 at the end of the stream. The client is told before a silence longer than
+    // `command_timeout_seconds` or a line longer than RFC 5321 allows ends the session
+    async fn read_line<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        reader: &mut BufReader<S>,
+        line: &mut Vec<u8>,
+    ) -> std::io::Result<usize> {
+        line.clear();
+        let timeout = Duration::from_secs(self.config.command_timeout_seconds);
+        let read = match tokio::time::timeout(timeout, (&mut *reader).take(MAX_LINE as u64).read_until(b'\n', line)).await {
+            Ok(read) => read?,
+            Err(_) => {
+                write_reply(reader, "421 4.4.2 Idle too long, closing connection").await?;
+                return Err(std::io::Error::new(ErrorKind::TimedOut, format!("idle for {}s", timeout.as_secs())));
+            }
+        };
+        if read == MAX_LINE && !line.ends_with(b"\n") {
+            write_reply(reader, "500 5.5.2 Line too long").await?;
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "line too long"));
+        }
+        Ok(read)
+    }
+
+    async fn prompt<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        reader: &mut BufReader<S>,
+        challenge: &str,
+    ) -> std::io::Result<String> {
+        write_reply(reader, challenge).await?;
+        let mut answer = Vec::new();
+        self.read_line(reader, &mut answer).await?;
+        Ok(String::from_utf8_lossy(&answer).trim_end().to_owned())
+    }
+}
+
+// "MAIL FROM:<a@example.com> SIZE=100" gives "a@example.com"
+fn address(command: &str) -> String {
+    let (_, rest) = command.split_once(':').unwrap_or_default();
+    let address = rest.split_whitespace().next().unwrap_or_default();
+    address.trim_start_matches('<').trim_end_matches('>').to_owned()
+}
+
+async fn write_reply<S: AsyncRead + AsyncWrite + Unpin>(reader: &mut BufReader<S>, reply: &str) -> std::io::Result<()> {
+    let stream = reader.get_mut();
+    stream.write_all(format!("{}\r\n", reply).as_bytes()).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    fn session(config: SubmissionConfig, ban_config: BanConfig) -> Session {
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 2525));
+        let (sender, _) = tokio::sync::mpsc::channel(1);
+        Session {
+            config,
+            acceptor: None,
+            sender,
+            bans: BanList::default(),
+            ban_config,
+            peer,
+            _permit: Connections::default().acquire(&ConnectionConfig::default(), peer.ip()).expect("permit"),
+        }
+    }
+
+    fn plaintext() -> SubmissionConfig {
+        SubmissionConfig {
+            require_tls: false,
+            users: HashMap::from([(String::from("backups"), String::from("secret"))]),
+            ..SubmissionConfig::default()
+        }
+    }
+
+    // Runs the command loop on one end of a pipe, handing back the other end and the loop's outcome
+    fn converse(
+        session: Session,
+        state: State,
+    ) -> (BufReader<DuplexStream>, tokio::task::JoinHandle<std::io::Result<Ended>>) {
+        let (client, server) = duplex(8192);
+        let handle = tokio::spawn(async move {
+            let mut state = state;
+            session.converse(&mut BufReader::new(server), &mut state).await
+        });
+        (BufReader::new(client), handle)
+    }
+
+    async fn command(client: &mut BufReader<DuplexStream>, line: &str) -> String {
+        client.get_mut().write_all(format!("{}\r\n", line).as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        reply.trim_end().to_owned()
+    }
+
+    fn plain(username: &str, password: &str) -> String {
+        format!("AUTH PLAIN {}", STANDARD.encode(format!("\0{}\0{}", username, password)))
+    }
+
+    #[test]
+    fn checks_logins() {
+        let users = plaintext().users;
+        assert!(check_login(&users, "backups", "secret"));
+        assert!(!check_login(&users, "backups", "secre"));
+        assert!(!check_login(&users, "backups", ""));
+        assert!(!check_login(&users, "nobody", "secret"));
+    }
+
+    #[test]
+    fn require_tls_needs_a_certificate() {
+        assert!(tls_acceptor(&SubmissionConfig::default()).is_err());
+        assert!(tls_acceptor(&plaintext()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_cleartext_auth_when_tls_is_required() {
+        let config = SubmissionConfig {
+            require_tls: true,
+            ..plaintext()
+        };
+        let (mut client, _) = converse(session(config, BanConfig::default()), State::default());
+        assert!(command(&mut client, &plain("backups", "secret")).await.starts_with("538 "));
+    }
+
+    #[tokio::test]
+    async fn authenticates() {
+        let (mut client, _) = converse(session(plaintext(), BanConfig::default()), State::default());
+        assert!(command(&mut client, &plain("backups", "secret")).await.starts_with("235 "));
+    }
+
+    #[tokio::test]
+    async fn bans_after_failed_logins() {
+        let ban_config = BanConfig {
+            max_failures: 2,
+            ..BanConfig::default()
+        };
+        let session = session(plaintext(), ban_config);
+        let bans = session.bans.clone();
+        let (mut client, handle) = converse(session, State::default());
+
+        assert!(command(&mut client, &plain("backups", "wrong")).await.starts_with("535 "));
+        assert!(command(&mut client, &plain("backups", "wrong")).await.starts_with("421 "));
+        assert!(matches!(handle.await.unwrap(), Ok(Ended::Quit)));
+        assert!(bans.is_banned(IpAddr::from(Ipv4Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn caps_recipients() {
+        let config = SubmissionConfig {
+            max_recipients: 2,
+            ..plaintext()
+        };
+        let state = State {
+            user: Some(String::from("backups")),
+            ..State::default()
+        };
+        let (mut client, _) = converse(session(config, BanConfig::default()), state);
+
+        assert!(command(&mut client, "MAIL FROM:<a@example.com>").await.starts_with("250 "));
+        assert!(command(&mut client, "RCPT TO:<b@example.com>").await.starts_with("250 "));
+        assert!(command(&mut client, "RCPT TO:<c@example.com>").await.starts_with("250 "));
+        assert!(command(&mut client, "RCPT TO:<d@example.com>").await.starts_with("452 "));
+    }
+
+    #[tokio::test]
+    async fn drops_overlong_lines() {
+        let (mut client, handle) = converse(session(plaintext(), BanConfig::default()), State::default());
+
+        let long = format!("NOOP {}", "x".repeat(MAX_LINE));
+        assert!(command(&mut client, &long).await.starts_with("500 "));
+        let ended = handle.await.unwrap();
+        assert!(ended.is_err_and(|e| e.kind() == ErrorKind::InvalidData));
+    }
+
+    #[tokio::test]
+    async fn disconnects_idle_clients() {
+        let config = SubmissionConfig {
+            command_timeout_seconds: 1,
+            ..plaintext()
+        };
+        let (mut client, handle) = converse(session(config, BanConfig::default()), State::default());
+
+        let mut reply = String::new();
+        client.read_line(&mut reply).await.unwrap();
+        assert!(reply.starts_with("421 "));
+        let ended = handle.await.unwrap();
+        assert!(ended.is_err_and(|e| e.kind() == ErrorKind::TimedOut));
+    }
+}
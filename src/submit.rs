@@ -0,0 +1,32 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use artisan_middleware::communication_proto::{send_message, Flags, Proto};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use tokio::net::TcpStream;
+
+use crate::{auth::sign, config::AppConfig, payload::EmailPayload};
+
+// Hands a payload to the running server over its own protocol, the way any other client would
+pub async fn submit(config: &AppConfig, email: &EmailPayload) -> Result<(), ErrorArrayItem> {
+    // A wildcard bind is reached over loopback
+    let host = match config.app.bind {
+        IpAddr::V4(address) if address.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(address) if address.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        address => address,
+    };
+
+    let mut payload = serde_json::to_string(email).map_err(ErrorArrayItem::from)?;
+    if let Some(secret) = &config.auth.secret {
+        payload = sign(secret, config.auth.key_id.as_deref(), &payload)?;
+    }
+
+    let mut stream = TcpStream::connect((host, config.app.port))
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("submit: {}:{}: {}", host, config.app.port, e)))?;
+
+    send_message::<TcpStream, String, ()>(&mut stream, Flags::OPTIMIZED, payload, Proto::TCP, false)
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("submit: {}", e)))?
+        .map_err(|status| ErrorArrayItem::new(Errors::GeneralError, format!("submit: refused with status {:?}", status)))?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::config::SuppressionConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuppressionEntry {
+    pub reason: String,
+    pub added: DateTime<Utc>,
+}
+
+// Addresses mail must never go to, the config's manual entries plus those added at runtime
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SuppressionList {
+    pub addresses: BTreeMap<String, SuppressionEntry>,
+    #[serde(skip)]
+    manual: Vec<String>,
+}
+
+impl SuppressionList {
+    pub async fn load(config: &SuppressionConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+
+        let mut list: Self = match fs::read_to_string(&config.path).await {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                log!(LogLevel::Error, "Ignoring unreadable suppression list {}: {}", config.path, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        };
+        list.manual = config.addresses.iter().map(|address| address_key(address)).collect();
+        list
+    }
+
+    pub async fn save(&self, path: &str) -> Result<(), ErrorArrayItem> {
+        let data = serde_json::to_vec_pretty(self).map_err(ErrorArrayItem::from)?;
+        fs::write(path, data)
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("suppression: {}: {}", path, e)))
+    }
+
+    // Returns false when the address was already suppressed
+    pub fn add(&mut self, address: &str, reason: &str) -> bool {
+        let key = address_key(address);
+        if self.contains(&key) {
+            return false;
+        }
+        self.addresses.insert(key, SuppressionEntry { reason: reason.to_owned(), added: Utc::now() });
+        true
+    }
+
+    pub fn contains(&self, address: &str) -> bool {
+        let key = address_key(address);
+        self.manual.contains(&key) || self.addresses.contains_key(&key)
+    }
+
+    // Drops suppressed addresses, logging each one so a missing alert can be traced back here
+    pub fn filter(&self, recipients: Vec<String>) -> Vec<String> {
+        recipients
+            .into_iter()
+            .filter(|recipient| {
+                let suppressed = self.contains(recipient);
+                if suppressed {
+                    log!(LogLevel::Info, "Not sending to suppressed address {}", recipient);
+                }
+                !suppressed
+            })
+            .collect()
+    }
+}
+
+// "Name <User@Example.com>" to "user@example.com"
+fn address_key(address: &str) -> String {
+    let address = match (address.rfind('<'), address.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &address[start + 1..end],
+        _ => address,
+    };
+    address.trim().to_lowercase()
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use dusa_collection_utils::{log, log::LogLevel};
+use sd_notify::NotifyState;
+
+// Every call is a no-op when not started by systemd with Type=notify
+
+fn send(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        log!(LogLevel::Warn, "Failed to notify systemd: {}", e);
+    }
+}
+
+pub fn notify_ready(status: &str) {
+    send(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+pub fn notify_status(status: &str) {
+    send(&[NotifyState::Status(status)]);
+}
+
+pub fn notify_stopping() {
+    send(&[NotifyState::Stopping]);
+}
+
+pub fn notify_watchdog() {
+    send(&[NotifyState::Watchdog]);
+}
+
+// WatchdogSec from the unit, if one is set for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    match sd_notify::watchdog_enabled(false, &mut usec) {
+        true => Some(Duration::from_micros(usec)),
+        false => None,
+    }
+}
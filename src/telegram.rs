@@ -0,0 +1,48 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde_json::json;
+
+use crate::{config::TelegramConfig, payload::EmailPayload, retry::SendError};
+
+// sendMessage rejects text longer than this
+const TEXT_LIMIT: usize = 4096;
+
+// Sends a plain text message to the configured chat, skipping mail below `min_priority`
+pub async fn post_telegram(config: &TelegramConfig, email: &EmailPayload) -> Result<(), SendError> {
+    if email.priority < config.min_priority {
+        log!(LogLevel::Debug, "Skipping telegram for {} priority mail", email.priority);
+        return Ok(());
+    }
+
+    let text = email.render(&config.template);
+    let text = match text.char_indices().nth(TEXT_LIMIT) {
+        Some((index, _)) => &text[..index],
+        None => &text,
+    };
+
+    let request = json!({
+        "chat_id": config.chat_id,
+        "text": text,
+        "disable_web_page_preview": true,
+    });
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/bot{}/sendMessage", config.api_url.trim_end_matches('/'), config.bot_token))
+        .json(&request)
+        .send()
+        .await
+        // The token is part of the URL, so keep it out of the error
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("telegram: {}", e.without_url())))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("telegram", status, &body));
+    }
+
+    log!(LogLevel::Debug, "Telegram accepted message ({})", status);
+    Ok(())
+}
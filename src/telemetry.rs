@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use dusa_collection_utils::{log, log::LogLevel};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::{info_span, Span};
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+use crate::{admin::redacted, config::TelemetryConfig, payload::EmailPayload};
+
+// Whether span subjects are hashed, set with the subscriber since spans are created without the config at hand
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+// Exports spans over OTLP/HTTP, read once at startup since the global subscriber can't be swapped on reload.
+// `redact` follows `app.redact_logs`
+pub fn init_tracing(config: &TelemetryConfig, redact: bool) -> Option<TracerProvider> {
+    REDACT.store(redact, Ordering::Relaxed);
+    if !config.enabled {
+        return None;
+    }
+
+    let exporter = match SpanExporter::builder().with_http().with_endpoint(&config.endpoint).build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            log!(LogLevel::Error, "Failed to set up OTLP export, tracing disabled: {}", e);
+            return None;
+        }
+    };
+
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", config.service_name.clone())]))
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+    if let Err(e) = tracing::subscriber::set_global_default(Registry::default().with(layer)) {
+        log!(LogLevel::Error, "Failed to install tracing subscriber: {}", e);
+        return None;
+    }
+
+    log!(LogLevel::Info, "Exporting traces to {}", config.endpoint);
+    Some(provider)
+}
+
+// Flushes spans still waiting in the batch before exit
+pub fn shutdown_tracing(provider: Option<TracerProvider>) {
+    if let Some(provider) = provider {
+        if let Err(e) = provider.shutdown() {
+            log!(LogLevel::Warn, "Failed to flush traces: {}", e);
+        }
+    }
+}
+
+// Root span covering a message from acceptance until it is sent or dropped
+pub fn message_span(email: &EmailPayload) -> Span {
+    info_span!(
+        parent: None,
+        "message",
+        subject = %redacted(&email.subject, REDACT.load(Ordering::Relaxed)),
+        client = email.client.as_deref().unwrap_or("unknown"),
+        priority = %email.priority,
+        correlation_id = email.correlation_id.as_deref().unwrap_or(""),
+    )
+}
@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use lettre::{
+    address::Envelope, message::Mailbox, AsyncFileTransport, AsyncSendmailTransport, AsyncTransport,
+    Tokio1Executor,
+};
+
+use crate::{
+    config::{
+        AppConfig, DeliveryMode, FileTransportConfig, MailgunConfig, SendGridConfig, SesConfig, SmtpConfig,
+        TransportKind,
+    },
+    email::{send_via_relay, verify_relay},
+    maildir::write_maildir,
+    mailgun::send_mailgun,
+    mx::deliver_mx,
+    payload::EmailPayload,
+    retry::SendError,
+    sendgrid::send_sendgrid,
+    ses::send_ses,
+};
+
+// Everything a backend may need to hand off one message
+pub struct Outgoing<'a> {
+    pub envelope: &'a Envelope,
+    pub formatted: &'a [u8],
+    pub from: &'a Mailbox,
+    pub to: &'a [Mailbox],
+    pub payload: &'a EmailPayload,
+}
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    fn kind(&self) -> TransportKind;
+
+    // Backends that build their own MIME can't carry signed or encrypted mail
+    fn carries_mime(&self) -> bool {
+        true
+    }
+
+    // Backends that deliver to each domain themselves are given one domain per send, so each can succeed or fail alone
+    fn splits_by_domain(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError>;
+
+    // Checks the backend is reachable, backends without a cheap check assume they are
+    async fn verify(&self) -> Result<(), ErrorArrayItem> {
+        Ok(())
+    }
+}
+
+fn missing_section(name: &str) -> ErrorArrayItem {
+    ErrorArrayItem::new(
+        Errors::ConfigParsing,
+        format!("mailer: {} transport selected without a [{}] section", name, name),
+    )
+}
+
+// Picks the backend for `kind`, failing when its config section is absent
+pub fn transport_for<'a>(
+    config: &'a AppConfig,
+    kind: TransportKind,
+    access_token: Option<&'a str>,
+) -> Result<Box<dyn MailTransport + 'a>, ErrorArrayItem> {
+    Ok(match kind {
+        TransportKind::Smtp => match config.smtp.delivery {
+            DeliveryMode::Relay => Box::new(SmtpRelay { config: &config.smtp, access_token }),
+            DeliveryMode::Mx => Box::new(SmtpMx { config: &config.smtp, access_token }),
+        },
+        TransportKind::Sendmail => Box::new(Sendmail { config: &config.smtp }),
+        TransportKind::File => Box::new(File { config: &config.file }),
+        TransportKind::Maildir => Box::new(Maildir { path: &config.maildir.path }),
+        TransportKind::Ses => Box::new(Ses { config: config.ses.as_ref().ok_or_else(|| missing_section("ses"))? }),
+        TransportKind::SendGrid => Box::new(SendGrid {
+            config: config.sendgrid.as_ref().ok_or_else(|| missing_section("sendgrid"))?,
+        }),
+        TransportKind::Mailgun => Box::new(Mailgun {
+            config: config.mailgun.as_ref().ok_or_else(|| missing_section("mailgun"))?,
+        }),
+    })
+}
+
+pub struct SmtpRelay<'a> {
+    config: &'a SmtpConfig,
+    access_token: Option<&'a str>,
+}
+
+#[async_trait]
+impl MailTransport for SmtpRelay<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Smtp
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        send_via_relay(self.config, self.access_token, message.envelope, message.formatted).await
+    }
+
+    async fn verify(&self) -> Result<(), ErrorArrayItem> {
+        verify_relay(self.config, self.access_token).await
+    }
+}
+
+pub struct SmtpMx<'a> {
+    config: &'a SmtpConfig,
+    access_token: Option<&'a str>,
+}
+
+#[async_trait]
+impl MailTransport for SmtpMx<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Smtp
+    }
+
+    fn splits_by_domain(&self) -> bool {
+        true
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        deliver_mx(self.config, self.access_token, message.envelope, message.formatted).await
+    }
+}
+
+pub struct Sendmail<'a> {
+    pub config: &'a SmtpConfig,
+}
+
+#[async_trait]
+impl MailTransport for Sendmail<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Sendmail
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        let transport = match &self.config.sendmail_command {
+            Some(command) => AsyncSendmailTransport::<Tokio1Executor>::new_with_command(command),
+            None => AsyncSendmailTransport::<Tokio1Executor>::new(),
+        };
+
+        transport
+            .send_raw(message.envelope, message.formatted)
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::GeneralError, format!("sendmail: {}", e)).into())
+    }
+}
+
+// Writes the rendered message to disk, for staging and CI where nothing may be sent
+pub struct File<'a> {
+    config: &'a FileTransportConfig,
+}
+
+#[async_trait]
+impl MailTransport for File<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::File
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        let directory = &self.config.directory;
+        tokio::fs::create_dir_all(directory).await.map_err(|e| {
+            ErrorArrayItem::new(Errors::CreatingDirectory, format!("file: {}: {}", directory, e))
+        })?;
+
+        let id = AsyncFileTransport::<Tokio1Executor>::new(directory)
+            .send_raw(message.envelope, message.formatted)
+            .await
+            .map_err(|e| ErrorArrayItem::new(Errors::CreatingFile, format!("file: {}", e)))?;
+
+        log!(LogLevel::Info, "Wrote message {} to {}", id, directory);
+        Ok(())
+    }
+}
+
+pub struct Maildir<'a> {
+    path: &'a str,
+}
+
+#[async_trait]
+impl MailTransport for Maildir<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Maildir
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        Ok(write_maildir(self.path, message.formatted).await?)
+    }
+}
+
+pub struct Ses<'a> {
+    config: &'a SesConfig,
+}
+
+#[async_trait]
+impl MailTransport for Ses<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Ses
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        send_ses(self.config, message.envelope, message.formatted).await
+    }
+}
+
+pub struct SendGrid<'a> {
+    config: &'a SendGridConfig,
+}
+
+#[async_trait]
+impl MailTransport for SendGrid<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::SendGrid
+    }
+
+    fn carries_mime(&self) -> bool {
+        false
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        send_sendgrid(self.config, message.from, message.to, message.payload).await
+    }
+}
+
+pub struct Mailgun<'a> {
+    config: &'a MailgunConfig,
+}
+
+#[async_trait]
+impl MailTransport for Mailgun<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Mailgun
+    }
+
+    async fn send(&self, message: &Outgoing<'_>) -> Result<(), SendError> {
+        send_mailgun(self.config, message.envelope, message.formatted).await
+    }
+}
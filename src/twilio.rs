@@ -0,0 +1,57 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+
+use crate::{config::TwilioConfig, payload::EmailPayload};
+
+// Keeps the summary within a few SMS segments
+const BODY_LIMIT: usize = 480;
+
+// Texts a summary of the message to every configured number, used when a critical email can't be delivered
+pub async fn send_sms(config: &TwilioConfig, email: &EmailPayload) -> Result<(), ErrorArrayItem> {
+    let body = email.render(&config.template);
+    let body = match body.char_indices().nth(BODY_LIMIT) {
+        Some((index, _)) => &body[..index],
+        None => &body,
+    };
+
+    let url = format!(
+        "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
+        config.account_sid
+    );
+    let client = reqwest::Client::new();
+    let mut last_error: Option<ErrorArrayItem> = None;
+
+    for to in &config.to {
+        let params = [("From", config.from.as_str()), ("To", to.as_str()), ("Body", body)];
+        let response = client
+            .post(&url)
+            .basic_auth(&config.account_sid, Some(&config.auth_token))
+            .form(&params)
+            .send()
+            .await;
+
+        let result = match response {
+            Ok(response) if response.status().is_success() => {
+                log!(LogLevel::Info, "Sent SMS fallback to {}", to);
+                continue;
+            }
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                ErrorArrayItem::new(Errors::GeneralError, format!("twilio: {}: {}: {}", to, status, body))
+            }
+            Err(e) => ErrorArrayItem::new(Errors::ConnectionError, format!("twilio: {}: {}", to, e)),
+        };
+
+        log!(LogLevel::Error, "Failed to send SMS fallback: {}", result);
+        last_error = Some(result);
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
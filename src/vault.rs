@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::VaultConfig;
+
+// Leases are renewed this long before Vault says they expire
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SecretResponse {
+    #[serde(default)]
+    lease_duration: u64,
+    data: Value,
+}
+
+// Holds the AppRole token and the lifetime of the SMTP credentials read with it
+#[derive(Debug, Default)]
+pub struct VaultCredentials {
+    token: Option<(String, Instant)>,
+    renew_at: Option<Instant>,
+}
+
+fn vault_error(e: reqwest::Error) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::Network, format!("vault: {}", e))
+}
+
+impl VaultCredentials {
+    pub fn clear(&mut self) {
+        self.token = None;
+        self.renew_at = None;
+    }
+
+    // Returns a new username and password once the current lease is due, None while it is still good
+    pub async fn rotate(&mut self, config: &VaultConfig) -> Result<Option<(String, String)>, ErrorArrayItem> {
+        if let Some(renew_at) = self.renew_at {
+            if Instant::now() < renew_at {
+                return Ok(None);
+            }
+        }
+
+        let token = self.client_token(config).await?;
+        let url = format!("{}/v1/{}", config.address.trim_end_matches('/'), config.secret_path);
+        log!(LogLevel::Debug, "Reading SMTP credentials from Vault at {}", config.secret_path);
+
+        let response = reqwest::Client::new()
+            .get(&url)
+            .header("X-Vault-Token", token)
+            .send()
+            .await
+            .map_err(vault_error)?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(vault_error)?;
+        if !status.is_success() {
+            return Err(ErrorArrayItem::new(
+                Errors::AuthenticationError,
+                format!("vault: reading {} failed with {}: {}", config.secret_path, status, body),
+            ));
+        }
+
+        let secret: SecretResponse = serde_json::from_str(&body).map_err(ErrorArrayItem::from)?;
+        // KV version 2 nests the secret one level further down
+        let data = match secret.data.get("data") {
+            Some(inner) if inner.is_object() => inner,
+            _ => &secret.data,
+        };
+        let field = |key: &str| {
+            data.get(key).and_then(Value::as_str).map(str::to_owned).ok_or_else(|| {
+                ErrorArrayItem::new(
+                    Errors::ConfigParsing,
+                    format!("vault: {} has no {} field", config.secret_path, key),
+                )
+            })
+        };
+        let credentials = (field(&config.username_key)?, field(&config.password_key)?);
+
+        // Static secrets carry no lease, so they are re-read on the configured interval
+        let lifetime = match secret.lease_duration {
+            0 => Duration::from_secs(config.refresh_minutes * 60),
+            seconds => Duration::from_secs(seconds).saturating_sub(REFRESH_MARGIN.min(Duration::from_secs(seconds / 2))),
+        };
+        self.renew_at = Some(Instant::now() + lifetime);
+
+        Ok(Some(credentials))
+    }
+
+    async fn client_token(&mut self, config: &VaultConfig) -> Result<String, ErrorArrayItem> {
+        if let Some((token, expires_at)) = &self.token {
+            if Instant::now() + REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        log!(LogLevel::Debug, "Logging in to Vault with AppRole");
+        let url = format!("{}/v1/auth/{}/login", config.address.trim_end_matches('/'), config.auth_mount);
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&json!({ "role_id": config.role_id, "secret_id": config.secret_id }))
+            .send()
+            .await
+            .map_err(vault_error)?;
+
+        let status = response.status();
+        let body = response.text().await.map_err(vault_error)?;
+        if !status.is_success() {
+            return Err(ErrorArrayItem::new(
+                Errors::AuthenticationError,
+                format!("vault: login failed with {}: {}", status, body),
+            ));
+        }
+
+        let login: LoginResponse = serde_json::from_str(&body).map_err(ErrorArrayItem::from)?;
+        self.token = Some((
+            login.auth.client_token.clone(),
+            Instant::now() + Duration::from_secs(login.auth.lease_duration),
+        ));
+
+        Ok(login.auth.client_token)
+    }
+}
@@ -0,0 +1,45 @@
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    log,
+    log::LogLevel,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{config::WebhookConfig, payload::EmailPayload, retry::SendError};
+
+// POSTs the payload exactly as the mailer sees it, signed when a secret is configured
+pub async fn post_webhook(config: &WebhookConfig, email: &EmailPayload) -> Result<(), SendError> {
+    let body = serde_json::to_vec(email).map_err(ErrorArrayItem::from)?;
+
+    let mut request = reqwest::Client::new()
+        .post(&config.url)
+        .header("Content-Type", "application/json");
+
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+
+    // Same scheme as GitHub webhooks: hex HMAC-SHA256 of the raw body
+    if let Some(secret) = &config.secret {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC key of any size");
+        mac.update(&body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+        request = request.header(&config.signature_header, format!("sha256={}", signature));
+    }
+
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| ErrorArrayItem::new(Errors::ConnectionError, format!("webhook: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(SendError::http("webhook", status, &body));
+    }
+
+    log!(LogLevel::Debug, "Webhook accepted message ({})", status);
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use dusa_collection_utils::errors::ErrorArrayItem;
+use mail_regulator::config::AppConfig;
+use mail_regulator::{
+    AuditLog, Dispatch, EmailPayload, Keyring, MailTransport, Metrics, MockTransport, QueueRunner, StatsD,
+    SuppressionList, TimedEmail,
+};
+
+// Just enough configuration to send mail, `extra` is appended for the section under test
+pub fn config(extra: &str) -> AppConfig {
+    let toml = format!(
+        r#"
+[smtp]
+server = "localhost"
+port = 25
+to = "ops@example.com"
+from = "regulator@example.com"
+
+[app]
+loop_interval_seconds = 1
+redact_logs = false
+dead_letter_path = "{}"
+
+{}
+"#,
+        std::env::temp_dir().join(format!("mailregulator-test-{}", std::process::id())).display(),
+        extra
+    );
+    toml::from_str(&toml).expect("test config")
+}
+
+pub fn message(subject: &str, to: &str) -> TimedEmail {
+    let mut email = EmailPayload::new(subject.to_owned(), String::from("body"));
+    email.to = vec![to.to_owned()];
+    TimedEmail::new(email)
+}
+
+// Holds what a round borrows so each test only deals with the queue and the runner
+pub struct Harness {
+    pub config: AppConfig,
+    // Sends go through the configured transport when there is no mock
+    pub mock: Option<MockTransport>,
+    pub keyring: Keyring,
+    pub audit: AuditLog,
+    pub suppressions: SuppressionList,
+    pub statsd: StatsD,
+    pub runner: QueueRunner,
+    pub metrics: Metrics,
+}
+
+impl Harness {
+    pub fn new(config: AppConfig, mock: Option<&MockTransport>) -> Self {
+        Self {
+            mock: mock.cloned(),
+            keyring: Keyring::default(),
+            audit: AuditLog::new(&config.audit),
+            suppressions: SuppressionList::default(),
+            statsd: StatsD::default(),
+            runner: QueueRunner::new(&config),
+            metrics: Metrics::default(),
+            config,
+        }
+    }
+
+    pub async fn round(&mut self, queue: &mut Vec<TimedEmail>) -> Vec<(String, ErrorArrayItem)> {
+        let dispatch = Dispatch {
+            app_config: &self.config,
+            keyring: &self.keyring,
+            audit: &self.audit,
+            suppressions: &self.suppressions,
+            statsd: &self.statsd,
+            access_token: None,
+            transport: self.mock.as_ref().map(|mock| mock as &dyn MailTransport),
+        };
+        self.runner.run_round(&dispatch, queue, &mut self.metrics).await
+    }
+}
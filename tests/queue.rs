@@ -0,0 +1,171 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{config, message, Harness};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use mail_regulator::{Failure, MockTransport, SendError, TimedEmail};
+
+// What the mock answers in place of a backend's reply
+fn refusal(message: &str, failure: Failure) -> SendError {
+    SendError::new(ErrorArrayItem::new(Errors::GeneralError, message.to_owned()), failure)
+}
+
+#[tokio::test]
+async fn delivers_queued_mail() {
+    let mock = MockTransport::new();
+    let mut harness = Harness::new(config(""), Some(&mock));
+    let mut queue = vec![message("first", "a@example.com"), message("second", "b@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert!(errors.is_empty());
+    assert!(queue.is_empty());
+    let sent = mock.sent();
+    assert_eq!(sent.len(), 2);
+    assert_eq!(sent[0].subject, "first");
+    assert_eq!(sent[0].to, vec!["a@example.com".to_owned()]);
+    assert_eq!(sent[1].subject, "second");
+    assert_eq!(harness.metrics.events.sent, 2);
+}
+
+#[tokio::test]
+async fn retries_transient_failures_after_backoff() {
+    let mock = MockTransport::new();
+    mock.fail_next(refusal("mailer: transient error (421): busy", Failure::Transient));
+    let mut harness = Harness::new(config("[retry]\ninitial_delay_seconds = 30"), Some(&mock));
+    let mut queue = vec![message("flaky", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, "flaky");
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].attempts, 1);
+    assert!(queue[0].next_attempt > Instant::now() + Duration::from_secs(20));
+    assert_eq!(harness.metrics.events.failed, 1);
+
+    // Still backing off, the next round leaves it alone
+    assert!(harness.round(&mut queue).await.is_empty());
+    assert_eq!(queue.len(), 1);
+    assert!(mock.sent().is_empty());
+
+    queue[0].next_attempt = Instant::now();
+    assert!(harness.round(&mut queue).await.is_empty());
+    assert!(queue.is_empty());
+    assert_eq!(mock.sent().len(), 1);
+    assert_eq!(harness.metrics.events.sent, 1);
+}
+
+#[tokio::test]
+async fn dead_letters_permanent_failures() {
+    let mock = MockTransport::new();
+    mock.fail_next(refusal("mailer: permanent error (550): no such user", Failure::Permanent));
+    let mut harness = Harness::new(config(""), Some(&mock));
+    let mut queue = vec![message("refused", "nobody@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert_eq!(errors.len(), 1);
+    assert!(queue.is_empty());
+    assert!(mock.sent().is_empty());
+    assert_eq!(harness.metrics.dead_lettered, 1);
+}
+
+#[tokio::test]
+async fn gives_up_after_max_attempts() {
+    let mock = MockTransport::new();
+    for _ in 0..2 {
+        mock.fail_next(refusal("mailer: transient error (451): later", Failure::Transient));
+    }
+    let mut harness = Harness::new(config("[retry]\nmax_attempts = 2"), Some(&mock));
+    let mut queue = vec![message("stubborn", "a@example.com")];
+
+    harness.round(&mut queue).await;
+    assert_eq!(queue.len(), 1);
+
+    queue[0].next_attempt = Instant::now();
+    harness.round(&mut queue).await;
+    assert!(queue.is_empty());
+    assert_eq!(harness.metrics.dead_lettered, 1);
+}
+
+#[tokio::test]
+async fn expires_mail_past_its_lifetime() {
+    let mock = MockTransport::new();
+    let mut harness = Harness::new(config("[retry]\nmax_lifetime_seconds = 60"), Some(&mock));
+    let mut stale = message("stale", "a@example.com");
+    stale.received_at = Instant::now() - Duration::from_secs(120);
+    let mut queue = vec![stale, message("fresh", "b@example.com")];
+
+    harness.round(&mut queue).await;
+
+    assert!(queue.is_empty());
+    let sent = mock.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].subject, "fresh");
+    assert_eq!(harness.metrics.events.expired, 1);
+}
+
+#[tokio::test]
+async fn holds_mail_over_the_rate_limit() {
+    let mock = MockTransport::new();
+    let mut harness = Harness::new(config("[rate_limit]\nper_minute = 1\nburst = 2"), Some(&mock));
+    let mut queue: Vec<TimedEmail> =
+        (0..5).map(|n| message(&format!("message {}", n), "a@example.com")).collect();
+
+    harness.round(&mut queue).await;
+    assert_eq!(mock.sent().len(), 2);
+    assert_eq!(queue.len(), 3);
+    assert_eq!(queue[0].email.subject.to_string(), "message 2");
+
+    // The bucket refills one a minute, nothing more goes out straight away
+    harness.round(&mut queue).await;
+    assert_eq!(mock.sent().len(), 2);
+    assert_eq!(queue.len(), 3);
+}
+
+#[tokio::test]
+async fn throttles_each_recipient_separately() {
+    let mock = MockTransport::new();
+    let extra = "[rate_limit]\nburst = 10\n\n[rate_limit.per_recipient]\nper_minute = 1\nburst = 1";
+    let mut harness = Harness::new(config(extra), Some(&mock));
+    let mut queue = vec![
+        message("one", "busy@example.com"),
+        message("two", "busy@example.com"),
+        message("three", "quiet@example.com"),
+    ];
+
+    harness.round(&mut queue).await;
+
+    let subjects: Vec<String> = mock.sent().into_iter().map(|sent| sent.subject).collect();
+    assert_eq!(subjects, vec!["one".to_owned(), "three".to_owned()]);
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].email.subject.to_string(), "two");
+}
+
+#[tokio::test]
+async fn keeps_retrying_mail_escalated_to_nothing() {
+    let mock = MockTransport::new();
+    mock.fail_next(refusal("mailer: transient error (421): busy", Failure::Transient));
+    let extra = "[escalation]\nenabled = true\nafter_attempts = 1\nnotify = [\"oncall@example.com\"]";
+    let mut harness = Harness::new(config(extra), Some(&mock));
+    let mut queue = vec![message("alert", "a@example.com")];
+
+    harness.round(&mut queue).await;
+
+    // The original stays queued for the relay alongside the operator notice
+    assert_eq!(queue.len(), 2);
+    let original = queue.iter().find(|timed| timed.email.subject.to_string() == "alert").expect("original");
+    assert!(!original.email.skip_email);
+    assert!(original.escalated);
+
+    for timed in queue.iter_mut() {
+        timed.next_attempt = Instant::now();
+    }
+    harness.round(&mut queue).await;
+    assert!(queue.is_empty());
+    let subjects: Vec<String> = mock.sent().into_iter().map(|sent| sent.subject).collect();
+    assert!(subjects.contains(&"alert".to_owned()));
+    assert!(subjects.contains(&"Escalated: alert".to_owned()));
+    assert_eq!(harness.metrics.events.sent, 2);
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::time::Instant;
+
+use common::{config, message, Harness};
+use dusa_collection_utils::errors::Errors;
+use mail_regulator::config::SmtpSecurity;
+use mail_regulator::{BreakerState, SmtpSink};
+
+// A harness sending over SMTP to `port` on localhost with the given transport security
+fn relay(port: u16, security: SmtpSecurity, extra: &str) -> Harness {
+    let mut config = config(extra);
+    config.smtp.server = Ipv4Addr::LOCALHOST.to_string();
+    config.smtp.port = port;
+    config.smtp.security = security;
+    Harness::new(config, None)
+}
+
+async fn sink() -> (SmtpSink, u16) {
+    let sink = SmtpSink::new();
+    let bound = sink.start(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await.expect("sink");
+    (sink, bound.port())
+}
+
+#[tokio::test]
+async fn delivers_through_the_relay() {
+    let (sink, port) = sink().await;
+    let mut harness = relay(port, SmtpSecurity::None, "");
+    let mut queue = vec![message("hello", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert!(queue.is_empty());
+    let captured = sink.captured();
+    assert_eq!(captured.len(), 1);
+    assert_eq!(captured[0].from, "regulator@example.com");
+    assert_eq!(captured[0].to, vec!["a@example.com".to_owned()]);
+    assert!(captured[0].data.contains("Subject: hello\r\n"));
+    assert!(harness.metrics.relay.as_ref().is_some_and(|relay| relay.ok));
+}
+
+#[tokio::test]
+async fn sends_from_the_bound_address() {
+    let (sink, port) = sink().await;
+    let mut harness = relay(port, SmtpSecurity::None, "");
+    harness.config.smtp.bind_address = Some(Ipv4Addr::LOCALHOST.into());
+    let mut queue = vec![message("bound", "a@example.com")];
+
+    assert!(harness.round(&mut queue).await.is_empty());
+    assert_eq!(sink.captured().len(), 1);
+}
+
+#[tokio::test]
+async fn retries_a_4xx_reply() {
+    let (sink, port) = sink().await;
+    sink.reply_to_next_data("451 4.3.0 try again later");
+    let mut harness = relay(port, SmtpSecurity::None, "");
+    let mut queue = vec![message("deferred", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].1.err_type, Errors::GeneralError));
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].attempts, 1);
+    assert!(sink.captured().is_empty());
+
+    // A reply proves the relay is up, the circuit stays closed
+    assert_eq!(harness.runner.breaker.state(), BreakerState::Closed);
+
+    queue[0].next_attempt = Instant::now();
+    assert!(harness.round(&mut queue).await.is_empty());
+    assert!(queue.is_empty());
+    assert_eq!(sink.captured().len(), 1);
+}
+
+#[tokio::test]
+async fn dead_letters_a_5xx_reply() {
+    let (sink, port) = sink().await;
+    sink.reply_to_next_data("550 5.1.1 no such user");
+    let mut harness = relay(port, SmtpSecurity::None, "");
+    let mut queue = vec![message("refused", "nobody@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert_eq!(errors.len(), 1);
+    assert!(queue.is_empty());
+    assert!(sink.captured().is_empty());
+    assert_eq!(harness.metrics.dead_lettered, 1);
+}
+
+#[tokio::test]
+async fn opens_the_circuit_when_the_relay_is_down() {
+    // Nothing listens on a port that was just released
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+    let mut harness = relay(port, SmtpSecurity::None, "[circuit_breaker]\nthreshold = 1");
+    let mut queue = vec![message("unreachable", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].1.err_type, Errors::ConnectionError));
+    assert_eq!(queue.len(), 1);
+    assert!(matches!(harness.runner.breaker.state(), BreakerState::Open(_)));
+
+    // Held while the circuit is open, even once its backoff is over
+    queue[0].next_attempt = Instant::now();
+    assert!(harness.round(&mut queue).await.is_empty());
+    assert_eq!(queue[0].attempts, 1);
+}
+
+#[tokio::test]
+async fn implicit_tls_refuses_a_plaintext_relay() {
+    let (sink, port) = sink().await;
+    let mut harness = relay(port, SmtpSecurity::Tls, "");
+    let mut queue = vec![message("secret", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].1.err_type, Errors::ConnectionError));
+    assert_eq!(queue.len(), 1);
+    assert!(sink.captured().is_empty());
+}
+
+#[tokio::test]
+async fn starttls_refuses_a_relay_without_starttls() {
+    let (sink, port) = sink().await;
+    let mut harness = relay(port, SmtpSecurity::Starttls, "");
+    let mut queue = vec![message("secret", "a@example.com")];
+
+    let errors = harness.round(&mut queue).await;
+
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].1.err_type, Errors::ConnectionError));
+    assert_eq!(queue.len(), 1);
+    assert!(sink.captured().is_empty());
+}